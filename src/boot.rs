@@ -0,0 +1,179 @@
+use crate::cpu::ioregister;
+use crate::gebemula::{GBMode, Model};
+use crate::mem::Memory;
+
+const LOGO_ADDR: u16 = 0x104;
+const LOGO_SIZE: u16 = 48;
+const TITLE_ADDR: u16 = 0x134;
+const TITLE_SIZE: u16 = 16;
+const NEW_LICENSEE_ADDR: u16 = 0x144;
+const OLD_LICENSEE_ADDR: u16 = 0x14B;
+const HEADER_CHECKSUM_ADDR: u16 = 0x14D;
+
+/// The ® next to the logo comes from the boot ROM itself.
+const REGISTERED_TILE: [u8; 8] = [0x3C, 0x42, 0xB9, 0xA5, 0xB9, 0xA5, 0x42, 0x3C];
+/// The logo is drawn from tile 1 on, 12 tiles per row, with the ® in tile 25.
+const LOGO_TILES_ADDR: u16 = 0x8010;
+const REGISTERED_TILE_ADDR: u16 = 0x8190;
+const LOGO_TOP_ROW_ADDR: u16 = 0x9904;
+const LOGO_BOTTOM_ROW_ADDR: u16 = 0x9924;
+const REGISTERED_MAP_ADDR: u16 = 0x9910;
+
+/// Palette the CGB boot ROM gives games without CGB support, as RGB555 colors for the
+/// background, OBJ 0 and OBJ 1. It picks other ones for some Nintendo titles, which aren't
+/// included here.
+pub const COMPAT_PALETTES: [[u16; 4]; 3] = [
+    [0x7FFF, 0x1BEF, 0x6180, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+    [0x7FFF, 0x421F, 0x1CF2, 0x0000],
+];
+
+/// IO registers as the boot ROMs leave them. The APU is powered on first, and the last write to
+/// NR14 retriggers pulse A with the inaudible frequency the boot sound ends on.
+const IO_REGISTERS: [(u16, u8); 35] = [
+    (0xFF26, 0x80),
+    (ioregister::JOYPAD_REGISTER_ADDR, 0xCF),
+    (ioregister::SB_REGISTER_ADDR, 0x00),
+    (ioregister::SC_REGISTER_ADDR, 0x7E),
+    (ioregister::TIMER_INTERNAL_COUNTER_ADDR, 0xCC),
+    (ioregister::DIV_REGISTER_ADDR, 0xAB),
+    (ioregister::TIMA_REGISTER_ADDR, 0x00),
+    (ioregister::TMA_REGISTER_ADDR, 0x00),
+    (ioregister::TAC_REGISTER_ADDR, 0xF8),
+    (ioregister::IF_REGISTER_ADDR, 0xE1),
+    (0xFF10, 0x80),
+    (0xFF11, 0xBF),
+    (0xFF12, 0xF3),
+    (0xFF13, 0xFF),
+    (0xFF14, 0xBF),
+    (0xFF16, 0x3F),
+    (0xFF17, 0x00),
+    (0xFF18, 0xFF),
+    (0xFF19, 0xBF),
+    (0xFF1A, 0x7F),
+    (0xFF1B, 0xFF),
+    (0xFF1C, 0x9F),
+    (0xFF1D, 0xFF),
+    (0xFF1E, 0xBF),
+    (0xFF20, 0xFF),
+    (0xFF21, 0x00),
+    (0xFF22, 0x00),
+    (0xFF23, 0xBF),
+    (0xFF24, 0x77),
+    (0xFF25, 0xF3),
+    (ioregister::LCDC_REGISTER_ADDR, 0x91),
+    (ioregister::DMA_REGISTER_ADDR, 0xFF),
+    (ioregister::BGP_REGISTER_ADDR, 0xFC),
+    (ioregister::OBP_0_REGISTER_ADDR, 0xFF),
+    (ioregister::OBP_1_REGISTER_ADDR, 0xFF),
+];
+
+/// Registers that only differ on the CGB.
+const CGB_IO_REGISTERS: [(u16, u8); 3] = [
+    (ioregister::SC_REGISTER_ADDR, 0x7F),
+    (ioregister::VBK_REGISTER_ADDR, 0xFE),
+    (ioregister::SVBK_REGISTER_ADDR, 0xF8),
+];
+
+fn is_nintendo_title(memory: &Memory) -> bool {
+    match memory.read_cartridge(OLD_LICENSEE_ADDR) {
+        0x01 => true,
+        0x33 => {
+            memory.read_cartridge(NEW_LICENSEE_ADDR) == b'0'
+                && memory.read_cartridge(NEW_LICENSEE_ADDR + 1) == b'1'
+        }
+        _ => false,
+    }
+}
+
+fn title_checksum(memory: &Memory) -> u8 {
+    (TITLE_ADDR..TITLE_ADDR + TITLE_SIZE)
+        .fold(0u8, |sum, addr| sum.wrapping_add(memory.read_cartridge(addr)))
+}
+
+/// AF, BC, DE and HL at 0x100, on `model` running a cartridge in `mode`.
+pub fn cpu_registers(model: Model, mode: GBMode, memory: &Memory) -> [u16; 4] {
+    match (model, mode) {
+        (Model::Dmg, _) | (Model::Mgb, _) => {
+            let a = if model == Model::Mgb { 0xFF } else { 0x01 };
+            // The header checksum check leaves H and C set unless the checksum byte is 0.
+            let f = if memory.read_cartridge(HEADER_CHECKSUM_ADDR) == 0 {
+                0x80
+            } else {
+                0xB0
+            };
+            [a << 8 | f, 0x0013, 0x00D8, 0x014D]
+        }
+        (Model::Cgb, GBMode::Color) => [0x1180, 0x0000, 0xFF56, 0x000D],
+        (Model::Cgb, GBMode::Mono) => {
+            // Leftovers from looking the title up in the compatibility palette table.
+            let (b, hl) = if is_nintendo_title(memory) {
+                (title_checksum(memory), 0x991A)
+            } else {
+                (0, 0x007C)
+            };
+            [0x1180, (b as u16) << 8, 0x0008, hl]
+        }
+    }
+}
+
+pub fn write_io_registers(model: Model, memory: &mut Memory) {
+    for &(addr, value) in IO_REGISTERS.iter() {
+        memory.write_byte(addr, value);
+    }
+    if model == Model::Cgb {
+        for &(addr, value) in CGB_IO_REGISTERS.iter() {
+            memory.write_byte(addr, value);
+        }
+    }
+}
+
+/// Spreads the 4 bits of `nibble` over 8, doubling every pixel horizontally.
+fn double_pixels(nibble: u8) -> u8 {
+    (0..4).fold(0, |row, bit| {
+        let pixel = (nibble >> bit) & 1;
+        row | (pixel * 0b11) << (bit * 2)
+    })
+}
+
+/// Draws the logo of the cartridge header, scaled up twice, and the ® into VRAM and the
+/// background map, the way the DMG boot ROM does. The CGB boot ROM draws it differently, but games
+/// don't rely on either so the same layout is used for every model.
+pub fn draw_logo(memory: &mut Memory) {
+    let mut addr = LOGO_TILES_ADDR;
+    for i in 0..LOGO_SIZE {
+        let byte = memory.read_cartridge(LOGO_ADDR + i);
+        for &nibble in [byte >> 4, byte & 0xF].iter() {
+            let row = double_pixels(nibble);
+            // Every row is drawn twice to double the height, leaving the second bitplane empty.
+            for _ in 0..2 {
+                memory.write_byte(addr, row);
+                addr += 2;
+            }
+        }
+    }
+    for (i, &row) in REGISTERED_TILE.iter().enumerate() {
+        memory.write_byte(REGISTERED_TILE_ADDR + i as u16 * 2, row);
+    }
+
+    for i in 0..12 {
+        memory.write_byte(LOGO_TOP_ROW_ADDR + i, 1 + i as u8);
+        memory.write_byte(LOGO_BOTTOM_ROW_ADDR + i, 13 + i as u8);
+    }
+    memory.write_byte(REGISTERED_MAP_ADDR, 25);
+}
+
+/// Loads `COMPAT_PALETTES` into the CGB palette memory.
+pub fn write_compat_palettes(memory: &mut Memory) {
+    for (i, &color) in COMPAT_PALETTES[0].iter().enumerate() {
+        memory.write_bg_palette(i as u8 * 2, color as u8);
+        memory.write_bg_palette(i as u8 * 2 + 1, (color >> 8) as u8);
+    }
+    for (palette, colors) in COMPAT_PALETTES[1..].iter().enumerate() {
+        for (i, &color) in colors.iter().enumerate() {
+            let addr = (palette * 8 + i * 2) as u8;
+            memory.write_sprite_palette(addr, color as u8);
+            memory.write_sprite_palette(addr + 1, (color >> 8) as u8);
+        }
+    }
+}
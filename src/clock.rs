@@ -0,0 +1,63 @@
+use crate::cpu::ioregister::CPU_FREQUENCY_HZ;
+
+use std::cell::Cell;
+use time::{self, Timespec, Tm};
+
+/// Wall clock as seen by cartridge hardware such as RTCs.
+pub trait Clock {
+    fn now(&self) -> Tm;
+    /// Called with the cycles emulated after every instruction.
+    fn advance(&self, _cycles: u32) {}
+
+    /// Cycles emulated so far, for clocks that follow emulation. Part of the save state.
+    fn cycles(&self) -> u64 {
+        0
+    }
+    /// Restores the cycles of a save state.
+    fn set_cycles(&self, _cycles: u64) {}
+}
+
+/// The local time of the host.
+pub struct HostClock;
+
+impl Clock for HostClock {
+    fn now(&self) -> Tm {
+        time::now()
+    }
+}
+
+/// Starts at a fixed time and only moves forward with emulation, so that runs can be reproduced
+/// exactly. Times are in UTC to not depend on the host timezone either.
+pub struct EmulatedClock {
+    start: i64,
+    cycles: Cell<u64>,
+}
+
+impl EmulatedClock {
+    /// `start` is in seconds since the Unix epoch.
+    pub fn new(start: i64) -> EmulatedClock {
+        EmulatedClock {
+            start,
+            cycles: Cell::new(0),
+        }
+    }
+}
+
+impl Clock for EmulatedClock {
+    fn now(&self) -> Tm {
+        let elapsed = self.cycles.get() / CPU_FREQUENCY_HZ as u64;
+        time::at_utc(Timespec::new(self.start + elapsed as i64, 0))
+    }
+
+    fn advance(&self, cycles: u32) {
+        self.cycles.set(self.cycles.get() + cycles as u64);
+    }
+
+    fn cycles(&self) -> u64 {
+        self.cycles.get()
+    }
+
+    fn set_cycles(&self, cycles: u64) {
+        self.cycles.set(cycles);
+    }
+}
@@ -0,0 +1,249 @@
+use crate::gebemula::Model;
+use crate::graphics::consts::DMG_PALETTE;
+use crate::ini::{Ini, ParseError};
+
+use std::env;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+/// Written to the configuration file when it doesn't exist yet.
+pub const DEFAULT_CONFIG: &str = "\
+# Gebemula configuration. Every setting can also be overridden from the command line, see
+# `gebemula --help`. Relative paths are relative to the directory of this file.
+
+[video]
+# Integer scale of the window.
+# scale = 2
+# fullscreen = false
+# Colors of the four shades of the original Game Boy, from lightest to darkest.
+# palette = #898F6E, #575C48, #232822, #101515
+
+[audio]
+# sample_rate = 48000
+# Samples per audio buffer, 0 leaves it up to the audio driver.
+# buffer_size = 0
+
+[system]
+# Hardware to emulate: auto (from the cartridge header), dmg, mgb or cgb.
+# model = auto
+# Boot ROMs of each model, relative to the working directory by default. Games start right away
+# from the state the boot ROM would leave if it's missing.
+# dmg_boot_rom = DMG_ROM.bin
+# mgb_boot_rom = MGB_ROM.bin
+# cgb_boot_rom = CGB_ROM.bin
+# Where battery saves and save states go. Next to the ROM by default.
+# save_dir = saves
+
+[input]
+# Key bindings file, created with the default bindings if it doesn't exist. The built-in
+# bindings are used by default.
+# bindings = bindings.ini
+";
+
+pub struct Config {
+    /// Integer scale of the window.
+    pub scale: u32,
+    pub fullscreen: bool,
+    /// Colors of the four DMG shades, from lightest to darkest.
+    pub palette: [(u8, u8, u8); 4],
+    pub sample_rate: u32,
+    /// Samples per audio buffer, or `None` to leave it up to the audio driver.
+    pub audio_buffer_size: Option<u16>,
+    /// Forced hardware model, or `None` to follow the cartridge header.
+    pub model: Option<Model>,
+    pub dmg_boot_rom: PathBuf,
+    pub mgb_boot_rom: PathBuf,
+    pub cgb_boot_rom: PathBuf,
+    /// Where battery saves and save states go, or `None` to keep them next to the ROM.
+    pub save_dir: Option<PathBuf>,
+    /// Key bindings file, or `None` for the built-in bindings.
+    pub bindings: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            scale: 2,
+            fullscreen: false,
+            palette: DMG_PALETTE,
+            sample_rate: 48000,
+            audio_buffer_size: None,
+            model: None,
+            dmg_boot_rom: PathBuf::from("DMG_ROM.bin"),
+            mgb_boot_rom: PathBuf::from("MGB_ROM.bin"),
+            cgb_boot_rom: PathBuf::from("CGB_ROM.bin"),
+            save_dir: None,
+            bindings: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ConfigError::Io(ref e) => write!(f, "{}", e),
+            ConfigError::Parse(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for ConfigError {
+    fn from(e: io::Error) -> Self {
+        ConfigError::Io(e)
+    }
+}
+
+impl From<ParseError> for ConfigError {
+    fn from(e: ParseError) -> Self {
+        ConfigError::Parse(e)
+    }
+}
+
+pub fn parse_model(value: &str) -> Result<Option<Model>, String> {
+    match value {
+        "auto" => Ok(None),
+        "dmg" => Ok(Some(Model::Dmg)),
+        "mgb" => Ok(Some(Model::Mgb)),
+        "cgb" => Ok(Some(Model::Cgb)),
+        _ => Err(format!("unknown model `{}`, expected auto, dmg, mgb or cgb", value)),
+    }
+}
+
+/// Parses four comma-separated `#RRGGBB` colors.
+pub fn parse_palette(value: &str) -> Result<[(u8, u8, u8); 4], String> {
+    let colors: Vec<&str> = value.split(',').map(str::trim).collect();
+    if colors.len() != 4 {
+        return Err(format!("expected 4 colors, found {}", colors.len()));
+    }
+    let mut palette = [(0, 0, 0); 4];
+    for (shade, color) in palette.iter_mut().zip(colors) {
+        let hex = color.trim_start_matches('#');
+        let rgb = match u32::from_str_radix(hex, 16) {
+            Ok(rgb) if hex.len() == 6 => rgb,
+            _ => return Err(format!("invalid color `{}`, expected #RRGGBB", color)),
+        };
+        *shade = ((rgb >> 16) as u8, (rgb >> 8) as u8, rgb as u8);
+    }
+    Ok(palette)
+}
+
+pub fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "on" | "1" => Ok(true),
+        "false" | "no" | "off" | "0" => Ok(false),
+        _ => Err(format!("expected true or false, found `{}`", value)),
+    }
+}
+
+fn parse_number<T: std::str::FromStr>(value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("expected a number, found `{}`", value))
+}
+
+/// Parses an integer scale factor, which has to be at least 1.
+pub fn parse_scale(value: &str) -> Result<u32, String> {
+    match parse_number(value)? {
+        0 => Err("the scale must be at least 1".to_owned()),
+        scale => Ok(scale),
+    }
+}
+
+impl Config {
+    /// `gebemula.ini` in the user's configuration directory.
+    pub fn default_path() -> Option<PathBuf> {
+        let dir = if cfg!(windows) {
+            env::var_os("APPDATA").map(PathBuf::from)
+        } else {
+            env::var_os("XDG_CONFIG_HOME")
+                .map(PathBuf::from)
+                .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))
+        };
+        dir.map(|dir| dir.join("gebemula").join("gebemula.ini"))
+    }
+
+    /// Reads the configuration from `path`. If it doesn't exist, either creates it with every
+    /// setting commented out or uses the defaults without touching the disk.
+    pub fn load(path: &Path, create: bool) -> Result<Config, ConfigError> {
+        if !path.exists() {
+            if !create {
+                return Ok(Config::default());
+            }
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            File::create(path)?.write_all(DEFAULT_CONFIG.as_bytes())?;
+            println!("Wrote default configuration to {}", path.display());
+        }
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        let base_dir = path.parent().unwrap_or_else(|| Path::new(""));
+        Ok(Config::parse(&text, base_dir)?)
+    }
+
+    /// Parses a configuration file, resolving relative paths against `base_dir`.
+    pub fn parse(text: &str, base_dir: &Path) -> Result<Config, ParseError> {
+        let ini = Ini::parse(text)?;
+        let mut config = Config::default();
+        for section in ini.sections.iter() {
+            for entry in section.entries.iter() {
+                let value = entry.value.as_str();
+                let path = || base_dir.join(value);
+                let result = match (section.name.as_str(), entry.key.as_str()) {
+                    ("video", "scale") => parse_scale(value).map(|v| config.scale = v),
+                    ("video", "fullscreen") => parse_bool(value).map(|v| config.fullscreen = v),
+                    ("video", "palette") => parse_palette(value).map(|v| config.palette = v),
+                    ("audio", "sample_rate") => {
+                        parse_number(value).map(|v| config.sample_rate = v)
+                    }
+                    ("audio", "buffer_size") => parse_number(value)
+                        .map(|v| config.audio_buffer_size = if v == 0 { None } else { Some(v) }),
+                    ("system", "model") => parse_model(value).map(|v| config.model = v),
+                    ("system", "dmg_boot_rom") => {
+                        config.dmg_boot_rom = path();
+                        Ok(())
+                    }
+                    ("system", "mgb_boot_rom") => {
+                        config.mgb_boot_rom = path();
+                        Ok(())
+                    }
+                    ("system", "cgb_boot_rom") => {
+                        config.cgb_boot_rom = path();
+                        Ok(())
+                    }
+                    ("system", "save_dir") => {
+                        config.save_dir = Some(path());
+                        Ok(())
+                    }
+                    ("input", "bindings") => {
+                        config.bindings = Some(path());
+                        Ok(())
+                    }
+                    (section, key) => Err(format!("unknown setting `{}` in [{}]", key, section)),
+                };
+                result.map_err(|message| ParseError {
+                    line: entry.line,
+                    message,
+                })?;
+            }
+        }
+        Ok(config)
+    }
+
+    /// The boot ROM of `model`.
+    pub fn boot_rom(&self, model: Model) -> &Path {
+        match model {
+            Model::Dmg => &self.dmg_boot_rom,
+            Model::Mgb => &self.mgb_boot_rom,
+            Model::Cgb => &self.cgb_boot_rom,
+        }
+    }
+}
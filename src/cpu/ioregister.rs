@@ -5,6 +5,10 @@ pub const CPU_FREQUENCY_HZ: u32 = 4194304; //that is, number of cycles per secon
 pub const DMA_DURATION_CYCLES: u32 = CPU_FREQUENCY_HZ / (1000000 / 160);
 pub const CGB_DMA_DURATION_CYCLES: u32 = 8; //for a transfer of length 0x10.
 
+// Serial registers
+pub const SB_REGISTER_ADDR: u16 = 0xFF01; //serial transfer data
+pub const SC_REGISTER_ADDR: u16 = 0xFF02; //serial transfer control
+
 // Divider Register
 pub const TIMER_INTERNAL_COUNTER_ADDR: u16 = 0xFF03;
 pub const DIV_REGISTER_ADDR: u16 = 0xFF04;
@@ -6,12 +6,14 @@ use std::fmt;
 use super::mem;
 use super::util;
 use super::debugger;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 pub enum EventRequest {
     BootstrapDisable,
     DMATransfer(u8), //left nibble of address to be used.
     HDMATransfer,
     JoypadUpdate,
+    SerialControl,
     SpeedModeSwitch,
 }
 
@@ -203,6 +205,19 @@ impl Cpu {
         self.last_instruction = None;
     }
 
+    /// Jumps to the cartridge entry point with AF, BC, DE and HL set to `regs`, as if the boot
+    /// ROM had just finished.
+    pub fn skip_boot_rom(&mut self, regs: [u16; 4]) {
+        self.restart();
+        self.ime_flag = false;
+        self.reg_set16(Reg::AF, regs[0]);
+        self.reg_set16(Reg::BC, regs[1]);
+        self.reg_set16(Reg::DE, regs[2]);
+        self.reg_set16(Reg::HL, regs[3]);
+        self.reg_set16(Reg::SP, 0xFFFE);
+        self.reg_set16(Reg::PC, 0x100);
+    }
+
     #[inline]
     fn reg_set16(&mut self, reg: Reg, value: u16) {
         let index = Cpu::reg_index(reg);
@@ -397,6 +412,7 @@ impl Cpu {
             }
             ioregister::HDMA5_REGISTER_ADDR => (value, Some(EventRequest::HDMATransfer)),
             ioregister::JOYPAD_REGISTER_ADDR => (value, Some(EventRequest::JoypadUpdate)),
+            ioregister::SC_REGISTER_ADDR => (value, Some(EventRequest::SerialControl)),
             _ => (value, None),
         };
         memory.write_byte(address, value);
@@ -1409,3 +1425,55 @@ impl Cpu {
         (instr, event)
     }
 }
+
+impl SaveState for Cpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.regs);
+        writer.write_bool(self.ime_flag);
+        writer.write_bool(self.halt_flag);
+        match self.last_instruction {
+            Some(ref instr) => {
+                writer.write_bool(true);
+                writer.write_bool(instr.prefix.is_some());
+                writer.write_u8(instr.prefix.unwrap_or(0));
+                writer.write_u8(instr.opcode);
+                writer.write_bool(instr.imm8.is_some());
+                writer.write_u8(instr.imm8.unwrap_or(0));
+                writer.write_bool(instr.imm16.is_some());
+                writer.write_u16(instr.imm16.unwrap_or(0));
+                writer.write_u16(instr.address);
+                writer.write_u32(instr.cycles);
+            }
+            None => writer.write_bool(false),
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.regs)?;
+        self.ime_flag = reader.read_bool()?;
+        self.halt_flag = reader.read_bool()?;
+        self.last_instruction = if reader.read_bool()? {
+            let has_prefix = reader.read_bool()?;
+            let prefix = reader.read_u8()?;
+            let opcode = reader.read_u8()?;
+            let has_imm8 = reader.read_bool()?;
+            let imm8 = reader.read_u8()?;
+            let has_imm16 = reader.read_bool()?;
+            let imm16 = reader.read_u16()?;
+            Some(Instruction {
+                prefix: if has_prefix { Some(prefix) } else { None },
+                opcode,
+                imm8: if has_imm8 { Some(imm8) } else { None },
+                imm16: if has_imm16 { Some(imm16) } else { None },
+                address: reader.read_u16()?,
+                cycles: reader.read_u32()?,
+            })
+        } else {
+            None
+        };
+        if self.halt_flag && self.last_instruction.is_none() {
+            return Err(StateError::Corrupt("halted cpu without a last instruction"));
+        }
+        Ok(())
+    }
+}
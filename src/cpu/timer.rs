@@ -1,5 +1,6 @@
 use super::super::mem;
 use crate::cpu::{interrupt, ioregister};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Timer {
     /// The timer overflow behavior is delayed.
@@ -66,3 +67,16 @@ impl Timer {
         }
     }
 }
+
+impl SaveState for Timer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.timer_overflow);
+        writer.write_u32(self.tima_cycles_counter);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.timer_overflow = reader.read_bool()?;
+        self.tima_cycles_counter = reader.read_u32()?;
+        Ok(())
+    }
+}
@@ -218,11 +218,7 @@ impl Debugger {
     }
     pub fn display_info(&self, mem: &Memory) {
         println!("GB Type: {:?}", GBMode::get(mem));
-        println!("Game: {}", mem::cartridge::game_title_str(mem));
-        let cart_type_id = mem.read_byte(mem::cartridge::CARTRIDGE_TYPE_ADDR);
-        let (mapper_type, extra_cart_hw) = mem::cartridge::cart_type_from_id(cart_type_id);
-        let cart_string = mem::cartridge::cartridge_type_string(mapper_type, extra_cart_hw);
-        println!("Cartridge Type: {}", cart_string);
+        println!("{}", mem::cartridge::CartridgeHeader::from_memory(mem));
     }
     fn read_loop(&mut self, instruction: &Instruction, cpu: &Cpu, mem: &Memory) {
         loop {
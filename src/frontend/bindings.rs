@@ -0,0 +1,570 @@
+use crate::config;
+use crate::ini::{Ini, ParseError};
+use crate::peripherals::joypad::JoypadKey;
+use crate::peripherals::tilt::Tilt;
+
+use sdl2::controller::{Axis, Button, GameController};
+use sdl2::keyboard::{self, KeyboardState, Mod, Scancode};
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// How far a stick has to be pushed to count as a direction.
+const AXIS_THRESHOLD: i16 = 16384;
+/// How far a stick has to be pushed before it starts tilting, out of 1.
+const TILT_DEADZONE: f32 = 0.1;
+
+/// Written to the bindings file when it doesn't exist yet.
+pub const DEFAULT_BINDINGS: &str = "\
+# Gebemula key bindings.
+#
+# Every entry is a comma-separated list of inputs:
+#   - keyboard keys by their SDL scancode name, optionally prefixed with Shift+, Ctrl+ or Alt+
+#     (e.g. `Z`, `Left Shift`, `Shift+F8`);
+#   - game controller buttons as Pad:<button> (a, b, x, y, back, guide, start, leftstick,
+#     rightstick, leftshoulder, rightshoulder, dpup, dpdown, dpleft, dpright);
+#   - game controller axes as Pad:<axis>+ or Pad:<axis>- (leftx, lefty, rightx, righty,
+#     lefttrigger, righttrigger).
+
+[joypad]
+a = Z, Pad:a
+b = X, Pad:b
+select = Left Shift, Pad:back
+start = Left Ctrl, Pad:start
+right = Right, Pad:dpright, Pad:leftx+
+left = Left, Pad:dpleft, Pad:leftx-
+up = Up, Pad:dpup, Pad:lefty-
+down = Down, Pad:dpdown, Pad:lefty+
+
+# Tilts the console for cartridges with an accelerometer. Keys and buttons tilt it all the way,
+# sticks as far as they're pushed. With mouse = yes, it also tilts towards the mouse cursor, by
+# how far the cursor is from the center of the window.
+[tilt]
+right = Keypad 6, Pad:rightx+
+left = Keypad 4, Pad:rightx-
+up = Keypad 8, Pad:righty-
+down = Keypad 2, Pad:righty+
+mouse = no
+
+[hotkeys]
+speed_up = U
+speed_down = I
+fast_forward = Tab, Pad:rightshoulder
+rewind = Backspace, Pad:leftshoulder
+restart = R
+bypass_logo = B
+save_state = S
+load_state = L
+slot_0 = 0
+slot_1 = 1
+slot_2 = 2
+slot_3 = 3
+slot_4 = 4
+slot_5 = 5
+slot_6 = 6
+slot_7 = 7
+slot_8 = 8
+slot_9 = 9
+toggle_background = F1
+toggle_window = F2
+toggle_sprites = F3
+toggle_pulse_a = F4
+toggle_pulse_b = F5
+toggle_wave = F6
+toggle_noise = F7
+record_audio = F8
+record_stems = Shift+F8
+record_movie = F9
+record_video = F10
+reload_bindings = F11
+screenshot = F12
+cancel_debugger_run = Q
+quit = Escape
+";
+
+const JOYPAD_KEYS: [(JoypadKey, &str); 8] = [
+    (JoypadKey::A, "a"),
+    (JoypadKey::B, "b"),
+    (JoypadKey::SELECT, "select"),
+    (JoypadKey::START, "start"),
+    (JoypadKey::RIGHT, "right"),
+    (JoypadKey::LEFT, "left"),
+    (JoypadKey::UP, "up"),
+    (JoypadKey::DOWN, "down"),
+];
+
+/// Tilt directions, given by the joypad direction keys.
+const TILT_DIRECTIONS: [(JoypadKey, &str); 4] = [
+    (JoypadKey::RIGHT, "right"),
+    (JoypadKey::LEFT, "left"),
+    (JoypadKey::UP, "up"),
+    (JoypadKey::DOWN, "down"),
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Action {
+    SpeedUp,
+    SpeedDown,
+    /// Held down.
+    FastForward,
+    /// Held down.
+    Rewind,
+    Restart,
+    BypassLogo,
+    SaveState,
+    LoadState,
+    SelectSlot(u8),
+    ToggleBackground,
+    ToggleWindow,
+    ToggleSprites,
+    ToggleAudioChannel(usize),
+    RecordAudio,
+    RecordStems,
+    RecordMovie,
+    RecordVideo,
+    ReloadBindings,
+    Screenshot,
+    CancelDebuggerRun,
+    Quit,
+}
+
+const ACTIONS: [(Action, &str, &str); 23] = [
+    (Action::SpeedUp, "speed_up", "increase speed"),
+    (Action::SpeedDown, "speed_down", "decrease speed"),
+    (Action::FastForward, "fast_forward", "speed up while being held down"),
+    (Action::Rewind, "rewind", "rewind while being held down"),
+    (Action::Restart, "restart", "restart"),
+    (Action::BypassLogo, "bypass_logo", "bypass nintendo logo"),
+    (Action::SaveState, "save_state", "save state to current slot"),
+    (Action::LoadState, "load_state", "load state from current slot"),
+    (Action::ToggleBackground, "toggle_background", "toggle background"),
+    (Action::ToggleWindow, "toggle_window", "toggle window"),
+    (Action::ToggleSprites, "toggle_sprites", "toggle sprites"),
+    (Action::ToggleAudioChannel(0), "toggle_pulse_a", "toggle pulse A"),
+    (Action::ToggleAudioChannel(1), "toggle_pulse_b", "toggle pulse B"),
+    (Action::ToggleAudioChannel(2), "toggle_wave", "toggle custom wave"),
+    (Action::ToggleAudioChannel(3), "toggle_noise", "toggle white noise"),
+    (Action::RecordAudio, "record_audio", "start/stop recording audio"),
+    (Action::RecordStems, "record_stems", "start/stop recording audio and each voice"),
+    (Action::RecordMovie, "record_movie", "start/stop recording a movie of the input"),
+    (Action::RecordVideo, "record_video", "start/stop recording video"),
+    (Action::ReloadBindings, "reload_bindings", "reload the key bindings"),
+    (Action::Screenshot, "screenshot", "save screenshot"),
+    (Action::CancelDebuggerRun, "cancel_debugger_run", "stop running in the debugger"),
+    (Action::Quit, "quit", "quit"),
+];
+
+const SLOT_ACTION_PREFIX: &str = "slot_";
+
+fn action_from_name(name: &str) -> Option<Action> {
+    if let Some(slot) = name.strip_prefix(SLOT_ACTION_PREFIX) {
+        return match slot.parse() {
+            Ok(slot) if slot < 10 => Some(Action::SelectSlot(slot)),
+            _ => None,
+        };
+    }
+    ACTIONS
+        .iter()
+        .find(|&&(_, action_name, _)| action_name == name)
+        .map(|&(action, _, _)| action)
+}
+
+fn action_description(action: Action) -> String {
+    match action {
+        Action::SelectSlot(slot) => format!("select save state slot {}", slot),
+        _ => ACTIONS
+            .iter()
+            .find(|&&(a, _, _)| a == action)
+            .map_or(String::new(), |&(_, _, description)| description.to_owned()),
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Input {
+    Key {
+        scancode: Scancode,
+        shift: bool,
+        ctrl: bool,
+        alt: bool,
+    },
+    Button(Button),
+    /// Axis pushed in the positive (`true`) or negative direction.
+    Axis(Axis, bool),
+}
+
+impl Input {
+    fn parse(name: &str) -> Option<Input> {
+        if let Some(name) = name.strip_prefix("Pad:") {
+            if name.ends_with('+') || name.ends_with('-') {
+                let axis = Axis::from_string(&name[..name.len() - 1])?;
+                return Some(Input::Axis(axis, name.ends_with('+')));
+            }
+            return Button::from_string(name).map(Input::Button);
+        }
+
+        let (mut shift, mut ctrl, mut alt) = (false, false, false);
+        let mut name = name;
+        loop {
+            if let Some(rest) = name.strip_prefix("Shift+") {
+                shift = true;
+                name = rest;
+            } else if let Some(rest) = name.strip_prefix("Ctrl+") {
+                ctrl = true;
+                name = rest;
+            } else if let Some(rest) = name.strip_prefix("Alt+") {
+                alt = true;
+                name = rest;
+            } else {
+                break;
+            }
+        }
+        Scancode::from_name(name).map(|scancode| Input::Key {
+            scancode,
+            shift,
+            ctrl,
+            alt,
+        })
+    }
+
+    fn is_held(&self, keyboard: &KeyboardState, controllers: &[GameController]) -> bool {
+        match *self {
+            Input::Key { scancode, .. } => keyboard.is_scancode_pressed(scancode),
+            Input::Button(button) => controllers.iter().any(|c| c.button(button)),
+            Input::Axis(axis, positive) => controllers.iter().any(|c| {
+                let value = c.axis(axis);
+                if positive {
+                    value >= AXIS_THRESHOLD
+                } else {
+                    value <= -AXIS_THRESHOLD
+                }
+            }),
+        }
+    }
+
+    /// How far the input is pushed, from 0 to 1. Only axes can be in between.
+    fn amount(&self, keyboard: &KeyboardState, controllers: &[GameController]) -> f32 {
+        match *self {
+            Input::Axis(axis, positive) => controllers
+                .iter()
+                .map(|c| {
+                    let value = c.axis(axis) as f32 / i16::MAX as f32;
+                    let value = if positive { value } else { -value };
+                    if value < TILT_DEADZONE {
+                        0.0
+                    } else {
+                        value.min(1.0)
+                    }
+                })
+                .fold(0.0, f32::max),
+            _ if self.is_held(keyboard, controllers) => 1.0,
+            _ => 0.0,
+        }
+    }
+
+    /// Number of modifiers `keymod` satisfies for this key, or `None` if one is missing.
+    fn matched_modifiers(&self, keymod: Mod) -> Option<u32> {
+        match *self {
+            Input::Key {
+                shift, ctrl, alt, ..
+            } => {
+                let required = [
+                    (shift, keyboard::LSHIFTMOD | keyboard::RSHIFTMOD),
+                    (ctrl, keyboard::LCTRLMOD | keyboard::RCTRLMOD),
+                    (alt, keyboard::LALTMOD | keyboard::RALTMOD),
+                ];
+                let mut matched = 0;
+                for &(needed, flags) in required.iter() {
+                    if needed {
+                        if !keymod.intersects(flags) {
+                            return None;
+                        }
+                        matched += 1;
+                    }
+                }
+                Some(matched)
+            }
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Input {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Input::Key {
+                scancode,
+                shift,
+                ctrl,
+                alt,
+            } => {
+                if shift {
+                    write!(f, "Shift+")?;
+                }
+                if ctrl {
+                    write!(f, "Ctrl+")?;
+                }
+                if alt {
+                    write!(f, "Alt+")?;
+                }
+                write!(f, "{}", scancode.name())
+            }
+            Input::Button(button) => write!(f, "Pad:{}", button.string()),
+            Input::Axis(axis, positive) => {
+                write!(f, "Pad:{}{}", axis.string(), if positive { '+' } else { '-' })
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum BindingsError {
+    Io(io::Error),
+    Parse(ParseError),
+}
+
+impl fmt::Display for BindingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            BindingsError::Io(ref e) => write!(f, "{}", e),
+            BindingsError::Parse(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for BindingsError {
+    fn from(e: io::Error) -> Self {
+        BindingsError::Io(e)
+    }
+}
+
+impl From<ParseError> for BindingsError {
+    fn from(e: ParseError) -> Self {
+        BindingsError::Parse(e)
+    }
+}
+
+/// Maps keyboard keys and game controller buttons and axes to joypad keys and emulator actions.
+pub struct Bindings {
+    joypad: Vec<(JoypadKey, Input)>,
+    /// Tilt directions are given by the joypad direction keys.
+    tilt: Vec<(JoypadKey, Input)>,
+    tilt_mouse: bool,
+    actions: Vec<(Action, Input)>,
+}
+
+impl Default for Bindings {
+    fn default() -> Bindings {
+        Bindings::parse(DEFAULT_BINDINGS).expect("Invalid default bindings")
+    }
+}
+
+impl Bindings {
+    pub fn parse(text: &str) -> Result<Bindings, ParseError> {
+        let ini = Ini::parse(text)?;
+        let mut bindings = Bindings {
+            joypad: Vec::new(),
+            tilt: Vec::new(),
+            tilt_mouse: false,
+            actions: Vec::new(),
+        };
+        let parse_inputs = |value: &str, line: usize| -> Result<Vec<Input>, ParseError> {
+            value
+                .split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(|name| {
+                    Input::parse(name).ok_or_else(|| ParseError {
+                        line,
+                        message: format!("unknown input `{}`", name),
+                    })
+                })
+                .collect()
+        };
+
+        for entry in ini.entries("joypad") {
+            let key = match JOYPAD_KEYS.iter().find(|&&(_, name)| name == entry.key) {
+                Some(&(key, _)) => key,
+                None => {
+                    return Err(ParseError {
+                        line: entry.line,
+                        message: format!("unknown joypad key `{}`", entry.key),
+                    });
+                }
+            };
+            for input in parse_inputs(&entry.value, entry.line)? {
+                bindings.joypad.push((key, input));
+            }
+        }
+        for entry in ini.entries("tilt") {
+            if entry.key == "mouse" {
+                bindings.tilt_mouse =
+                    config::parse_bool(&entry.value).map_err(|message| ParseError {
+                        line: entry.line,
+                        message,
+                    })?;
+                continue;
+            }
+            let direction = match TILT_DIRECTIONS.iter().find(|&&(_, name)| name == entry.key) {
+                Some(&(direction, _)) => direction,
+                None => {
+                    return Err(ParseError {
+                        line: entry.line,
+                        message: format!("unknown tilt direction `{}`", entry.key),
+                    });
+                }
+            };
+            for input in parse_inputs(&entry.value, entry.line)? {
+                bindings.tilt.push((direction, input));
+            }
+        }
+        for entry in ini.entries("hotkeys") {
+            let action = action_from_name(&entry.key).ok_or_else(|| ParseError {
+                line: entry.line,
+                message: format!("unknown action `{}`", entry.key),
+            })?;
+            for input in parse_inputs(&entry.value, entry.line)? {
+                bindings.actions.push((action, input));
+            }
+        }
+        Ok(bindings)
+    }
+
+    /// Reads the bindings from `path`, creating it with the default bindings if it doesn't exist.
+    pub fn load(path: &Path) -> Result<Bindings, BindingsError> {
+        if !path.exists() {
+            File::create(path)?.write_all(DEFAULT_BINDINGS.as_bytes())?;
+            println!("Wrote default key bindings to {}", path.display());
+        }
+        let mut text = String::new();
+        File::open(path)?.read_to_string(&mut text)?;
+        Ok(Bindings::parse(&text)?)
+    }
+
+    pub fn joypad_state(
+        &self,
+        keyboard: &KeyboardState,
+        controllers: &[GameController],
+    ) -> JoypadKey {
+        self.joypad
+            .iter()
+            .filter(|&&(_, input)| input.is_held(keyboard, controllers))
+            .fold(JoypadKey::NONE, |pressed, &(key, _)| pressed | key)
+    }
+
+    /// How far the inputs bound to tilting push the console. `mouse` is the position of the
+    /// cursor in the window, from -1 to 1 on both axes with y going down, if it's used for tilting.
+    pub fn tilt(
+        &self,
+        keyboard: &KeyboardState,
+        controllers: &[GameController],
+        mouse: (f32, f32),
+    ) -> Tilt {
+        let mut tilt = if self.tilt_mouse {
+            Tilt {
+                x: mouse.0,
+                y: -mouse.1,
+            }
+        } else {
+            Tilt::default()
+        };
+        for &(direction, input) in self.tilt.iter() {
+            let amount = input.amount(keyboard, controllers);
+            match direction {
+                JoypadKey::RIGHT => tilt.x += amount,
+                JoypadKey::LEFT => tilt.x -= amount,
+                JoypadKey::UP => tilt.y += amount,
+                _ => tilt.y -= amount,
+            }
+        }
+        tilt.clamped()
+    }
+
+    /// Whether any input bound to `action` is held down.
+    pub fn is_held(
+        &self,
+        action: Action,
+        keyboard: &KeyboardState,
+        controllers: &[GameController],
+    ) -> bool {
+        self.actions
+            .iter()
+            .any(|&(a, input)| a == action && input.is_held(keyboard, controllers))
+    }
+
+    /// The action of a key press. When several bindings match, the one requiring the most
+    /// modifiers wins, so that e.g. Shift+F8 takes precedence over F8.
+    pub fn key_action(&self, scancode: Scancode, keymod: Mod) -> Option<Action> {
+        self.actions
+            .iter()
+            .filter_map(|&(action, input)| match input {
+                Input::Key { scancode: s, .. } if s == scancode => {
+                    input.matched_modifiers(keymod).map(|matched| (matched, action))
+                }
+                _ => None,
+            })
+            .max_by_key(|&(matched, _)| matched)
+            .map(|(_, action)| action)
+    }
+
+    pub fn button_action(&self, button: Button) -> Option<Action> {
+        self.actions
+            .iter()
+            .find(|&&(_, input)| input == Input::Button(button))
+            .map(|&(action, _)| action)
+    }
+
+    /// Prints every binding, in the order of the file.
+    pub fn print(&self) {
+        let inputs_of = |inputs: Vec<Input>| {
+            inputs
+                .iter()
+                .map(|input| input.to_string())
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+
+        println!(" Gameboy | Inputs");
+        println!("---------+------------");
+        for &(key, name) in JOYPAD_KEYS.iter() {
+            let inputs = self
+                .joypad
+                .iter()
+                .filter(|&&(k, _)| k == key)
+                .map(|&(_, input)| input)
+                .collect();
+            println!("{:^9}| {}", name, inputs_of(inputs));
+        }
+        println!("---------+------------");
+        println!("  Tilt   |");
+        for &(direction, name) in TILT_DIRECTIONS.iter() {
+            let inputs = self
+                .tilt
+                .iter()
+                .filter(|&&(d, _)| d == direction)
+                .map(|&(_, input)| input)
+                .collect();
+            println!("{:^9}| {}", name, inputs_of(inputs));
+        }
+        if self.tilt_mouse {
+            println!("{:^9}| yes", "mouse");
+        }
+        println!("---------+------------");
+        let mut printed: Vec<Action> = Vec::new();
+        for &(action, _) in self.actions.iter() {
+            if printed.contains(&action) {
+                continue;
+            }
+            printed.push(action);
+            let inputs = self
+                .actions
+                .iter()
+                .filter(|&&(a, _)| a == action)
+                .map(|&(_, input)| input)
+                .collect();
+            println!("{}: {}", inputs_of(inputs), action_description(action));
+        }
+        println!("######################");
+    }
+}
@@ -0,0 +1,24 @@
+use crate::gebemula::Gebemula;
+use crate::peripherals::tilt::TiltScript;
+
+/// Sample rate used when nobody is listening; the audio still has to be generated and drained.
+const HEADLESS_SAMPLE_RATE: u32 = 48000;
+
+/// Runs the emulator without any display or audio device, for `frames` frames or until the
+/// debugger asks to quit. The console is tilted as `tilt` says.
+pub fn run(gebemula: &mut Gebemula, frames: Option<u64>, tilt: &TiltScript) {
+    gebemula.set_debugger_enabled(false);
+    gebemula.set_audio_sample_rate(HEADLESS_SAMPLE_RATE);
+
+    let mut audio_buffer = Vec::new();
+    let mut frame = 0;
+    while frames.map_or(true, |frames| frame < frames) && !gebemula.exit_requested() {
+        gebemula.set_tilt(tilt.tilt_at(frame));
+        gebemula.run_frame();
+        gebemula.drain_audio(&mut audio_buffer);
+        audio_buffer.clear();
+        frame += 1;
+    }
+    gebemula.finish_recordings();
+    gebemula.update_battery();
+}
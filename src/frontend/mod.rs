@@ -0,0 +1,5 @@
+#[cfg(feature = "sdl")]
+pub mod bindings;
+pub mod headless;
+#[cfg(feature = "sdl")]
+pub mod sdl;
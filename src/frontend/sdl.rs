@@ -0,0 +1,285 @@
+use crate::config::Config;
+use crate::frontend::bindings::{Action, Bindings};
+use crate::gebemula::Gebemula;
+use crate::graphics;
+
+use sdl2;
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::controller::GameController;
+use sdl2::GameControllerSubsystem;
+use sdl2::event::Event;
+use sdl2::pixels::{Color, PixelFormatEnum};
+
+use std::path::Path;
+use std::{self, thread};
+use time;
+
+
+
+const AUDIO_CHANNEL_NAMES: [&str; 4] = ["Pulse A", "Pulse B", "Custom Wave", "White Noise"];
+
+fn load_bindings(path: Option<&Path>) -> Bindings {
+    match path {
+        Some(path) => Bindings::load(path).unwrap_or_else(|e| {
+            println!(
+                "Unable to load key bindings from {}: {}. Using the defaults.",
+                path.display(),
+                e
+            );
+            Bindings::default()
+        }),
+        None => Bindings::default(),
+    }
+}
+
+fn open_controller(
+    subsystem: &GameControllerSubsystem,
+    joystick_index: u32,
+    controllers: &mut Vec<GameController>,
+) {
+    match subsystem.open(joystick_index) {
+        Ok(controller) => {
+            println!("Game controller connected: {}", controller.name());
+            controllers.push(controller);
+        }
+        Err(e) => println!("Unable to open game controller {}: {}", joystick_index, e),
+    }
+}
+
+fn feed_audio(gebemula: &mut Gebemula, audio_device: &AudioQueue<i16>, audio_buffer: &mut Vec<i16>) {
+    gebemula.drain_audio(audio_buffer);
+    let current_audio_buf = audio_device.size();
+    audio_device.queue(audio_buffer.as_ref());
+    if current_audio_buf < 128 {
+        //println!("Audio buffer underrun: {} (adding {})", current_audio_buf, audio_buffer.len());
+    }
+    audio_buffer.clear();
+}
+
+/// Runs the emulator in a window, set up from `config`.
+pub fn run(gebemula: &mut Gebemula, config: &Config) {
+    let bindings_path = config.bindings.as_ref().map(|path| path.as_path());
+    let mut bindings = load_bindings(bindings_path);
+    bindings.print();
+
+    let sdl_context = sdl2::init().unwrap();
+    let video_subsystem = sdl_context.video().unwrap();
+    let audio_subsystem = sdl_context.audio().unwrap();
+    // Controllers connected at startup are reported as added as well.
+    let controller_subsystem = sdl_context.game_controller().unwrap();
+    let mut controllers = Vec::new();
+
+    let audio_desired_spec = AudioSpecDesired {
+        freq: Some(config.sample_rate as i32),
+        channels: Some(2),
+        samples: config.audio_buffer_size,
+    };
+    let audio_device = audio_subsystem.open_queue(None, &audio_desired_spec).unwrap();
+    let audio_spec = audio_device.spec();
+    let mut audio_buffer = Vec::new();
+
+    gebemula.set_audio_sample_rate(audio_spec.freq as u32);
+
+    let mut window_builder = video_subsystem.window(
+        "Gebemula Emulator",
+        graphics::consts::DISPLAY_WIDTH_PX as u32 * config.scale,
+        graphics::consts::DISPLAY_HEIGHT_PX as u32 * config.scale,
+    );
+    window_builder.opengl();
+    if config.fullscreen {
+        window_builder.fullscreen_desktop();
+    }
+    let window = window_builder.build().unwrap();
+
+    let mut canvas = window.into_canvas().build().unwrap();
+    canvas.set_draw_color(Color::RGBA(0, 0, 0, 255));
+    canvas.clear();
+
+    let texture_creator = canvas.texture_creator();
+
+    let mut texture = texture_creator
+        .create_texture_streaming(
+            PixelFormatEnum::ABGR8888,
+            graphics::consts::DISPLAY_WIDTH_PX as u32,
+            graphics::consts::DISPLAY_HEIGHT_PX as u32,
+        )
+        .unwrap();
+
+    canvas.present();
+    audio_device.resume();
+
+    let mut event_pump = sdl_context.event_pump().unwrap();
+    let mut last_time_seconds = time::now();
+    let mut last_time = time::now();
+    let mut frame_time_err = 0;
+
+    let mut speed_mul = 1;
+    let mut fast_forward = false;
+    let target_fps = 60;
+    let mut fps = 0;
+    let mut cycles_per_sec = 0;
+    if !cfg!(debug_assertions) {
+        gebemula.display_info();
+    }
+    'running: loop {
+        let mut actions = Vec::new();
+        for event in event_pump.poll_iter() {
+            match event {
+                Event::KeyDown {
+                    scancode: Some(scancode),
+                    keymod,
+                    repeat: false,
+                    ..
+                } => actions.extend(bindings.key_action(scancode, keymod)),
+                Event::ControllerButtonDown { button, .. } => {
+                    actions.extend(bindings.button_action(button))
+                }
+                Event::ControllerDeviceAdded { which, .. } => {
+                    open_controller(&controller_subsystem, which, &mut controllers)
+                }
+                Event::ControllerDeviceRemoved { .. } => controllers.retain(|c| c.attached()),
+                Event::Quit { .. } => break 'running,
+                _ => {}
+            }
+        }
+
+        for action in actions {
+            match action {
+                Action::SpeedUp => {
+                    speed_mul += 1;
+                    if speed_mul >= 15 {
+                        speed_mul = 15;
+                    }
+                    println!("speed x{}", speed_mul);
+                }
+                Action::SpeedDown => {
+                    speed_mul -= 1;
+                    if speed_mul == 0 {
+                        speed_mul = 1;
+                    }
+                    println!("speed x{}", speed_mul);
+                }
+                Action::Restart => gebemula.restart(),
+                Action::BypassLogo => gebemula.bypass_nintendo_logo(),
+                Action::SaveState => gebemula.save_state_to_slot(),
+                Action::LoadState => gebemula.load_state_from_slot(),
+                Action::SelectSlot(slot) => gebemula.select_save_state_slot(slot),
+                Action::ToggleBackground => gebemula.toggle_bg(),
+                Action::ToggleWindow => gebemula.toggle_wn(),
+                Action::ToggleSprites => gebemula.toggle_sprites(),
+                Action::ToggleAudioChannel(channel) => println!(
+                    "{}: {:?}",
+                    AUDIO_CHANNEL_NAMES[channel],
+                    gebemula.toggle_audio_channel(channel)
+                ),
+                Action::RecordAudio => gebemula.toggle_audio_recording(false),
+                Action::RecordStems => gebemula.toggle_audio_recording(true),
+                Action::RecordMovie => gebemula.toggle_movie_recording(),
+                Action::RecordVideo => gebemula.toggle_video_recording(),
+                Action::Screenshot => gebemula.take_screenshot(),
+                Action::ReloadBindings => match bindings_path {
+                    Some(path) => match Bindings::load(path) {
+                        Ok(reloaded) => {
+                            bindings = reloaded;
+                            println!("Reloaded key bindings from {}", path.display());
+                            bindings.print();
+                        }
+                        Err(e) => println!(
+                            "Unable to reload key bindings from {}: {}",
+                            path.display(),
+                            e
+                        ),
+                    },
+                    None => println!("No key bindings file to reload, use --bindings"),
+                },
+                Action::CancelDebuggerRun => gebemula.cancel_debugger_run(),
+                Action::Quit => break 'running,
+                // Held down, checked below.
+                Action::FastForward | Action::Rewind => {}
+            }
+        }
+
+        let keyboard = event_pump.keyboard_state();
+        if bindings.is_held(Action::FastForward, &keyboard, &controllers) != fast_forward {
+            fast_forward = !fast_forward;
+            if fast_forward {
+                speed_mul += 1;
+            } else {
+                speed_mul -= 1;
+            }
+            println!("speed x{}", speed_mul);
+        }
+        let desired_frametime_ns = 1_000_000_000 / (target_fps * speed_mul);
+
+        if bindings.is_held(Action::Rewind, &keyboard, &controllers) {
+            gebemula.rewind_frame();
+        } else {
+            gebemula.set_joypad_state(bindings.joypad_state(&keyboard, &controllers));
+            let mouse = event_pump.mouse_state();
+            let (width, height) = canvas.window().size();
+            let mouse_position = (
+                mouse.x() as f32 / width as f32 * 2.0 - 1.0,
+                mouse.y() as f32 / height as f32 * 2.0 - 1.0,
+            );
+            gebemula.set_tilt(bindings.tilt(&keyboard, &controllers, mouse_position));
+            cycles_per_sec += gebemula.run_frame();
+        }
+
+        feed_audio(gebemula, &audio_device, &mut audio_buffer);
+
+        if gebemula.exit_requested() {
+            break 'running;
+        }
+
+        /*
+         * Yuri Kunde Schlesner:
+         * it's just the way you do it (fps checking)  seems brittle and
+         * you'll get error depending on your timing
+         * instead of counting "each >= 1 second check how many frames
+         * were rendered and show that as fps", you should either do
+         * "each >= 1 second check how many frame were rendered / *actual*
+         * elapsed time since last reset of fps"
+         * or "each N frames, check elapsed time since last fps update and
+         * calculate based on that" fps is just 1 / frametime, so you should
+         * just try to average frametime over time to calculate it imo
+         *
+         * https://github.com/yuriks/super-match-5-dx/blob/master/src/main.cpp#L224
+         */
+        texture
+            .update(
+                None,
+                gebemula.framebuffer(),
+                graphics::consts::DISPLAY_WIDTH_PX as usize * 4,
+            )
+            .unwrap();
+        canvas.clear();
+        if let Err(_) = canvas.copy(&texture, None, None) {
+            println!("Unable to draw texture to canvas!");
+            break 'running;
+        }
+        canvas.present();
+
+        frame_time_err += desired_frametime_ns;
+        let now = time::now();
+        let elapsed = (now - last_time).num_nanoseconds().unwrap() as i64;
+        frame_time_err -= elapsed;
+        if frame_time_err > 0 {
+            thread::sleep(std::time::Duration::new(0, frame_time_err as u32));
+        }
+        last_time = now;
+        fps += 1;
+
+        let now = time::now();
+        if now - last_time_seconds >= time::Duration::seconds(1) {
+            last_time_seconds = now;
+            let title = &format!("{} Gebemula - {}", fps, cycles_per_sec);
+            canvas.window_mut().set_title(title).unwrap();
+            cycles_per_sec = 0;
+            fps = 0;
+
+            gebemula.update_battery();
+        }
+    }
+    gebemula.finish_recordings();
+    gebemula.update_battery();
+}
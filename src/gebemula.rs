@@ -1,26 +1,42 @@
+use crate::boot;
+use crate::clock::{Clock, EmulatedClock, HostClock};
+use crate::movie::{Movie, MovieError, MovieInput, MovieStart};
 use crate::peripherals::joypad::{Joypad, JoypadKey};
 use crate::peripherals::lcd::LCD;
-use crate::peripherals::sound::{AudioController, AUDIO_DESIRED_SPEC};
+use crate::peripherals::serial::{Serial, SerialLink};
+use crate::peripherals::sound::{self, AudioController};
+use crate::peripherals::tilt::Tilt;
 
 use crate::cpu::{ioregister, Cpu, EventRequest};
 use crate::cpu::timer::Timer;
 
 use crate::graphics;
 
-use crate::mem::Memory;
+use crate::mem::cartridge::{self, CartridgeError};
+use crate::mem::{ImageSource, Infrared, Memory};
 use crate::debugger::Debugger;
+use crate::recording::png;
+use crate::recording::video::{VideoFormat, VideoWriter};
+use crate::recording::wav::WavWriter;
+use crate::rewind::RewindBuffer;
+use crate::state::{self, SaveState, StateError, StateReader, StateWriter};
+use crate::util;
 
-use sdl2;
-use sdl2::pixels::{Color, PixelFormatEnum};
-use sdl2::keyboard::{Keycode, Scancode};
-
-use time;
-use std::{self, thread};
 use std::cell::RefCell;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
-use sdl2::audio::AudioQueue;
+use time;
 
 const GB_MODE_ADDR: u16 = 0x143;
+/// Save states are tied to the cartridge header, from the title up to the global checksum.
+const STATE_HEADER_START_ADDR: u16 = 0x134;
+const STATE_HEADER_END_ADDR: u16 = 0x14F;
+const NUM_SAVE_STATE_SLOTS: u8 = 10;
+/// The LCD refreshes at about 59.7 Hz.
+const FRAMES_PER_SECOND: usize = 60;
 
 #[derive(Copy, Clone, Debug, PartialEq)]
 pub enum GBMode {
@@ -30,11 +46,85 @@ pub enum GBMode {
 
 impl GBMode {
     pub fn get(memory: &Memory) -> Self {
-        match memory.read_cartridge(GB_MODE_ADDR) {
+        if let Some(mode) = memory.forced_mode() {
+            return mode;
+        }
+        GBMode::from_cgb_flag(memory.read_cartridge(GB_MODE_ADDR))
+    }
+
+    /// The mode a cartridge runs in, from the CGB flag of its header.
+    pub fn from_cgb_flag(flag: u8) -> Self {
+        match flag {
             0x80 | 0xC0 => GBMode::Color,
             _ => GBMode::Mono,
         }
     }
+
+    /// The mode of the cartridge `rom`, before it's loaded.
+    pub fn from_rom(rom: &[u8]) -> Self {
+        let header = cartridge::boot_header(rom);
+        GBMode::from_cgb_flag(header.get(GB_MODE_ADDR as usize).cloned().unwrap_or(0))
+    }
+}
+
+/// The console being emulated.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum Model {
+    /// The original Game Boy.
+    Dmg,
+    /// Game Boy Pocket and Light.
+    Mgb,
+    Cgb,
+}
+
+impl Model {
+    /// The console `rom` was made for.
+    pub fn from_rom(rom: &[u8]) -> Self {
+        match GBMode::from_rom(rom) {
+            GBMode::Color => Model::Cgb,
+            GBMode::Mono => Model::Dmg,
+        }
+    }
+
+    /// The mode `rom` runs in on this console.
+    pub fn mode(self, rom: &[u8]) -> GBMode {
+        match self {
+            Model::Dmg | Model::Mgb => GBMode::Mono,
+            Model::Cgb => GBMode::from_rom(rom),
+        }
+    }
+}
+
+/// Why a game couldn't be started.
+#[derive(Debug)]
+pub enum LoadError {
+    /// Reading the file at the path failed.
+    Io(PathBuf, io::Error),
+    Cartridge(CartridgeError),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            LoadError::Io(ref path, ref e) => write!(f, "{}: {}", path.display(), e),
+            LoadError::Cartridge(ref e) => write!(f, "invalid cartridge: {}", e),
+        }
+    }
+}
+
+impl From<CartridgeError> for LoadError {
+    fn from(e: CartridgeError) -> Self {
+        LoadError::Cartridge(e)
+    }
+}
+
+/// Reads a ROM, boot ROM or battery file.
+pub fn read_file(path: &Path) -> Result<Vec<u8>, LoadError> {
+    let mut data = Vec::new();
+    File::open(path)
+        .and_then(|mut file| file.read_to_end(&mut data))
+        .map_err(|e| LoadError::Io(path.to_owned(), e))?;
+    Ok(data)
 }
 
 enum SpeedMode {
@@ -42,18 +132,49 @@ enum SpeedMode {
     Double,
 }
 
+enum MovieMode {
+    Off,
+    Recording { movie: Movie, path: PathBuf },
+    Playing { movie: Movie, frame: usize },
+}
+
 pub struct Gebemula<'a> {
     cpu: Cpu,
     mem: Memory,
     timer: Timer,
     debugger: Debugger,
-    cycles_per_sec: u32,
+    /// The interactive debugger reads from stdin, so batch runs have to be able to turn it off.
+    debugger_enabled: bool,
     lcd: LCD,
     joypad: Joypad,
+    /// Keys to press during the next frame.
+    joypad_state: JoypadKey,
+    serial: Serial,
     apu: Rc<RefCell<AudioController>>,
     /// Used to periodically save the battery-backed cartridge SRAM to file.
     battery_save_callback: Option<&'a dyn Fn(&[u8])>,
     speed_mode: SpeedMode,
+    /// Base path of the save state files; each slot replaces its extension with `ssN`.
+    save_state_path: Option<PathBuf>,
+    save_state_slot: u8,
+    /// Set when starting without a boot ROM, to the console whose boot ROM is skipped.
+    boot_model: Option<Model>,
+    /// CRC-32 of the loaded ROM, identifies the game in movies.
+    rom_checksum: u32,
+    movie: MovieMode,
+    /// Snapshots of the last frames, when rewinding is enabled.
+    rewind: Option<RewindBuffer>,
+    /// Recordings and screenshots started from hotkeys are named after this path.
+    capture_path_base: Option<PathBuf>,
+    screenshot_scale: u32,
+    screenshot_layers: bool,
+    audio_sample_rate: u32,
+    audio_recorder: Option<WavWriter<BufWriter<File>>>,
+    /// One per voice when the recording includes stems.
+    audio_stem_recorders: Vec<WavWriter<BufWriter<File>>>,
+    /// Format of the videos recorded with `toggle_video_recording`.
+    video_format: VideoFormat,
+    video_recorder: Option<VideoWriter>,
 }
 
 impl<'a> Default for Gebemula<'a> {
@@ -65,12 +186,28 @@ impl<'a> Default for Gebemula<'a> {
             mem: Memory::new(apu.clone()),
             timer: Timer::default(),
             debugger: Debugger::default(),
-            cycles_per_sec: 0,
+            debugger_enabled: cfg!(debug_assertions),
             lcd: LCD::default(),
             joypad: Joypad::default(),
+            joypad_state: JoypadKey::NONE,
+            serial: Serial::default(),
             apu,
             battery_save_callback: None,
             speed_mode: SpeedMode::Normal,
+            save_state_path: None,
+            save_state_slot: 0,
+            boot_model: None,
+            rom_checksum: 0,
+            movie: MovieMode::Off,
+            rewind: None,
+            capture_path_base: None,
+            screenshot_scale: 1,
+            screenshot_layers: false,
+            audio_sample_rate: 0,
+            audio_recorder: None,
+            audio_stem_recorders: Vec::new(),
+            video_format: VideoFormat::Y4m,
+            video_recorder: None,
         }
     }
 }
@@ -81,26 +218,290 @@ impl<'a> Gebemula<'a> {
         self.mem.restart();
         self.lcd.restart(&mut self.mem);
         self.timer = Timer::default();
-        self.cycles_per_sec = 0;
         self.joypad = Joypad::default();
+        self.serial.restart();
+        if let Some(model) = self.boot_model {
+            self.skip_boot_rom(model);
+        }
+    }
+
+    /// Starts the cartridge right away, with the machine as the boot ROM of `model` would leave
+    /// it. Restarts skip the boot ROM as well. The cartridge has to be loaded first.
+    pub fn skip_boot_rom(&mut self, model: Model) {
+        self.boot_model = Some(model);
+        let mode = GBMode::get(&self.mem);
+        self.mem.disable_bootstrap();
+        boot::write_io_registers(model, &mut self.mem);
+        boot::draw_logo(&mut self.mem);
+        if model == Model::Cgb && mode == GBMode::Mono {
+            boot::write_compat_palettes(&mut self.mem);
+            let mut palettes = [[(0, 0, 0); 4]; 3];
+            for (palette, colors) in palettes.iter_mut().zip(boot::COMPAT_PALETTES.iter()) {
+                for (shade, &color) in palette.iter_mut().zip(colors.iter()) {
+                    *shade = graphics::cgb_color_to_rgb(color);
+                }
+            }
+            self.lcd.graphics.set_mono_palettes(palettes);
+        }
+        self.cpu
+            .skip_boot_rom(boot::cpu_registers(model, mode, &self.mem));
     }
 
     pub fn load_bootstrap_rom(&mut self, bootstrap_rom: &[u8]) {
         self.mem.load_bootstrap_rom(bootstrap_rom);
     }
 
-    pub fn load_cartridge(&mut self, game_rom: &[u8], battery: &[u8]) {
-        self.mem.load_cartridge(game_rom, battery);
+    /// Inserts a cartridge, keeping the current one if `game_rom` isn't valid.
+    pub fn load_cartridge(
+        &mut self,
+        game_rom: &[u8],
+        battery: &[u8],
+    ) -> Result<(), CartridgeError> {
+        self.mem.load_cartridge(game_rom, battery)?;
+        self.rom_checksum = util::crc32(game_rom, 0);
         if GBMode::get(&self.mem) == GBMode::Color {
             self.lcd.set_color();
         }
+        Ok(())
+    }
+
+    /// Emulates `mode` whatever the cartridge header says, or follows the header if `None`. Must
+    /// be set before loading the cartridge.
+    pub fn set_forced_mode(&mut self, mode: Option<GBMode>) {
+        self.mem.set_forced_mode(mode);
+    }
+
+    /// Sets the colors of the four DMG shades, from lightest to darkest.
+    pub fn set_palette(&mut self, palette: [(u8, u8, u8); 4]) {
+        self.lcd.graphics.set_palette(palette);
     }
 
     pub fn set_save_battery_callback(&mut self, callback: &'a dyn Fn(&[u8])) {
         self.battery_save_callback = Some(callback);
     }
 
-    fn update_battery(&mut self) {
+    /// Replaces the wall clock seen by the cartridge.
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        let mut context = self.mem.cartridge_context().clone();
+        context.clock = clock;
+        self.mem.set_cartridge_context(context);
+    }
+
+    /// Points the infrared port of the cartridge, if it has one, at something else.
+    pub fn set_infrared(&mut self, infrared: Rc<dyn Infrared>) {
+        let mut context = self.mem.cartridge_context().clone();
+        context.infrared = infrared;
+        self.mem.set_cartridge_context(context);
+    }
+
+    /// Replaces what the Pocket Camera sees.
+    pub fn set_camera(&mut self, camera: Rc<dyn ImageSource>) {
+        let mut context = self.mem.cartridge_context().clone();
+        context.camera = camera;
+        self.mem.set_cartridge_context(context);
+    }
+
+    /// Plugs something into the link port.
+    pub fn set_serial_link(&mut self, link: Box<dyn SerialLink>) {
+        self.serial.set_link(link);
+    }
+
+    pub fn set_save_state_path(&mut self, path: &Path) {
+        self.save_state_path = Some(path.to_path_buf());
+    }
+
+    pub fn set_capture_path_base(&mut self, path: &Path) {
+        self.capture_path_base = Some(path.to_path_buf());
+    }
+
+    /// First unused `<base>-N.<extension>` path.
+    fn next_capture_path(&self, extension: &str) -> Option<PathBuf> {
+        let base = self.capture_path_base.as_ref()?;
+        let file_name = base.file_name()?.to_string_lossy().into_owned();
+        (0..)
+            .map(|n| base.with_file_name(format!("{}-{}.{}", file_name, n, extension)))
+            .find(|path| !path.exists())
+    }
+
+    fn state_header(&self) -> Vec<u8> {
+        (STATE_HEADER_START_ADDR..=STATE_HEADER_END_ADDR)
+            .map(|addr| self.mem.read_cartridge(addr))
+            .collect()
+    }
+
+    /// Snapshots the whole machine.
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        for &byte in state::STATE_MAGIC.iter() {
+            writer.write_u8(byte);
+        }
+        writer.write_u32(state::STATE_VERSION);
+        writer.write_bytes(&self.state_header());
+
+        self.cpu.save_state(&mut writer);
+        self.mem.save_state(&mut writer);
+        self.timer.save_state(&mut writer);
+        self.lcd.save_state(&mut writer);
+        self.joypad.save_state(&mut writer);
+        self.serial.save_state(&mut writer);
+        self.apu.borrow().save_state(&mut writer);
+        writer.write_bool(match self.speed_mode {
+            SpeedMode::Normal => false,
+            SpeedMode::Double => true,
+        });
+        // Movies run on an emulated clock, which has to go back along with the rest.
+        writer.write_u64(self.mem.cartridge_context().clock.cycles());
+
+        writer.into_inner()
+    }
+
+    /// Restores a snapshot taken by `save_state`. The machine is left untouched if it fails.
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut reader = StateReader::new(data);
+        let mut magic = [0; 4];
+        for byte in magic.iter_mut() {
+            *byte = reader.read_u8()?;
+        }
+        if &magic != state::STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        let version = reader.read_u32()?;
+        if version != state::STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(version));
+        }
+        if reader.read_bytes()? != &self.state_header()[..] {
+            return Err(StateError::RomMismatch);
+        }
+
+        let backup = self.save_state();
+        if let Err(e) = self.load_components(&mut reader) {
+            self.load_state(&backup)
+                .expect("Unable to restore the machine after a failed state load");
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn load_components(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.cpu.load_state(reader)?;
+        self.mem.load_state(reader)?;
+        self.timer.load_state(reader)?;
+        self.lcd.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        self.serial.load_state(reader)?;
+        self.apu.borrow_mut().load_state(reader)?;
+        self.speed_mode = if reader.read_bool()? {
+            SpeedMode::Double
+        } else {
+            SpeedMode::Normal
+        };
+        self.mem.cartridge_context().clock.set_cycles(reader.read_u64()?);
+        Ok(())
+    }
+
+    /// Keeps a snapshot of every frame of the last `seconds` seconds, in at most `max_size`
+    /// bytes, so that `rewind_frame` can go back to them. Zero seconds disables rewinding.
+    pub fn set_rewind(&mut self, seconds: usize, max_size: usize) {
+        self.rewind = if seconds > 0 {
+            Some(RewindBuffer::new(seconds * FRAMES_PER_SECOND, max_size))
+        } else {
+            None
+        };
+    }
+
+    /// The machine state along with the screen, which isn't part of the state.
+    fn rewind_snapshot(&self) -> Vec<u8> {
+        let mut snapshot = self.save_state();
+        snapshot.extend_from_slice(&self.lcd.graphics.screen_buffer);
+        snapshot
+    }
+
+    /// Forgets the frames before the current one, which becomes the furthest rewinding can go.
+    fn restart_rewind(&mut self) {
+        if self.rewind.is_some() {
+            let snapshot = self.rewind_snapshot();
+            if let Some(ref mut rewind) = self.rewind {
+                rewind.clear();
+                rewind.push(snapshot);
+            }
+        }
+    }
+
+    /// Goes back to the state and screen of the previous frame. Returns whether there was one.
+    /// Movies can't go back past their first frame.
+    pub fn rewind_frame(&mut self) -> bool {
+        match self.movie {
+            MovieMode::Recording { ref movie, .. } if movie.inputs.is_empty() => return false,
+            MovieMode::Playing { frame: 0, .. } => return false,
+            _ => {}
+        }
+        let snapshot = match self.rewind.as_mut().and_then(|rewind| rewind.pop()) {
+            Some(snapshot) => snapshot.to_vec(),
+            None => return false,
+        };
+        let screen_start = snapshot.len() - self.lcd.graphics.screen_buffer.len();
+        let (state, screen) = snapshot.split_at(screen_start);
+        if let Err(e) = self.load_state(state) {
+            println!("Unable to rewind: {}", e);
+            if let Some(ref mut rewind) = self.rewind {
+                rewind.clear();
+            }
+            return false;
+        }
+        self.lcd.graphics.screen_buffer.copy_from_slice(screen);
+        // The frame is undone as if it never happened, so the movie stays in sync.
+        match self.movie {
+            MovieMode::Off => {}
+            MovieMode::Recording { ref mut movie, .. } => {
+                movie.inputs.pop();
+            }
+            MovieMode::Playing { ref mut frame, .. } => *frame = frame.saturating_sub(1),
+        }
+        true
+    }
+
+    /// Save state slots live next to the battery file, with extensions ss0-ss9.
+    fn save_state_slot_path(&self) -> Option<PathBuf> {
+        self.save_state_path
+            .as_ref()
+            .map(|path| path.with_extension(format!("ss{}", self.save_state_slot)))
+    }
+
+    pub fn save_state_to_slot(&mut self) {
+        if let Some(path) = self.save_state_slot_path() {
+            let data = self.save_state();
+            match File::create(&path).and_then(|mut f| f.write_all(&data)) {
+                Ok(_) => println!("Saved state {}: {}", self.save_state_slot, path.display()),
+                Err(e) => println!("Unable to save state to {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Refused while a movie is running, since movies don't record state loads.
+    pub fn load_state_from_slot(&mut self) {
+        if !matches!(self.movie, MovieMode::Off) {
+            println!("Unable to load a save state while a movie is running");
+            return;
+        }
+        if let Some(path) = self.save_state_slot_path() {
+            let mut data = Vec::new();
+            if let Err(e) = File::open(&path).and_then(|mut f| f.read_to_end(&mut data)) {
+                println!("Unable to open save state {}: {}", path.display(), e);
+                return;
+            }
+            match self.load_state(&data) {
+                Ok(_) => println!("Loaded state {}: {}", self.save_state_slot, path.display()),
+                Err(e) => println!("Unable to load state {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    pub fn select_save_state_slot(&mut self, slot: u8) {
+        self.save_state_slot = slot % NUM_SAVE_STATE_SLOTS;
+        println!("save state slot: {}", self.save_state_slot);
+    }
+
+    pub fn update_battery(&mut self) {
         if let Some(ref callback) = self.battery_save_callback {
             let data = self.mem.save_battery();
             if !data.is_empty() {
@@ -109,6 +510,409 @@ impl<'a> Gebemula<'a> {
         }
     }
 
+    pub fn set_debugger_enabled(&mut self, enabled: bool) {
+        self.debugger_enabled = enabled;
+    }
+
+    /// True once the user asked the debugger to quit.
+    pub fn exit_requested(&self) -> bool {
+        self.debugger.exit
+    }
+
+    pub fn display_info(&self) {
+        self.debugger.display_info(&self.mem);
+    }
+
+    pub fn cancel_debugger_run(&mut self) {
+        self.debugger.cancel_run();
+    }
+
+    pub fn bypass_nintendo_logo(&mut self) {
+        self.cpu.bypass_nintendo_logo(&mut self.mem);
+    }
+
+    pub fn toggle_bg(&mut self) {
+        self.lcd.graphics.toggle_bg();
+    }
+
+    pub fn toggle_wn(&mut self) {
+        self.lcd.graphics.toggle_wn();
+    }
+
+    pub fn toggle_sprites(&mut self) {
+        self.lcd.graphics.toggle_sprites();
+    }
+
+    /// Mutes or unmutes an audio channel (0-3). Returns whether it is now audible.
+    pub fn toggle_audio_channel(&mut self, channel: usize) -> bool {
+        self.apu.borrow_mut().debug_toggle_channel(channel)
+    }
+
+    pub fn set_audio_sample_rate(&mut self, sample_rate: u32) {
+        self.apu.borrow_mut().set_sample_rate(sample_rate);
+        self.audio_sample_rate = sample_rate;
+        let recorders = self.audio_recorder.iter_mut().chain(self.audio_stem_recorders.iter_mut());
+        for recorder in recorders {
+            recorder.set_sample_rate(sample_rate);
+        }
+    }
+
+    fn create_wav(&self, path: &Path) -> io::Result<WavWriter<BufWriter<File>>> {
+        let file = BufWriter::new(File::create(path)?);
+        WavWriter::new(file, self.audio_sample_rate, sound::OUTPUT_CHANNELS as u16)
+    }
+
+    /// Records all audio drained with `drain_audio` to a WAV file at `path`. With `stems`, each
+    /// voice is also recorded on its own next to it, to `<name>-ch1.wav` through `<name>-ch4.wav`,
+    /// whether it is muted in the mix or not.
+    pub fn start_audio_recording(&mut self, path: &Path, stems: bool) -> io::Result<()> {
+        self.stop_audio_recording();
+        let recorder = self.create_wav(path)?;
+        let mut stem_recorders = Vec::new();
+        if stems {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            for ch in 1..=4 {
+                let stem_path = path.with_file_name(format!("{}-ch{}.wav", name, ch));
+                stem_recorders.push(self.create_wav(&stem_path)?);
+            }
+        }
+        self.audio_recorder = Some(recorder);
+        self.audio_stem_recorders = stem_recorders;
+        self.apu.borrow_mut().set_stems_enabled(stems);
+        println!("Recording audio to {}", path.display());
+        Ok(())
+    }
+
+    pub fn stop_audio_recording(&mut self) {
+        if let Some(recorder) = self.audio_recorder.take() {
+            self.apu.borrow_mut().set_stems_enabled(false);
+            let stem_recorders = self.audio_stem_recorders.drain(..);
+            let result = stem_recorders
+                .map(|stem| stem.finish().map(|_| ()))
+                .fold(recorder.finish().map(|_| ()), |result, stem| result.and(stem));
+            match result {
+                Ok(_) => println!("Stopped recording audio"),
+                Err(e) => println!("Unable to finish audio recording: {}", e),
+            }
+        }
+    }
+
+    /// Starts recording audio to a new file next to the game, or stops the current recording.
+    pub fn toggle_audio_recording(&mut self, stems: bool) {
+        if self.audio_recorder.is_some() {
+            self.stop_audio_recording();
+        } else if let Some(path) = self.next_capture_path("wav") {
+            if let Err(e) = self.start_audio_recording(&path, stems) {
+                println!("Unable to record audio to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    pub fn set_video_format(&mut self, format: VideoFormat) {
+        self.video_format = format;
+    }
+
+    /// Records every frame finished by `run_frame` to a video at `path`. The audio drained with
+    /// `drain_audio` goes to a WAV file with the same name, replacing any audio recording in
+    /// progress, so that both tracks start and end on the same frame.
+    pub fn start_video_recording(&mut self, path: &Path, format: VideoFormat) -> io::Result<()> {
+        self.stop_video_recording();
+        let width = graphics::consts::DISPLAY_WIDTH_PX as u32;
+        let height = graphics::consts::DISPLAY_HEIGHT_PX as u32;
+        let recorder = VideoWriter::create(path, format, width, height)?;
+        self.start_audio_recording(&path.with_extension("wav"), false)?;
+        self.video_recorder = Some(recorder);
+        println!("Recording video to {}", path.display());
+        Ok(())
+    }
+
+    pub fn stop_video_recording(&mut self) {
+        if let Some(recorder) = self.video_recorder.take() {
+            match recorder.finish() {
+                Ok(_) => println!("Stopped recording video"),
+                Err(e) => println!("Unable to finish video recording: {}", e),
+            }
+            self.stop_audio_recording();
+        }
+    }
+
+    /// Starts recording video to a new file next to the game, or stops the current recording.
+    pub fn toggle_video_recording(&mut self) {
+        if self.video_recorder.is_some() {
+            self.stop_video_recording();
+            return;
+        }
+        let extension = match self.video_format {
+            VideoFormat::Y4m => "y4m",
+            VideoFormat::RawRgb => "rgb",
+            VideoFormat::Apng => "apng",
+        };
+        if let Some(path) = self.next_capture_path(extension) {
+            if let Err(e) = self.start_video_recording(&path, self.video_format) {
+                println!("Unable to record video to {}: {}", path.display(), e);
+            }
+        }
+    }
+
+    /// Finishes every recording in progress. Must be called before exiting.
+    pub fn finish_recordings(&mut self) {
+        if let MovieMode::Recording { .. } = self.movie {
+            self.stop_movie();
+        }
+        self.stop_video_recording();
+        self.stop_audio_recording();
+    }
+
+    /// Sets the complete joypad state for the next frames: every key in `pressed` is held down, all
+    /// others released. Ignored while a movie is playing.
+    pub fn set_joypad_state(&mut self, pressed: JoypadKey) {
+        self.joypad_state = pressed;
+    }
+
+    /// Sets how far the console is tilted, for cartridges with an accelerometer. Stays until the
+    /// next call. Ignored while a movie is playing.
+    pub fn set_tilt(&mut self, tilt: Tilt) {
+        self.mem.cartridge_context().tilt.set(tilt.clamped());
+    }
+
+    /// Records the joypad state of every frame to a movie at `path`, starting either from the
+    /// current state or after a restart. The cartridge clock stops following the host until the
+    /// recording stops, so that the run can be reproduced exactly.
+    pub fn start_movie_recording(
+        &mut self,
+        path: &Path,
+        start: MovieStart,
+    ) -> Result<(), MovieError> {
+        if self.mem.cartridge_context().camera.is_live() {
+            return Err(MovieError::LiveCamera);
+        }
+        self.stop_movie();
+        if start == MovieStart::PowerOn {
+            self.restart();
+        }
+        let start_time = time::get_time().sec;
+        self.set_clock(Rc::new(EmulatedClock::new(start_time)));
+        let movie = Movie {
+            rom_checksum: self.rom_checksum,
+            start,
+            start_time,
+            start_state: self.save_state(),
+            inputs: Vec::new(),
+            end_state_checksum: None,
+        };
+        self.movie = MovieMode::Recording {
+            movie,
+            path: path.to_path_buf(),
+        };
+        self.restart_rewind();
+        println!("Recording movie to {}", path.display());
+        Ok(())
+    }
+
+    /// Replays `movie` from its starting state. The joypad follows the movie until it ends.
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), MovieError> {
+        if movie.rom_checksum != self.rom_checksum {
+            return Err(MovieError::RomMismatch);
+        }
+        if movie.inputs.is_empty() {
+            return Err(MovieError::Empty);
+        }
+        if self.mem.cartridge_context().camera.is_live() {
+            return Err(MovieError::LiveCamera);
+        }
+        self.stop_movie();
+        self.set_clock(Rc::new(EmulatedClock::new(movie.start_time)));
+        self.load_state(&movie.start_state)?;
+        println!("Playing movie ({} frames)", movie.inputs.len());
+        self.movie = MovieMode::Playing { movie, frame: 0 };
+        self.restart_rewind();
+        Ok(())
+    }
+
+    /// Stops the movie being recorded or played back. Recordings are written to disk.
+    pub fn stop_movie(&mut self) {
+        match std::mem::replace(&mut self.movie, MovieMode::Off) {
+            MovieMode::Off => return,
+            MovieMode::Recording { mut movie, path } => {
+                movie.end_state_checksum = Some(util::crc32(&self.save_state(), 0));
+                match movie.save(&path) {
+                    Ok(_) => println!(
+                        "Saved movie ({} frames): {}",
+                        movie.inputs.len(),
+                        path.display()
+                    ),
+                    Err(e) => println!("Unable to save movie to {}: {}", path.display(), e),
+                }
+            }
+            MovieMode::Playing { movie, frame } => {
+                if frame < movie.inputs.len() {
+                    println!("Stopped movie at frame {}", frame);
+                } else if let Some(checksum) = movie.end_state_checksum {
+                    if checksum == util::crc32(&self.save_state(), 0) {
+                        println!("Movie finished, the replay matches the recording");
+                    } else {
+                        println!("WARNING: Movie finished, but the replay desynced from the recording");
+                    }
+                } else {
+                    println!("Movie finished");
+                }
+            }
+        }
+        self.set_clock(Rc::new(HostClock));
+    }
+
+    /// Starts recording a movie from the current state to a new file next to the game, or stops
+    /// the current recording.
+    pub fn toggle_movie_recording(&mut self) {
+        if let MovieMode::Recording { .. } = self.movie {
+            self.stop_movie();
+        } else if let Some(path) = self.next_capture_path("gbm") {
+            if let Err(e) = self.start_movie_recording(&path, MovieStart::SaveState) {
+                println!("Unable to record movie: {}", e);
+            }
+        }
+    }
+
+    /// Scale of the screenshots taken with `take_screenshot`.
+    pub fn set_screenshot_scale(&mut self, scale: u32) {
+        self.screenshot_scale = scale.max(1);
+    }
+
+    /// Whether `take_screenshot` also saves the background and window layer.
+    pub fn set_screenshot_layers(&mut self, layers: bool) {
+        self.screenshot_layers = layers;
+    }
+
+    /// Writes the last finished frame to `path` as a PNG, scaled up by `scale`. With `layers`, the
+    /// background and window are also written on their own to `<name>-bgwn.png`.
+    pub fn save_screenshot(&self, path: &Path, scale: u32, layers: bool) -> io::Result<()> {
+        let width = graphics::consts::DISPLAY_WIDTH_PX as u32;
+        let height = graphics::consts::DISPLAY_HEIGHT_PX as u32;
+        let write = |path: &Path, rgba: &[u8]| -> io::Result<()> {
+            let mut file = BufWriter::new(File::create(path)?);
+            let scaled = png::scale_rgba(width, rgba, scale);
+            png::write_png(&mut file, width * scale, height * scale, &scaled)?;
+            file.flush()
+        };
+
+        write(path, self.framebuffer())?;
+        if layers {
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            let layer_path = path.with_file_name(format!("{}-bgwn.png", name));
+            write(&layer_path, &self.lcd.graphics.bg_wn_layer(&self.mem))?;
+        }
+        Ok(())
+    }
+
+    /// Saves a screenshot to a new file next to the game.
+    pub fn take_screenshot(&self) {
+        if let Some(path) = self.next_capture_path("png") {
+            match self.save_screenshot(&path, self.screenshot_scale, self.screenshot_layers) {
+                Ok(_) => println!("Saved screenshot: {}", path.display()),
+                Err(e) => println!("Unable to save screenshot to {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// The last finished frame, as 160x144 RGBA pixels.
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.lcd.graphics.screen_buffer
+    }
+
+    /// Appends all audio generated so far to `output`, as interleaved stereo samples.
+    pub fn drain_audio(&mut self, output: &mut Vec<i16>) {
+        let previous_len = output.len();
+        self.apu.borrow_mut().generate_audio(output);
+        if let Some(ref mut recorder) = self.audio_recorder {
+            let mut result = recorder.write_samples(&output[previous_len..]);
+            let mut stem = Vec::new();
+            for (ch, stem_recorder) in self.audio_stem_recorders.iter_mut().enumerate() {
+                self.apu.borrow_mut().drain_stem(ch, &mut stem);
+                result = result.and_then(|_| stem_recorder.write_samples(&stem));
+                stem.clear();
+            }
+            if let Err(e) = result {
+                println!("Unable to record audio: {}", e);
+                self.stop_audio_recording();
+            }
+        }
+    }
+
+    /// Runs the machine until the LCD enters VBlank, that is, until a whole frame is ready in
+    /// `framebuffer`. Returns the number of cycles ran.
+    pub fn run_frame(&mut self) -> u32 {
+        let tilt = &self.mem.cartridge_context().tilt;
+        let pressed = match self.movie {
+            MovieMode::Off => self.joypad_state,
+            MovieMode::Recording { ref mut movie, .. } => {
+                movie.inputs.push(MovieInput {
+                    keys: self.joypad_state,
+                    tilt: tilt.get(),
+                });
+                self.joypad_state
+            }
+            MovieMode::Playing {
+                ref movie,
+                ref mut frame,
+            } => {
+                *frame += 1;
+                let input = movie.inputs[*frame - 1];
+                tilt.set(input.tilt);
+                input.keys
+            }
+        };
+        self.joypad.press_key(pressed);
+        self.joypad.release_key(JoypadKey::all() & !pressed);
+
+        self.clear_framebuffer();
+
+        let mut cycles = 0;
+        loop {
+            cycles += self.step();
+            if self.lcd.has_entered_vblank(&self.mem) || self.debugger.exit {
+                break;
+            }
+        }
+
+        if let Some(ref mut recorder) = self.video_recorder {
+            if let Err(e) = recorder.write_frame(&self.lcd.graphics.screen_buffer, cycles) {
+                println!("Unable to record video: {}", e);
+                self.stop_video_recording();
+            }
+        }
+        if let MovieMode::Playing { ref movie, frame } = self.movie {
+            if frame == movie.inputs.len() {
+                self.stop_movie();
+            }
+        }
+        if self.rewind.is_some() {
+            let snapshot = self.rewind_snapshot();
+            if let Some(ref mut rewind) = self.rewind {
+                rewind.push(snapshot);
+            }
+        }
+        cycles
+    }
+
+    fn clear_framebuffer(&mut self) {
+        let color = match GBMode::get(&self.mem) {
+            GBMode::Color => {
+                //TODO: remove hardcoded stuff?
+                (255, 255, 255) //all white
+            }
+            GBMode::Mono => self.lcd.graphics.palette()[0],
+        };
+        for p in self.lcd.graphics.screen_buffer.chunks_mut(4) {
+            // This actually makes the code faster by skipping redundant bound checking:
+            assert_eq!(p.len(), 4);
+
+            p[0] = color.0;
+            p[1] = color.1;
+            p[2] = color.2;
+            p[3] = 255;
+        }
+    }
+
     fn step(&mut self) -> u32 {
         let mut extra_cycles = 0;
         let mut cycles = 0;
@@ -149,6 +953,9 @@ impl<'a> Gebemula<'a> {
                     EventRequest::JoypadUpdate => {
                         self.joypad.update_joypad_register(&mut self.mem);
                     }
+                    EventRequest::SerialControl => {
+                        self.serial.control_written(&mut self.mem);
+                    }
                     EventRequest::SpeedModeSwitch => {
                         let key1 = self.mem.read_byte(ioregister::KEY1_REGISTER_ADDR);
                         let double_speed = key1 >> 7;
@@ -165,9 +972,12 @@ impl<'a> Gebemula<'a> {
                 SpeedMode::Double => instruction.cycles / 2,
             };
             self.cpu.handle_interrupts(&mut self.mem);
+            self.mem.cartridge_context().clock.advance(instr_cycles);
+            self.mem.update_cartridge(instr_cycles);
             self.timer.update(instr_cycles, &mut self.mem);
+            self.serial.update(instr_cycles, &mut self.mem);
             self.apu.borrow_mut().run_for(instr_cycles);
-            if cfg!(debug_assertions) {
+            if self.debugger_enabled {
                 self.debugger.run(&instruction, &self.cpu, &self.mem);
                 if self.debugger.exit {
                     break;
@@ -178,314 +988,4 @@ impl<'a> Gebemula<'a> {
         cycles += self.lcd.stat_mode_change(&mut self.mem);
         cycles
     }
-
-    fn set_joypad_key(&mut self, key: JoypadKey, code: Scancode, event_pump: &sdl2::EventPump) {
-        if event_pump.keyboard_state().is_scancode_pressed(code) {
-            self.joypad.press_key(key);
-        } else {
-            self.joypad.release_key(key);
-        }
-    }
-
-    fn adjust_joypad_keys(&mut self, event_pump: &sdl2::EventPump) {
-        self.set_joypad_key(JoypadKey::A, Scancode::Z, event_pump);
-        self.set_joypad_key(JoypadKey::B, Scancode::X, event_pump);
-        self.set_joypad_key(JoypadKey::SELECT, Scancode::LShift, event_pump);
-        self.set_joypad_key(JoypadKey::START, Scancode::LCtrl, event_pump);
-
-        self.set_joypad_key(JoypadKey::RIGHT, Scancode::Right, event_pump);
-        self.set_joypad_key(JoypadKey::LEFT, Scancode::Left, event_pump);
-        self.set_joypad_key(JoypadKey::UP, Scancode::Up, event_pump);
-        self.set_joypad_key(JoypadKey::DOWN, Scancode::Down, event_pump);
-    }
-
-    fn print_buttons() {
-        println!(" Gameboy | Keyboard");
-        println!("---------+------------");
-        println!("   dir   |  arrows");
-        println!("    A    |    Z");
-        println!("    B    |    X");
-        println!("  start  | left ctrl");
-        println!("  select | left shift");
-        println!("---------+------------");
-        println!("  U: increase speed");
-        println!("  I: decrease speed");
-        println!("  R: restart");
-        println!("  B: bypass nintendo logo");
-        println!(" F1: toggle background");
-        println!(" F2: toggle window");
-        println!(" F3: toggle sprites");
-        println!(" F4: toggle pulse A");
-        println!(" F5: toggle pulse B");
-        println!(" F6: toggle custom wave");
-        println!(" F7: toggle white noise");
-        println!("Tab: speed up while being held down");
-        println!("Esc: quit");
-        println!("######################");
-    }
-
-    pub fn run_sdl(&mut self) {
-        Gebemula::print_buttons();
-
-        let sdl_context = sdl2::init().unwrap();
-        let video_subsystem = sdl_context.video().unwrap();
-        let audio_subsystem = sdl_context.audio().unwrap();
-
-        let audio_device = audio_subsystem.open_queue(None, &AUDIO_DESIRED_SPEC).unwrap();
-        let audio_spec = audio_device.spec();
-        let mut audio_buffer = Vec::new();
-
-        self.apu.borrow_mut().set_sample_rate(audio_spec.freq as u32);
-
-        let window = video_subsystem
-            .window(
-                "Gebemula Emulator",
-                graphics::consts::DISPLAY_WIDTH_PX as u32 * 2,
-                graphics::consts::DISPLAY_HEIGHT_PX as u32 * 2,
-            )
-            .opengl()
-            .build()
-            .unwrap();
-
-        let mut canvas = window.into_canvas().build().unwrap();
-        canvas.set_draw_color(Color::RGBA(0, 0, 0, 255));
-        canvas.clear();
-
-        let texture_creator = canvas.texture_creator();
-
-        let mut texture = texture_creator
-            .create_texture_streaming(
-                PixelFormatEnum::ABGR8888,
-                graphics::consts::DISPLAY_WIDTH_PX as u32,
-                graphics::consts::DISPLAY_HEIGHT_PX as u32,
-            )
-            .unwrap();
-
-        canvas.present();
-        audio_device.resume();
-
-        let mut event_pump = sdl_context.event_pump().unwrap();
-        let mut last_time_seconds = time::now();
-        let mut last_time = time::now();
-        let mut frame_time_err = 0;
-
-        let mut speed_mul = 1;
-        let target_fps = 60;
-        let mut desired_frametime_ns = 1_000_000_000 / target_fps;
-        let mut fps = 0;
-        if !cfg!(debug_assertions) {
-            self.debugger.display_info(&self.mem);
-        }
-        'running: loop {
-            for event in event_pump.poll_iter() {
-                match event {
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::B),
-                        ..
-                    } => {
-                        self.cpu.bypass_nintendo_logo(&mut self.mem);
-                    }
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::F1),
-                        ..
-                    } => {
-                        self.lcd.graphics.toggle_bg();
-                    }
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::F2),
-                        ..
-                    } => {
-                        self.lcd.graphics.toggle_wn();
-                    }
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::F3),
-                        ..
-                    } => {
-                        self.lcd.graphics.toggle_sprites();
-                    }
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::F4),
-                        ..
-                    } => {
-                        println!("Pulse A: {:?}",
-                            self.apu.borrow_mut().debug_toggle_channel(0)
-                        );
-                    }
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::F5),
-                        ..
-                    } => {
-                        println!("Pulse B: {:?}",
-                            self.apu.borrow_mut().debug_toggle_channel(1)
-                        );
-                    }
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::F6),
-                        ..
-                    } => {
-                        println!("Custom Wave: {:?}",
-                            self.apu.borrow_mut().debug_toggle_channel(2)
-                        );
-                    }
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::F7),
-                        ..
-                    } => {
-                        //sound_controller.whitenoise_toggle();
-                    }
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::Q),
-                        ..
-                    } => {
-                        self.debugger.cancel_run();
-                    }
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::R),
-                        ..
-                    } => {
-                        self.restart();
-                        //sound_controller.reset(&mut self.mem);
-                    }
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::Tab),
-                        repeat: false,
-                        ..
-                    } => {
-                        speed_mul += 1;
-                        println!("speed x{}", speed_mul);
-                        desired_frametime_ns = 1_000_000_000 / (target_fps * speed_mul);
-                    }
-                    sdl2::event::Event::KeyUp {
-                        keycode: Some(Keycode::Tab),
-                        repeat: false,
-                        ..
-                    } => {
-                        speed_mul -= 1;
-                        println!("speed x{}", speed_mul);
-                        desired_frametime_ns = 1_000_000_000 / (target_fps * speed_mul);
-                    }
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::U),
-                        ..
-                    } => {
-                        speed_mul += 1;
-                        if speed_mul >= 15 {
-                            speed_mul = 15;
-                        }
-                        println!("speed x{}", speed_mul);
-                        desired_frametime_ns = 1_000_000_000 / (target_fps * speed_mul);
-                    }
-                    sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::I),
-                        ..
-                    } => {
-                        speed_mul -= 1;
-                        if speed_mul == 0 {
-                            speed_mul = 1;
-                        }
-                        println!("speed x{}", speed_mul);
-                        desired_frametime_ns = 1_000_000_000 / (target_fps * speed_mul);
-                    }
-                    sdl2::event::Event::Quit { .. }
-                    | sdl2::event::Event::KeyDown {
-                        keycode: Some(Keycode::Escape),
-                        ..
-                    } => break 'running,
-                    _ => {}
-                }
-            }
-
-            self.adjust_joypad_keys(&event_pump);
-            let cycles_ran = self.step();
-            self.cycles_per_sec += cycles_ran;
-
-            self.feed_audio(&audio_device, &mut audio_buffer);
-
-            if !cfg!(debug_assertions) {
-                if self.debugger.exit {
-                    break 'running;
-                }
-            }
-
-            /*
-             * Yuri Kunde Schlesner:
-             * it's just the way you do it (fps checking)  seems brittle and
-             * you'll get error depending on your timing
-             * instead of counting "each >= 1 second check how many frames
-             * were rendered and show that as fps", you should either do
-             * "each >= 1 second check how many frame were rendered / *actual*
-             * elapsed time since last reset of fps"
-             * or "each N frames, check elapsed time since last fps update and
-             * calculate based on that" fps is just 1 / frametime, so you should
-             * just try to average frametime over time to calculate it imo
-             *
-             * https://github.com/yuriks/super-match-5-dx/blob/master/src/main.cpp#L224
-             */
-            if self.lcd.has_entered_vblank(&self.mem) {
-                texture
-                    .update(
-                        None,
-                        &self.lcd.graphics.screen_buffer,
-                        graphics::consts::DISPLAY_WIDTH_PX as usize * 4,
-                    )
-                    .unwrap();
-                canvas.clear();
-                if let Err(_) = canvas.copy(&texture, None, None) {
-                    println!("Unable to draw texture to canvas!");
-                    return;
-                }
-                canvas.present();
-
-                //clear buffer
-                for p in self.lcd.graphics.screen_buffer.chunks_mut(4) {
-                    // This actually makes the code faster by skipping redundant bound checking:
-                    assert_eq!(p.len(), 4);
-
-                    let color = match GBMode::get(&self.mem) {
-                        GBMode::Color => {
-                            //TODO: remove hardcoded stuff?
-                            (255, 255, 255) //all white
-                        }
-                        GBMode::Mono => graphics::consts::DMG_PALETTE[0],
-                    };
-                    p[0] = color.0;
-                    p[1] = color.1;
-                    p[2] = color.2;
-                    p[3] = 255;
-                }
-
-                frame_time_err += desired_frametime_ns;
-                let now = time::now();
-                let elapsed = (now - last_time).num_nanoseconds().unwrap() as i64;
-                frame_time_err -= elapsed;
-                if frame_time_err > 0 {
-                    thread::sleep(std::time::Duration::new(0, frame_time_err as u32));
-                }
-                last_time = now;
-                fps += 1;
-            }
-
-            let now = time::now();
-            if now - last_time_seconds >= time::Duration::seconds(1) {
-                last_time_seconds = now;
-                let title = &format!("{} Gebemula - {}", fps, self.cycles_per_sec);
-                canvas.window_mut().set_title(title).unwrap();
-                self.cycles_per_sec = 0;
-                fps = 0;
-
-                self.update_battery();
-            }
-        }
-        self.update_battery();
-    }
-
-    fn feed_audio(&mut self, audio_device: &AudioQueue<i16>, audio_buffer: &mut Vec<i16>) {
-        self.apu.borrow_mut().generate_audio(audio_buffer);
-        let current_audio_buf = audio_device.size();
-        audio_device.queue(audio_buffer.as_ref());
-        if current_audio_buf < 128 {
-            //println!("Audio buffer underrun: {} (adding {})", current_audio_buf, audio_buffer.len());
-        }
-        audio_buffer.clear();
-    }
 }
@@ -112,22 +112,31 @@ trait RGB {
     }
 }
 
-struct MonoRGB;
+/// Colors of the four DMG shades, from lightest to darkest.
+pub type MonoPalette = [(u8, u8, u8); 4];
+
+struct MonoRGB {
+    /// Background and window, OBJ 0 and OBJ 1.
+    palettes: [MonoPalette; 3],
+}
 impl RGB for MonoRGB {
     fn rgb(&self, pixel: &TilePixel, memory: &Memory) -> (u8, u8, u8) {
         //TODO make sure this write isn't necessary.
         //memory.write_byte(ioregister::VBK_REGISTER_ADDR, 0);
-        let pixel_index = match pixel.tile_type {
-            TileType::Background | TileType::Window => {
-                ioregister::bg_window_palette(pixel.color_number, memory)
-            }
-            TileType::Sprite => ioregister::sprite_palette(
-                pixel.tile_attr.dmg_palette_number() == 0,
-                pixel.color_number,
-                memory,
+        let (palette, pixel_index) = match pixel.tile_type {
+            TileType::Background | TileType::Window => (
+                0,
+                ioregister::bg_window_palette(pixel.color_number, memory),
             ),
+            TileType::Sprite => {
+                let obp0 = pixel.tile_attr.dmg_palette_number() == 0;
+                (
+                    if obp0 { 1 } else { 2 },
+                    ioregister::sprite_palette(obp0, pixel.color_number, memory),
+                )
+            }
         };
-        consts::DMG_PALETTE[pixel_index as usize]
+        self.palettes[palette][pixel_index as usize]
     }
 
     fn mode(&self) -> GBMode {
@@ -147,6 +156,11 @@ impl ColorRGB {
         (to255(r), to255(g), to255(b))
     }
 }
+
+/// Converts a CGB RGB555 color to RGB888.
+pub fn cgb_color_to_rgb(color: u16) -> (u8, u8, u8) {
+    ColorRGB::palette_to_rgb((color >> 8) as u8, color as u8)
+}
 impl RGB for ColorRGB {
     fn rgb(&self, pixel: &TilePixel, memory: &Memory) -> (u8, u8, u8) {
         let h_addr = (pixel.tile_attr.cgb_palette_number() * 8) + 1 + (pixel.color_number * 2); // each palette uses 8 bytes.
@@ -174,6 +188,8 @@ pub struct Graphics {
     bg_on: bool,
     wn_on: bool,
     sprites_on: bool,
+    /// Colors of the four DMG shades of the background and window, OBJ 0 and OBJ 1.
+    mono_palettes: [MonoPalette; 3],
     rgb: Box<dyn RGB>,
 }
 
@@ -185,7 +201,10 @@ impl Default for Graphics {
             bg_on: true,
             wn_on: true,
             sprites_on: true,
-            rgb: Box::new(MonoRGB),
+            mono_palettes: [consts::DMG_PALETTE; 3],
+            rgb: Box::new(MonoRGB {
+                palettes: [consts::DMG_PALETTE; 3],
+            }),
         }
     }
 }
@@ -210,6 +229,36 @@ impl Graphics {
         self.rgb = Box::new(ColorRGB)
     }
 
+    /// The colors of the background and window.
+    pub fn palette(&self) -> MonoPalette {
+        self.mono_palettes[0]
+    }
+
+    /// Replaces the colors of the four DMG shades. Has no effect in color mode.
+    pub fn set_palette(&mut self, palette: MonoPalette) {
+        self.set_mono_palettes([palette; 3]);
+    }
+
+    /// Gives the background and window, OBJ 0 and OBJ 1 their own colors, like the CGB does for
+    /// DMG games. Has no effect in color mode.
+    pub fn set_mono_palettes(&mut self, palettes: [MonoPalette; 3]) {
+        self.mono_palettes = palettes;
+        if !self.rgb.is_color() {
+            self.rgb = Box::new(MonoRGB { palettes });
+        }
+    }
+
+    /// The background and window of the last frame without sprites, as RGBA pixels. Colors come
+    /// from the current palettes.
+    pub fn bg_wn_layer(&self, memory: &Memory) -> Vec<u8> {
+        let mut layer = Vec::with_capacity(self.screen_buffer.len());
+        for pixel in self.bg_wn_pixel_indexes.iter() {
+            let (r, g, b) = self.rgb.rgb(pixel, memory);
+            layer.extend_from_slice(&[r, g, b, 255]);
+        }
+        layer
+    }
+
     fn update_line_buffer(&mut self, memory: &mut Memory) {
         // we can't draw below DISPLAY_HEIGHT_PX
         let curr_line = memory.read_byte(ioregister::LY_REGISTER_ADDR);
@@ -0,0 +1,87 @@
+use std::fmt;
+
+/// A parsed INI file: `key = value` lines grouped under `[section]` headers. Lines starting with
+/// `#` or `;` are comments. Entries before the first header belong to the section named "".
+#[derive(Default)]
+pub struct Ini {
+    pub sections: Vec<Section>,
+}
+
+pub struct Section {
+    pub name: String,
+    pub entries: Vec<Entry>,
+}
+
+pub struct Entry {
+    pub key: String,
+    pub value: String,
+    /// 1-based, for error messages.
+    pub line: usize,
+}
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl Ini {
+    pub fn parse(text: &str) -> Result<Ini, ParseError> {
+        let mut ini = Ini::default();
+        for (i, line) in text.lines().enumerate() {
+            let line_num = i + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') || line.starts_with(';') {
+                continue;
+            }
+            if line.starts_with('[') {
+                if !line.ends_with(']') {
+                    return Err(ParseError {
+                        line: line_num,
+                        message: "unterminated section header".to_owned(),
+                    });
+                }
+                ini.sections.push(Section {
+                    name: line[1..line.len() - 1].trim().to_owned(),
+                    entries: Vec::new(),
+                });
+                continue;
+            }
+            let (key, value) = match line.find('=') {
+                Some(pos) => (line[..pos].trim(), line[pos + 1..].trim()),
+                None => {
+                    return Err(ParseError {
+                        line: line_num,
+                        message: format!("expected `key = value`, found `{}`", line),
+                    });
+                }
+            };
+            if ini.sections.is_empty() {
+                ini.sections.push(Section {
+                    name: String::new(),
+                    entries: Vec::new(),
+                });
+            }
+            ini.sections.last_mut().unwrap().entries.push(Entry {
+                key: key.to_owned(),
+                value: value.to_owned(),
+                line: line_num,
+            });
+        }
+        Ok(ini)
+    }
+
+    /// All entries of the sections called `name`.
+    pub fn entries<'a>(&'a self, name: &'a str) -> impl Iterator<Item = &'a Entry> + 'a {
+        self.sections
+            .iter()
+            .filter(move |section| section.name == name)
+            .flat_map(|section| section.entries.iter())
+    }
+}
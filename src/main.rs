@@ -3,24 +3,50 @@ extern crate arrayref;
 #[macro_use]
 extern crate bitflags;
 extern crate blip_buf;
+#[macro_use]
 extern crate clap;
+#[cfg(feature = "sdl")]
 extern crate sdl2;
 extern crate time;
 
+mod boot;
+mod clock;
+mod config;
 mod cpu;
 mod debugger;
+mod frontend;
 mod gebemula;
 mod graphics;
+mod ini;
 mod mem;
+mod movie;
 mod peripherals;
+mod recording;
+mod rewind;
+mod state;
 mod util;
 
-use clap::{App, Arg};
-use std::fs::File;
-use std::io::{Read, Write};
-use std::path::Path;
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
 
-use crate::gebemula::Gebemula;
+use crate::config::Config;
+use crate::gebemula::{self as gb, Gebemula, LoadError, Model};
+use crate::mem::cartridge::{self, CartridgeHeader};
+use crate::mem::camera::still::StillImage;
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+use crate::mem::camera::v4l2::V4l2Camera;
+use crate::mem::{infrared, ImageSource};
+use crate::peripherals::serial::capture::CaptureLink;
+use crate::peripherals::serial::loopback::LoopbackLink;
+use crate::peripherals::serial::tcp::TcpLink;
+use crate::peripherals::tilt::TiltScript;
+use crate::movie::{Movie, MovieStart};
+use crate::recording::video::VideoFormat;
 
 fn main() {
     let args = App::new("Gebemula")
@@ -31,6 +57,17 @@ fn main() {
              Yuri Kunde Schlesner <yuriks@yuriks.net>",
         )
         .about("Emulator for GameBoy written in Rust.")
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .subcommand(
+            SubCommand::with_name("info")
+                .about("Prints and validates the cartridge header of a ROM.")
+                .arg(
+                    Arg::with_name("ROM")
+                        .index(1)
+                        .required(true)
+                        .help("Path to the game ROM."),
+                ),
+        )
         .arg(
             Arg::with_name("INPUT_ROM")
                 .index(1)
@@ -41,56 +78,379 @@ fn main() {
             Arg::with_name("bootstrap_rom")
                 .short("b")
                 .long("bootstrap")
-                .help("Sets the path to the Gameboy bootstrap ROM.")
+                .help("Sets the path to the Gameboy bootstrap ROM, whatever the model.")
                 .value_name("DMG_ROM.bin")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("no_boot_rom")
+                .long("no-boot-rom")
+                .help("Starts the game right away, as the boot ROM would leave the console.")
+                .conflicts_with("bootstrap_rom"),
+        )
+        .arg(
+            Arg::with_name("config")
+                .long("config")
+                .help(
+                    "Reads the settings from this file instead of gebemula.ini in the user's \
+                     configuration directory. It's created with the defaults if it doesn't exist.",
+                )
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("scale")
+                .long("scale")
+                .help("Scales the window up by this integer factor.")
+                .value_name("N")
+                .validator(|value| config::parse_scale(&value).map(|_| ()))
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fullscreen")
+                .long("fullscreen")
+                .help("Starts in fullscreen."),
+        )
+        .arg(
+            Arg::with_name("palette")
+                .long("palette")
+                .help("Colors of the four original Game Boy shades, from lightest to darkest.")
+                .value_name("#RRGGBB,#RRGGBB,#RRGGBB,#RRGGBB")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sample_rate")
+                .long("sample-rate")
+                .help("Sets the audio sample rate.")
+                .value_name("HZ")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("audio_buffer")
+                .long("audio-buffer")
+                .help("Sets the number of samples per audio buffer, 0 leaves it to the driver.")
+                .value_name("SAMPLES")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("model")
+                .long("model")
+                .help("Forces the hardware model instead of following the cartridge header.")
+                .possible_values(&["auto", "dmg", "mgb", "cgb"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dmg_boot_rom")
+                .long("dmg-boot-rom")
+                .help("Sets the path to the boot ROM of the original Game Boy.")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mgb_boot_rom")
+                .long("mgb-boot-rom")
+                .help("Sets the path to the boot ROM of the Game Boy Pocket.")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cgb_boot_rom")
+                .long("cgb-boot-rom")
+                .help("Sets the path to the boot ROM of the Game Boy Color.")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("save_dir")
+                .long("save-dir")
+                .help("Keeps battery saves and save states in this directory.")
+                .value_name("DIR")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("serial")
+                .long("serial")
+                .help("Sets what is plugged into the link port.")
+                .possible_values(&["disconnected", "capture", "loopback"])
+                .default_value("disconnected")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("serial_log")
+                .long("serial-log")
+                .help("Writes the bytes sent through the link port to this file instead of stdout.")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("link_listen")
+                .long("link-listen")
+                .help("Waits for another instance to connect its link cable to this address.")
+                .value_name("HOST:PORT")
+                .takes_value(true)
+                .conflicts_with_all(&["serial", "link_connect"]),
+        )
+        .arg(
+            Arg::with_name("link_connect")
+                .long("link-connect")
+                .help("Connects the link cable to another instance listening on this address.")
+                .value_name("HOST:PORT")
+                .takes_value(true)
+                .conflicts_with("serial"),
+        )
+        .arg(
+            Arg::with_name("infrared")
+                .long("infrared")
+                .help("What the infrared port of HuC1 and HuC3 cartridges points at.")
+                .possible_values(&["none", "mirror"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("camera")
+                .long("camera")
+                .help(
+                    "What the Pocket Camera sees: \"pattern\", a V4L2 device such as /dev/video0, \
+                     or a binary PGM/PPM image. Devices are only opened when given here, and can't \
+                     be used with movies.",
+                )
+                .value_name("SOURCE")
+                .default_value("pattern")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("record_audio")
+                .long("record-audio")
+                .help("Records the audio output to this WAV file.")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("record_stems")
+                .long("record-stems")
+                .help("Also records each voice to its own file next to the audio recording.")
+                .requires("record_audio"),
+        )
+        .arg(
+            Arg::with_name("record_video")
+                .long("record-video")
+                .help(
+                    "Records every frame to this video file, with the audio next to it. The \
+                     format depends on the extension: .y4m, .rgb (raw RGB24) or .apng.",
+                )
+                .value_name("FILE")
+                .takes_value(true)
+                .conflicts_with("record_audio"),
+        )
+        .arg(
+            Arg::with_name("video_format")
+                .long("video-format")
+                .help("Sets the format of the videos recorded with the hotkey.")
+                .possible_values(&["y4m", "rgb", "apng"])
+                .default_value("y4m")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("record_movie")
+                .long("record-movie")
+                .help("Records the input of every frame to this movie file, starting at power-on.")
+                .value_name("FILE")
+                .takes_value(true)
+                .conflicts_with("play_movie"),
+        )
+        .arg(
+            Arg::with_name("movie_start_state")
+                .long("movie-start-state")
+                .help("Starts the movie recording from this save state instead of power-on.")
+                .value_name("FILE")
+                .takes_value(true)
+                .requires("record_movie"),
+        )
+        .arg(
+            Arg::with_name("play_movie")
+                .long("play-movie")
+                .help("Replays the input recorded in this movie file.")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("screenshot")
+                .long("screenshot")
+                .help("Saves the last frame to this PNG file when exiting.")
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("screenshot_scale")
+                .long("screenshot-scale")
+                .help("Scales screenshots up by this integer factor.")
+                .value_name("N")
+                .validator(|value| config::parse_scale(&value).map(|_| ()))
+                .default_value("1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("screenshot_layers")
+                .long("screenshot-layers")
+                .help("Also saves the background and window layer with every screenshot."),
+        )
+        .arg(
+            Arg::with_name("rewind_seconds")
+                .long("rewind-seconds")
+                .help("How far back the rewind key can go. 0 disables rewinding.")
+                .value_name("N")
+                .default_value("10")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rewind_memory")
+                .long("rewind-memory")
+                .help("Memory budget of the rewind buffer, in MiB.")
+                .value_name("MIB")
+                .default_value("64")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("bindings")
+                .long("bindings")
+                .help(
+                    "Reads the key bindings from this file, which is created with the defaults if \
+                     it doesn't exist. The built-in bindings are used by default.",
+                )
+                .value_name("FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("headless")
+                .long("headless")
+                .help("Runs without opening a window or an audio device."),
+        )
+        .arg(
+            Arg::with_name("frames")
+                .long("frames")
+                .help("Stops after running this many frames in headless mode.")
+                .value_name("N")
+                .takes_value(true)
+                .requires("headless"),
+        )
+        .arg(
+            Arg::with_name("tilt")
+                .long("tilt")
+                .help(
+                    "Tilts the console for MBC7 cartridges in headless mode, as \
+                     whitespace-separated [FRAME:]X,Y entries in g, each applying from its frame \
+                     on (e.g. \"0.5,0 120:-0.5,0\"). X is positive to the right, Y away from the \
+                     player.",
+                )
+                .value_name("SCRIPT")
+                .takes_value(true)
+                .requires("headless"),
+        )
         .get_matches();
 
+    if let Some(args) = args.subcommand_matches("info") {
+        print_info(args);
+        return;
+    }
+
+    // Only a file given on the command line is created when missing.
+    let config_path = match args.value_of("config") {
+        Some(path) => Some((PathBuf::from(path), true)),
+        None => Config::default_path().map(|path| (path, false)),
+    };
+    let mut config = match config_path {
+        Some((path, create)) => Config::load(&path, create).unwrap_or_else(|e| {
+            println!(
+                "Unable to load configuration from {}: {}. Using the defaults.",
+                path.display(),
+                e
+            );
+            Config::default()
+        }),
+        None => Config::default(),
+    };
+    let invalid_value = |message: String| -> ! {
+        clap::Error::with_description(&message, clap::ErrorKind::InvalidValue).exit()
+    };
+    if args.is_present("scale") {
+        config.scale = value_t!(args, "scale", u32).unwrap_or_else(|e| e.exit());
+    }
+    if args.is_present("fullscreen") {
+        config.fullscreen = true;
+    }
+    if let Some(palette) = args.value_of("palette") {
+        config.palette = config::parse_palette(palette).unwrap_or_else(|e| invalid_value(e));
+    }
+    if args.is_present("sample_rate") {
+        config.sample_rate = value_t!(args, "sample_rate", u32).unwrap_or_else(|e| e.exit());
+    }
+    if args.is_present("audio_buffer") {
+        let samples = value_t!(args, "audio_buffer", u16).unwrap_or_else(|e| e.exit());
+        config.audio_buffer_size = if samples == 0 { None } else { Some(samples) };
+    }
+    if let Some(model) = args.value_of("model") {
+        config.model = config::parse_model(model).unwrap_or_else(|e| invalid_value(e));
+    }
+    if let Some(path) = args.value_of("dmg_boot_rom") {
+        config.dmg_boot_rom = PathBuf::from(path);
+    }
+    if let Some(path) = args.value_of("mgb_boot_rom") {
+        config.mgb_boot_rom = PathBuf::from(path);
+    }
+    if let Some(path) = args.value_of("cgb_boot_rom") {
+        config.cgb_boot_rom = PathBuf::from(path);
+    }
+    if let Some(path) = args.value_of("save_dir") {
+        config.save_dir = Some(PathBuf::from(path));
+    }
+    if let Some(path) = args.value_of("bindings") {
+        config.bindings = Some(PathBuf::from(path));
+    }
+
     let rom_path = Path::new(args.value_of("INPUT_ROM").unwrap());
+    let game_data = gb::read_file(rom_path).unwrap_or_else(|e| exit_with_error(e));
+
+    let model = config
+        .model
+        .unwrap_or_else(|| Model::from_rom(&game_data));
     let bootstrap_path = match args.value_of("bootstrap_rom") {
         Some(boot_rom) => Path::new(boot_rom),
+        None => config.boot_rom(model),
+    };
+    let bootstrap_data = if args.is_present("no_boot_rom") {
+        Vec::new()
+    } else {
+        gb::read_file(bootstrap_path).unwrap_or_else(|e| {
+            println!("Unable to load the boot ROM ({}), starting without it.", e);
+            Vec::new()
+        })
+    };
+
+    let rom_file_name = rom_path.file_name().unwrap().to_string_lossy();
+    let (battery_path, save_state_path) = match config.save_dir {
+        Some(ref save_dir) => {
+            fs::create_dir_all(save_dir).unwrap_or_else(|e| {
+                exit_with_error(format!("{}: {}", save_dir.display(), e))
+            });
+            let save_path = save_dir.join(&*rom_file_name);
+            (save_path.with_extension("sav"), save_path)
+        }
         None => {
-            if rom_path.extension().unwrap() == "gb" {
-                Path::new("DMG_ROM.bin")
-            } else {
-                Path::new("CGB_ROM.bin")
-            }
+            // battery files should start with '.'.
+            let save_path = rom_path.with_file_name(format!(".{}", rom_file_name));
+            (save_path.with_extension("sav"), save_path)
         }
     };
-    // battery files should start with '.'.
-    let rom_file_name = rom_path.file_name().unwrap().to_str().unwrap();
-    let battery_file_name = &format!(".{}", rom_file_name);
-    let battery_path = rom_path
-        .with_file_name(battery_file_name)
-        .with_extension("sav");
-
-    let mut bootstrap_data = Vec::new();
-    File::open(bootstrap_path)
-        .expect("Unable to open Bootstrap Rom")
-        .read_to_end(&mut bootstrap_data)
-        .unwrap();
-
-    let mut game_data = Vec::new();
-    File::open(rom_path)
-        .expect("Unable to open Game Rom")
-        .read_to_end(&mut game_data)
-        .unwrap();
 
     let mut battery_data = Vec::new();
     if battery_path.exists() {
+        battery_data = gb::read_file(&battery_path).unwrap_or_else(|e| exit_with_error(e));
         println!("Loaded battery: {}", battery_path.display());
-        File::open(&battery_path)
-            .expect("Unable to open Save file")
-            .read_to_end(&mut battery_data)
-            .unwrap();
     }
 
     let save_battery_callback = |data: &[u8]| {
-        File::create(&battery_path)
-            .unwrap()
-            .write_all(data)
-            .unwrap();
+        if let Err(e) = File::create(&battery_path).and_then(|mut file| file.write_all(data)) {
+            println!("Unable to save battery to {}: {}", battery_path.display(), e);
+        }
         // Some games use SRAM as non-save scratch space, so this tends to get a bit spammy:
         //println!("Saved battery: {}", battery_path.display());
     };
@@ -98,7 +458,175 @@ fn main() {
     // This variable needs to be boxed since it's large and causes a stack overflow in Windows
     let mut gebemula = Box::new(Gebemula::default());
     gebemula.set_save_battery_callback(&save_battery_callback);
+    gebemula.set_save_state_path(&save_state_path);
+    gebemula.set_capture_path_base(&rom_path.with_extension(""));
+    gebemula.set_forced_mode(config.model.map(|model| model.mode(&game_data)));
+    gebemula.set_palette(config.palette);
     gebemula.load_bootstrap_rom(&bootstrap_data);
-    gebemula.load_cartridge(&game_data, &battery_data);
-    gebemula.run_sdl();
+    gebemula
+        .load_cartridge(&game_data, &battery_data)
+        .unwrap_or_else(|e| exit_with_error(LoadError::from(e)));
+    if bootstrap_data.is_empty() {
+        gebemula.skip_boot_rom(model);
+    }
+
+    match args.value_of("serial") {
+        Some("capture") => match args.value_of("serial_log") {
+            Some(log_path) => {
+                let log = File::create(log_path).unwrap_or_else(|e| {
+                    exit_with_error(format!("Unable to create serial log: {}", e))
+                });
+                gebemula.set_serial_link(Box::new(CaptureLink::new(log)));
+            }
+            None => gebemula.set_serial_link(Box::new(CaptureLink::new(io::stdout()))),
+        },
+        Some("loopback") => gebemula.set_serial_link(Box::new(LoopbackLink::default())),
+        _ => {}
+    }
+    if let Some(addr) = args.value_of("link_listen") {
+        let link = TcpLink::listen(addr).unwrap_or_else(|e| {
+            exit_with_error(format!("Unable to listen for the link cable: {}", e))
+        });
+        gebemula.set_serial_link(Box::new(link));
+    } else if let Some(addr) = args.value_of("link_connect") {
+        let link = TcpLink::connect(addr).unwrap_or_else(|e| {
+            exit_with_error(format!("Unable to connect the link cable: {}", e))
+        });
+        gebemula.set_serial_link(Box::new(link));
+    }
+    if args.value_of("infrared") == Some("mirror") {
+        gebemula.set_infrared(Rc::new(infrared::Mirror::default()));
+    }
+    match args.value_of("camera").unwrap() {
+        // The cartridge starts out with the test pattern.
+        "pattern" => {}
+        device if device.starts_with("/dev/") => {
+            gebemula.set_camera(video_device(Path::new(device)));
+        }
+        path => {
+            let image = StillImage::load(Path::new(path))
+                .unwrap_or_else(|e| exit_with_error(format!("{}: {}", path, e)));
+            gebemula.set_camera(Rc::new(image));
+        }
+    }
+    if let Some(path) = args.value_of("record_movie") {
+        let start = match args.value_of("movie_start_state") {
+            Some(state_path) => {
+                let state_data = gb::read_file(Path::new(state_path))
+                    .unwrap_or_else(|e| exit_with_error(e));
+                gebemula.load_state(&state_data).unwrap_or_else(|e| {
+                    exit_with_error(format!("Unable to load the movie start state: {}", e))
+                });
+                MovieStart::SaveState
+            }
+            None => MovieStart::PowerOn,
+        };
+        gebemula
+            .start_movie_recording(Path::new(path), start)
+            .unwrap_or_else(|e| exit_with_error(format!("Unable to record movie: {}", e)));
+    } else if let Some(path) = args.value_of("play_movie") {
+        let movie = Movie::load(Path::new(path))
+            .unwrap_or_else(|e| exit_with_error(format!("Unable to load movie: {}", e)));
+        gebemula
+            .play_movie(movie)
+            .unwrap_or_else(|e| exit_with_error(format!("Unable to play movie: {}", e)));
+    }
+    if let Some(path) = args.value_of("record_audio") {
+        gebemula
+            .start_audio_recording(Path::new(path), args.is_present("record_stems"))
+            .unwrap_or_else(|e| {
+                exit_with_error(format!("Unable to create audio recording: {}", e))
+            });
+    }
+    gebemula.set_video_format(match args.value_of("video_format") {
+        Some("rgb") => VideoFormat::RawRgb,
+        Some("apng") => VideoFormat::Apng,
+        _ => VideoFormat::Y4m,
+    });
+    if let Some(path) = args.value_of("record_video") {
+        let path = Path::new(path);
+        let format = VideoFormat::from_path(path).unwrap_or_else(|| {
+            exit_with_error(format!("Unknown video format: {}", path.display()))
+        });
+        gebemula
+            .start_video_recording(path, format)
+            .unwrap_or_else(|e| {
+                exit_with_error(format!("Unable to create video recording: {}", e))
+            });
+    }
+
+    let screenshot_scale = value_t!(args, "screenshot_scale", u32).unwrap_or_else(|e| e.exit());
+    let screenshot_layers = args.is_present("screenshot_layers");
+    gebemula.set_screenshot_scale(screenshot_scale);
+    gebemula.set_screenshot_layers(screenshot_layers);
+
+    if args.is_present("headless") {
+        let frames = if args.is_present("frames") {
+            Some(value_t!(args, "frames", u64).unwrap_or_else(|e| e.exit()))
+        } else {
+            None
+        };
+        let tilt = match args.value_of("tilt") {
+            Some(script) => TiltScript::parse(script).unwrap_or_else(|e| exit_with_error(e)),
+            None => TiltScript::default(),
+        };
+        frontend::headless::run(&mut gebemula, frames, &tilt);
+    } else {
+        let rewind_seconds = value_t!(args, "rewind_seconds", usize).unwrap_or_else(|e| e.exit());
+        let rewind_memory = value_t!(args, "rewind_memory", usize).unwrap_or_else(|e| e.exit());
+        gebemula.set_rewind(rewind_seconds, rewind_memory << 20);
+        run_interactive(&mut gebemula, &config);
+    }
+
+    if let Some(path) = args.value_of("screenshot") {
+        gebemula
+            .save_screenshot(Path::new(path), screenshot_scale, screenshot_layers)
+            .unwrap_or_else(|e| println!("Unable to save screenshot: {}", e));
+    }
+}
+
+/// Prints the header of a ROM, exiting with an error if it's invalid.
+fn print_info(args: &ArgMatches) {
+    let rom_path = Path::new(args.value_of("ROM").unwrap());
+    let rom = gb::read_file(rom_path).unwrap_or_else(|e| exit_with_error(e));
+    let header = CartridgeHeader::parse(cartridge::boot_header(&rom))
+        .unwrap_or_else(|e| exit_with_error(LoadError::from(e)));
+    println!("{}", header);
+    let global_checksum_valid = header.is_global_checksum_valid(&rom);
+    println!(
+        "Global checksum: {:#06X}, {}",
+        header.global_checksum,
+        if global_checksum_valid { "valid" } else { "INVALID" }
+    );
+    if !header.is_logo_valid() || !header.is_header_checksum_valid() {
+        // The boot ROM would refuse to start the game.
+        process::exit(1);
+    }
+}
+
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+fn video_device(path: &Path) -> Rc<dyn ImageSource> {
+    Rc::new(V4l2Camera::new(path))
+}
+
+#[cfg(not(all(target_os = "linux", target_pointer_width = "64")))]
+fn video_device(path: &Path) -> Rc<dyn ImageSource> {
+    exit_with_error(format!("{}: video devices aren't supported on this platform", path.display()))
+}
+
+/// Reports an error that leaves nothing to run and exits.
+fn exit_with_error<E: fmt::Display>(error: E) -> ! {
+    println!("Error: {}", error);
+    process::exit(1);
+}
+
+#[cfg(feature = "sdl")]
+fn run_interactive(gebemula: &mut Gebemula, config: &Config) {
+    frontend::sdl::run(gebemula, config);
+}
+
+#[cfg(not(feature = "sdl"))]
+fn run_interactive(gebemula: &mut Gebemula, _config: &Config) {
+    println!("Gebemula was built without SDL support, running headless.");
+    frontend::headless::run(gebemula, None, &TiltScript::default());
 }
@@ -1,19 +1,46 @@
 use crate::mem::Memory;
-use crate::mem::mapper::{Mapper, NullMapper};
+use crate::mem::mapper::{CartridgeContext, Mapper, NullMapper};
 use crate::mem::mapper::rom::RomMapper;
+use crate::mem::mapper::huc1::Huc1Mapper;
+use crate::mem::mapper::huc3::{self, Huc3Mapper};
 use crate::mem::mapper::mbc1::Mbc1Mapper;
 use crate::mem::mapper::mbc2::Mbc2Mapper;
 use crate::mem::mapper::mbc3::Mbc3Mapper;
 use crate::mem::mapper::mbc5::Mbc5Mapper;
+use crate::mem::mapper::mbc6::{self, Mbc6Mapper};
+use crate::mem::mapper::mbc7::{self, Mbc7Mapper};
+use crate::mem::mapper::mmm01::{self, Mmm01Mapper};
+use crate::mem::mapper::pocket_camera::{self, PocketCameraMapper};
+use crate::mem::mapper::tama5::{self, Tama5Mapper};
 use std::str;
 use std::cmp;
-
-pub const GAME_TITLE_ADDR_START: u16 = 0x134;
-pub const GAME_TITLE_ADDR_END: u16 = 0x142;
+use std::fmt;
+
+const LOGO_ADDR: usize = 0x104;
+const TITLE_ADDR: usize = 0x134;
+const MANUFACTURER_CODE_ADDR: usize = 0x13F;
+const CGB_FLAG_ADDR: usize = 0x143;
+const NEW_LICENSEE_ADDR: usize = 0x144;
+const SGB_FLAG_ADDR: usize = 0x146;
 pub const CARTRIDGE_TYPE_ADDR: u16 = 0x147;
 const ROM_SIZE_ADDR: u16 = 0x148;
 const RAM_SIZE_ADDR: u16 = 0x149;
-
+const DESTINATION_ADDR: usize = 0x14A;
+const OLD_LICENSEE_ADDR: usize = 0x14B;
+const VERSION_ADDR: usize = 0x14C;
+const HEADER_CHECKSUM_ADDR: usize = 0x14D;
+const GLOBAL_CHECKSUM_ADDR: usize = 0x14E;
+/// The header ends right before 0x150.
+const HEADER_END: usize = 0x150;
+
+/// The logo every cartridge has to carry for the boot ROM to start it.
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum MapperType {
     Rom,
 
@@ -46,6 +73,43 @@ bitflags! {
     }
 }
 
+#[derive(Debug)]
+pub enum CartridgeError {
+    /// The ROM doesn't even have a complete header.
+    TooSmall(usize),
+    UnknownType(u8),
+    /// A known cartridge type without an implementation.
+    Unsupported(String),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+    /// The mapper can't address a ROM of this size.
+    RomSize { mapper: &'static str, size: usize },
+    /// The mapper can't address a RAM of this size.
+    RamSize { mapper: &'static str, size: usize },
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CartridgeError::TooSmall(size) => {
+                write!(f, "ROM is too small to be a cartridge ({} bytes)", size)
+            }
+            CartridgeError::UnknownType(id) => write!(f, "unknown cartridge type {:#04X}", id),
+            CartridgeError::Unsupported(ref name) => {
+                write!(f, "{} cartridges are not supported yet", name)
+            }
+            CartridgeError::UnknownRomSize(id) => write!(f, "unknown ROM size {:#04X}", id),
+            CartridgeError::UnknownRamSize(id) => write!(f, "unknown RAM size {:#04X}", id),
+            CartridgeError::RomSize { mapper, size } => {
+                write!(f, "{} cartridges can't have {} KiB of ROM", mapper, size >> 10)
+            }
+            CartridgeError::RamSize { mapper, size } => {
+                write!(f, "{} cartridges can't have {} bytes of RAM", mapper, size)
+            }
+        }
+    }
+}
+
 pub fn cartridge_type_string(mapper: MapperType, extra_hw: CartExtraHardware) -> String {
     let mut s = match mapper {
         MapperType::Rom => "ROM",
@@ -73,11 +137,14 @@ pub fn cartridge_type_string(mapper: MapperType, extra_hw: CartExtraHardware) ->
         s.push_str("+BATTERY");
     }
     if extra_hw.contains(CartExtraHardware::RTC) {
-        s.push_str("+CartExtraHardware::RTC");
+        s.push_str("+RTC");
     }
     if extra_hw.contains(CartExtraHardware::RUMBLE) {
         s.push_str("+RUMBLE");
     }
+    if extra_hw.contains(CartExtraHardware::ACCELEROMETER) {
+        s.push_str("+ACCELEROMETER");
+    }
 
     s
 }
@@ -116,76 +183,328 @@ pub fn cart_type_from_id(id: u8) -> (MapperType, CartExtraHardware) {
 
         0x22 => (MapperType::Mbc7, CartExtraHardware::RAM | CartExtraHardware::BATTERY | CartExtraHardware::ACCELEROMETER),
 
-        0xFC => (MapperType::PocketCamera, CartExtraHardware::NONE_HW),
-        0xFD => (MapperType::Tama5, CartExtraHardware::NONE_HW),
-        0xFE => (MapperType::Huc3, CartExtraHardware::NONE_HW),
+        0xFC => (MapperType::PocketCamera, CartExtraHardware::RAM | CartExtraHardware::BATTERY),
+        0xFD => (MapperType::Tama5, CartExtraHardware::BATTERY | CartExtraHardware::RTC),
+        0xFE => (MapperType::Huc3, CartExtraHardware::RAM | CartExtraHardware::BATTERY | CartExtraHardware::RTC),
         0xFF => (MapperType::Huc1, CartExtraHardware::RAM | CartExtraHardware::BATTERY),
 
         _ => (MapperType::Unknown, CartExtraHardware::NONE_HW),
     }
 }
 
-pub fn game_title_str(memory: &Memory) -> String {
-    let game_title_u8 = &mut Vec::new();
-    for byte in GAME_TITLE_ADDR_START..(GAME_TITLE_ADDR_END + 1) {
-        if byte == 0 {
-            break;
+/// Decodes a zero-padded ASCII field of the header.
+fn header_string(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    match str::from_utf8(&bytes[..len]) {
+        Ok(s) if s.chars().all(|c| c.is_ascii_graphic() || c == ' ') => s.trim_end().to_owned(),
+        _ => "Undefined".to_owned(),
+    }
+}
+
+/// The header checksum the boot ROM computes over the title to version bytes of `header`.
+fn header_checksum(header: &[u8]) -> u8 {
+    header[TITLE_ADDR..HEADER_CHECKSUM_ADDR]
+        .iter()
+        .fold(0u8, |x, &b| x.wrapping_sub(b).wrapping_sub(1))
+}
+
+fn is_mmm01_header(header: &[u8]) -> bool {
+    header.len() >= HEADER_END
+        && cart_type_from_id(header[CARTRIDGE_TYPE_ADDR as usize]).0 == MapperType::Mmm01
+        && header[HEADER_CHECKSUM_ADDR] == header_checksum(header)
+}
+
+/// Where the header the console boots from starts in `rom`. That's the start of the ROM, except
+/// for MMM01 multicarts, which boot into a menu in their last 32 KiB while the start has the
+/// header of the first game.
+pub fn header_offset(rom: &[u8]) -> usize {
+    if rom.len() > mmm01::MENU_SIZE && !is_mmm01_header(rom) {
+        let menu_start = rom.len() - mmm01::MENU_SIZE;
+        if is_mmm01_header(&rom[menu_start..]) {
+            return menu_start;
         }
-        game_title_u8.push(memory.read_byte(byte));
     }
-    let game_title = match str::from_utf8(&game_title_u8) {
-        Ok(v) => v,
-        Err(_) => "Undefined",
-    };
+    0
+}
+
+/// `rom` from the header the console boots from on, see `header_offset`.
+pub fn boot_header(rom: &[u8]) -> &[u8] {
+    &rom[header_offset(rom)..]
+}
+
+/// Sum of every byte of the ROM except the global checksum itself.
+pub fn global_checksum(rom: &[u8]) -> u16 {
+    rom.iter()
+        .enumerate()
+        .filter(|&(i, _)| i != GLOBAL_CHECKSUM_ADDR && i != GLOBAL_CHECKSUM_ADDR + 1)
+        .fold(0u16, |sum, (_, &byte)| sum.wrapping_add(byte as u16))
+}
+
+fn size_string(size: usize) -> String {
+    if size >= 1 << 20 {
+        format!("{} MiB", size >> 20)
+    } else {
+        format!("{} KiB", size >> 10)
+    }
+}
+
+/// The cartridge header, at 0x100-0x14F of the ROM.
+pub struct CartridgeHeader {
+    pub logo: [u8; 48],
+    pub title: String,
+    /// Only in some CGB games, which use the end of the title area for it. Empty otherwise.
+    pub manufacturer_code: String,
+    pub cgb_flag: u8,
+    /// Two ASCII characters, used when `old_licensee` is 0x33.
+    pub new_licensee: String,
+    pub sgb_flag: u8,
+    pub cartridge_type: u8,
+    pub rom_size_id: u8,
+    pub ram_size_id: u8,
+    /// 0 for Japan, 1 for everywhere else.
+    pub destination: u8,
+    pub old_licensee: u8,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    /// The header checksum computed from the header bytes.
+    computed_header_checksum: u8,
+}
+
+impl CartridgeHeader {
+    /// Parses the header at the start of `rom`. Only the first 0x150 bytes are read.
+    pub fn parse(rom: &[u8]) -> Result<CartridgeHeader, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+
+        let cgb_flag = rom[CGB_FLAG_ADDR];
+        // CGB games shortened the title to 15 characters, then 11 to fit a manufacturer code.
+        let manufacturer_code = &rom[MANUFACTURER_CODE_ADDR..CGB_FLAG_ADDR];
+        let has_manufacturer_code = cgb_flag & 0x80 != 0
+            && manufacturer_code
+                .iter()
+                .all(|&c| c.is_ascii_uppercase() || c.is_ascii_digit());
+        let title_end = if has_manufacturer_code {
+            MANUFACTURER_CODE_ADDR
+        } else if cgb_flag & 0x80 != 0 {
+            CGB_FLAG_ADDR
+        } else {
+            NEW_LICENSEE_ADDR
+        };
+
+        let mut logo = [0; 48];
+        logo.copy_from_slice(&rom[LOGO_ADDR..LOGO_ADDR + 48]);
+        let computed_header_checksum = header_checksum(rom);
+
+        Ok(CartridgeHeader {
+            logo,
+            title: header_string(&rom[TITLE_ADDR..title_end]),
+            manufacturer_code: if has_manufacturer_code {
+                header_string(manufacturer_code)
+            } else {
+                String::new()
+            },
+            cgb_flag,
+            new_licensee: String::from_utf8_lossy(&rom[NEW_LICENSEE_ADDR..SGB_FLAG_ADDR])
+                .into_owned(),
+            sgb_flag: rom[SGB_FLAG_ADDR],
+            cartridge_type: rom[CARTRIDGE_TYPE_ADDR as usize],
+            rom_size_id: rom[ROM_SIZE_ADDR as usize],
+            ram_size_id: rom[RAM_SIZE_ADDR as usize],
+            destination: rom[DESTINATION_ADDR],
+            old_licensee: rom[OLD_LICENSEE_ADDR],
+            version: rom[VERSION_ADDR],
+            header_checksum: rom[HEADER_CHECKSUM_ADDR],
+            global_checksum: (rom[GLOBAL_CHECKSUM_ADDR] as u16) << 8
+                | rom[GLOBAL_CHECKSUM_ADDR + 1] as u16,
+            computed_header_checksum,
+        })
+    }
+
+    /// Reads the header of the inserted cartridge.
+    pub fn from_memory(memory: &Memory) -> CartridgeHeader {
+        let header: Vec<u8> = (0..HEADER_END as u16)
+            .map(|addr| memory.read_cartridge(addr))
+            .collect();
+        CartridgeHeader::parse(&header).unwrap()
+    }
+
+    pub fn mapper(&self) -> (MapperType, CartExtraHardware) {
+        cart_type_from_id(self.cartridge_type)
+    }
+
+    pub fn rom_size(&self) -> Result<usize, CartridgeError> {
+        parse_rom_size(self.rom_size_id)
+    }
+
+    pub fn ram_size(&self) -> Result<usize, CartridgeError> {
+        parse_ram_size(self.ram_size_id)
+    }
+
+    pub fn supports_sgb(&self) -> bool {
+        self.sgb_flag == 0x03
+    }
+
+    /// The licensee code, from the new field if the old one says so.
+    pub fn licensee(&self) -> String {
+        if self.old_licensee == 0x33 {
+            self.new_licensee.clone()
+        } else {
+            format!("{:02X}", self.old_licensee)
+        }
+    }
+
+    pub fn is_logo_valid(&self) -> bool {
+        self.logo == NINTENDO_LOGO
+    }
 
-    game_title.to_owned()
+    /// The boot ROM locks up if this doesn't match.
+    pub fn is_header_checksum_valid(&self) -> bool {
+        self.header_checksum == self.computed_header_checksum
+    }
+
+    /// Nothing checks the global checksum, so homebrew and hacks often get it wrong.
+    pub fn is_global_checksum_valid(&self, rom: &[u8]) -> bool {
+        self.global_checksum == global_checksum(rom)
+    }
+}
+
+impl fmt::Display for CartridgeHeader {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let (mapper_type, extra_hw) = self.mapper();
+        let valid = |ok| if ok { "valid" } else { "INVALID" };
+        writeln!(f, "Title: {}", self.title)?;
+        if !self.manufacturer_code.is_empty() {
+            writeln!(f, "Manufacturer code: {}", self.manufacturer_code)?;
+        }
+        let cgb = match self.cgb_flag {
+            0x80 => "supported",
+            0xC0 => "required",
+            _ => "no",
+        };
+        writeln!(f, "CGB: {} ({:#04X})", cgb, self.cgb_flag)?;
+        writeln!(f, "SGB: {}", if self.supports_sgb() { "supported" } else { "no" })?;
+        writeln!(f, "Licensee: {}", self.licensee())?;
+        writeln!(
+            f,
+            "Cartridge type: {} ({:#04X})",
+            cartridge_type_string(mapper_type, extra_hw),
+            self.cartridge_type
+        )?;
+        match self.rom_size() {
+            Ok(size) => writeln!(f, "ROM size: {} ({} banks)", size_string(size), size >> 14)?,
+            Err(e) => writeln!(f, "ROM size: {}", e)?,
+        }
+        match self.ram_size() {
+            Ok(0) => writeln!(f, "RAM size: none")?,
+            Ok(size) => writeln!(f, "RAM size: {}", size_string(size))?,
+            Err(e) => writeln!(f, "RAM size: {}", e)?,
+        }
+        let destination = if self.destination == 0 { "Japan" } else { "overseas" };
+        writeln!(f, "Destination: {} ({:#04X})", destination, self.destination)?;
+        writeln!(f, "Version: {}", self.version)?;
+        writeln!(f, "Logo: {}", valid(self.is_logo_valid()))?;
+        write!(
+            f,
+            "Header checksum: {:#04X}, {}",
+            self.header_checksum,
+            valid(self.is_header_checksum_valid())
+        )
+    }
 }
 
-pub fn parse_rom_size(id: u8) -> usize {
+pub fn parse_rom_size(id: u8) -> Result<usize, CartridgeError> {
     match id {
-        0x00..=0x08 => (32 * 1024) << id,
-        _ => panic!("Unknown ROM size: {:#02X}", id),
+        0x00..=0x08 => Ok((32 * 1024) << id),
+        _ => Err(CartridgeError::UnknownRomSize(id)),
     }
 }
 
-pub fn parse_ram_size(id: u8) -> usize {
+pub fn parse_ram_size(id: u8) -> Result<usize, CartridgeError> {
     match id {
-        0x00 => 0,
-        0x01 => 2 * 1024,
-        0x02 => 8 * 1024,
-        0x03 => 32 * 1024,
-        0x04 => 128 * 1024,
-        0x05 => 64 * 1024,
-        _ => panic!("Unknown cartridge RAM size: {:#02X}", id),
+        0x00 => Ok(0),
+        0x01 => Ok(2 * 1024),
+        0x02 => Ok(8 * 1024),
+        0x03 => Ok(32 * 1024),
+        0x04 => Ok(128 * 1024),
+        0x05 => Ok(64 * 1024),
+        _ => Err(CartridgeError::UnknownRamSize(id)),
     }
 }
 
-pub fn load_cartridge(rom: &[u8], battery: &[u8]) -> Box<dyn Mapper> {
+pub fn load_cartridge(
+    rom: &[u8],
+    battery: &[u8],
+    context: &CartridgeContext,
+) -> Result<Box<dyn Mapper>, CartridgeError> {
     if rom.len() == 0 {
         println!("Warning: No cartridge inserted.");
-        return Box::new(NullMapper);
+        return Ok(Box::new(NullMapper));
     }
 
     if rom.len() < 0x200 {
         // Files this small aren't even large enough to have a header.
-        panic!("Input ROM is too small. ({} bytes)", rom.len());
+        return Err(CartridgeError::TooSmall(rom.len()));
     }
 
-    let cart_type_id = rom[CARTRIDGE_TYPE_ADDR as usize];
+    let header = boot_header(rom);
+    let cart_type_id = header[CARTRIDGE_TYPE_ADDR as usize];
     let (mapper_type, extra_hw) = cart_type_from_id(cart_type_id);
-    let rom_size = parse_rom_size(rom[ROM_SIZE_ADDR as usize]);
+    if mapper_type == MapperType::Unknown {
+        return Err(CartridgeError::UnknownType(cart_type_id));
+    }
+    let rom_size = match mapper_type {
+        // The menu header doesn't always count the games.
+        MapperType::Mmm01 => cmp::max(
+            parse_rom_size(header[ROM_SIZE_ADDR as usize])?,
+            rom.len().next_power_of_two(),
+        ),
+        _ => parse_rom_size(header[ROM_SIZE_ADDR as usize])?,
+    };
     let ram_size = match mapper_type {
         MapperType::Mbc2 => 512, // MBC2 always has 512 nibbles of internal SRAM
-        _ => parse_ram_size(rom[RAM_SIZE_ADDR as usize]),
+        MapperType::Mbc6 => 32 * 1024,
+        MapperType::Mbc7 => mbc7::EEPROM_SIZE,
+        MapperType::Tama5 => tama5::EEPROM_SIZE,
+        MapperType::PocketCamera => pocket_camera::RAM_SIZE,
+        _ => parse_ram_size(header[RAM_SIZE_ADDR as usize])?,
+    };
+
+    // Some MMM01 dumps have the menu moved to the start, put it back at the end.
+    let mut rearranged_rom = Vec::new();
+    let rom = if mapper_type == MapperType::Mmm01
+        && header_offset(rom) == 0
+        && rom.len() > mmm01::MENU_SIZE
+    {
+        rearranged_rom.extend_from_slice(&rom[mmm01::MENU_SIZE..]);
+        rearranged_rom.extend_from_slice(&rom[..mmm01::MENU_SIZE]);
+        &rearranged_rom[..]
+    } else {
+        rom
     };
 
-    // Copy ROM data from file to backing memory
+    // Copy ROM data from file to backing memory. MMM01 dumps are padded at the start instead, the
+    // menu has to stay in the last 32 KiB.
     let mut rom_data = vec![0xFF; rom_size].into_boxed_slice();
     let copy_len = cmp::min(rom.len(), rom_data.len());
-    &rom_data[..copy_len].copy_from_slice(&rom[..copy_len]);
+    let copy_start = match mapper_type {
+        MapperType::Mmm01 => rom_data.len() - copy_len,
+        _ => 0,
+    };
+    rom_data[copy_start..copy_start + copy_len].copy_from_slice(&rom[..copy_len]);
 
     // Initialize RAM backing memory
-    let expected_battery_size = ram_size + if extra_hw.contains(CartExtraHardware::BATTERY) { 48 } else { 0 };
+    // Saved after the RAM.
+    let extra_save_size = match mapper_type {
+        MapperType::Mbc6 => mbc6::FLASH_SIZE,
+        MapperType::Mbc7 | MapperType::PocketCamera => 0,
+        MapperType::Huc3 => huc3::RTC_SAVE_SIZE,
+        MapperType::Tama5 => tama5::RTC_SAVE_SIZE,
+        _ if extra_hw.contains(CartExtraHardware::BATTERY) => 48,
+        _ => 0,
+    };
+    let expected_battery_size = ram_size + extra_save_size;
     if !battery.is_empty() && battery.len() != expected_battery_size {
         println!(
             "WARNING: Battery file has unexpected size: {:#X}, expected {:#X}",
@@ -198,36 +517,82 @@ pub fn load_cartridge(rom: &[u8], battery: &[u8]) -> Box<dyn Mapper> {
     let copy_len = cmp::min(battery.len(), ram_data.len());
     &ram_data[..copy_len].copy_from_slice(&battery[..copy_len]);
 
-    match mapper_type {
+    Ok(match mapper_type {
         MapperType::Rom => Box::new(RomMapper::new(
             rom_data,
             ram_data,
             extra_hw.contains(CartExtraHardware::BATTERY),
-        )),
+        )?),
         MapperType::Mbc1 => Box::new(Mbc1Mapper::new(
             rom_data,
             ram_data,
             extra_hw.contains(CartExtraHardware::BATTERY),
-        )),
+        )?),
         MapperType::Mbc2 => Box::new(Mbc2Mapper::new(
             rom_data,
             ram_data,
             extra_hw.contains(CartExtraHardware::BATTERY),
-        )),
+        )?),
         MapperType::Mbc3 => Box::new(Mbc3Mapper::new(
             rom_data,
             ram_data,
             extra_hw.contains(CartExtraHardware::BATTERY),
             extra_hw.contains(CartExtraHardware::RTC),
-        )),
+            context,
+        )?),
         MapperType::Mbc5 => Box::new(Mbc5Mapper::new(
             rom_data,
             ram_data,
             extra_hw.contains(CartExtraHardware::BATTERY),
-        )),
-        _ => panic!(
-            "Cartridges of type {:#X} are not yet supported.",
-            cart_type_id
-        ),
-    }
+        )?),
+        MapperType::Mbc6 => Box::new(Mbc6Mapper::new(
+            rom_data,
+            ram_data,
+            battery.get(ram_size..).unwrap_or(&[]),
+            extra_hw.contains(CartExtraHardware::BATTERY),
+        )?),
+        MapperType::Mbc7 => Box::new(Mbc7Mapper::new(
+            rom_data,
+            ram_data,
+            extra_hw.contains(CartExtraHardware::BATTERY),
+            context,
+        )?),
+        MapperType::Mmm01 => Box::new(Mmm01Mapper::new(
+            rom_data,
+            ram_data,
+            extra_hw.contains(CartExtraHardware::BATTERY),
+        )?),
+        MapperType::Huc1 => Box::new(Huc1Mapper::new(
+            rom_data,
+            ram_data,
+            extra_hw.contains(CartExtraHardware::BATTERY),
+            context,
+        )?),
+        MapperType::Huc3 => Box::new(Huc3Mapper::new(
+            rom_data,
+            ram_data,
+            battery.get(ram_size..).unwrap_or(&[]),
+            extra_hw.contains(CartExtraHardware::BATTERY),
+            context,
+        )?),
+        MapperType::Tama5 => Box::new(Tama5Mapper::new(
+            rom_data,
+            ram_data,
+            battery.get(ram_size..).unwrap_or(&[]),
+            extra_hw.contains(CartExtraHardware::BATTERY),
+            context,
+        )?),
+        MapperType::PocketCamera => Box::new(PocketCameraMapper::new(
+            rom_data,
+            ram_data,
+            extra_hw.contains(CartExtraHardware::BATTERY),
+            context,
+        )?),
+        _ => {
+            return Err(CartridgeError::Unsupported(cartridge_type_string(
+                mapper_type,
+                extra_hw,
+            )))
+        }
+    })
 }
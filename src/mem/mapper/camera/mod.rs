@@ -0,0 +1,64 @@
+pub mod still;
+#[cfg(all(target_os = "linux", target_pointer_width = "64"))]
+pub mod v4l2;
+
+/// Size of the images the cartridge keeps, in pixels.
+pub const IMAGE_WIDTH: usize = 128;
+pub const IMAGE_HEIGHT: usize = 112;
+
+/// What the lens of the Pocket Camera sees.
+pub trait ImageSource {
+    /// Fills `image` with the current view, `IMAGE_WIDTH` by `IMAGE_HEIGHT` brightness values
+    /// from black (0) to white (255), row by row. `captures` is the number of images the
+    /// cartridge took before this one.
+    fn capture(&self, image: &mut [u8], captures: u32);
+
+    /// True if the view comes from outside the emulator, so that save states and movies can't
+    /// reproduce it.
+    fn is_live(&self) -> bool {
+        false
+    }
+}
+
+/// Gray bars over a gradient that scrolls a little with every capture, so that the viewfinder
+/// visibly updates.
+#[derive(Default)]
+pub struct TestPattern;
+
+impl ImageSource for TestPattern {
+    fn capture(&self, image: &mut [u8], captures: u32) {
+        let offset = captures as usize;
+        for (y, row) in image.chunks_mut(IMAGE_WIDTH).enumerate() {
+            for (x, pixel) in row.iter_mut().enumerate() {
+                *pixel = if y < IMAGE_HEIGHT / 2 {
+                    // 8 bars from white to black.
+                    255 - (x / (IMAGE_WIDTH / 8) * 255 / 7) as u8
+                } else {
+                    ((x + y + offset) * 2 % 256) as u8
+                };
+            }
+        }
+    }
+}
+
+/// Scales a `width` by `height` grayscale image to fill `image`, cropping the sides or the top
+/// and bottom to keep its aspect ratio.
+pub fn scale_to_image(pixels: &[u8], width: usize, height: usize, image: &mut [u8]) {
+    if width == 0 || height == 0 {
+        return;
+    }
+    // The largest part of the source with the aspect ratio of the image.
+    let (crop_width, crop_height) = if width * IMAGE_HEIGHT > height * IMAGE_WIDTH {
+        (height * IMAGE_WIDTH / IMAGE_HEIGHT, height)
+    } else {
+        (width, width * IMAGE_HEIGHT / IMAGE_WIDTH)
+    };
+    let (left, top) = ((width - crop_width) / 2, (height - crop_height) / 2);
+    for (y, row) in image.chunks_mut(IMAGE_WIDTH).enumerate() {
+        let source_y = top + y * crop_height / IMAGE_HEIGHT;
+        for (x, pixel) in row.iter_mut().enumerate() {
+            let source_x = left + x * crop_width / IMAGE_WIDTH;
+            *pixel = pixels[source_y * width + source_x];
+        }
+    }
+}
@@ -0,0 +1,102 @@
+use crate::mem::mapper::camera::{self, ImageSource, IMAGE_HEIGHT, IMAGE_WIDTH};
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_owned())
+}
+
+/// Reads the whitespace-separated numbers of a netpbm header, skipping comments.
+struct HeaderReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> HeaderReader<'a> {
+    fn number(&mut self) -> io::Result<usize> {
+        loop {
+            match self.data.get(self.pos) {
+                Some(b'#') => {
+                    while matches!(self.data.get(self.pos), Some(&c) if c != b'\n') {
+                        self.pos += 1;
+                    }
+                }
+                Some(c) if c.is_ascii_whitespace() => self.pos += 1,
+                _ => break,
+            }
+        }
+        let start = self.pos;
+        while matches!(self.data.get(self.pos), Some(c) if c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        std::str::from_utf8(&self.data[start..self.pos])
+            .ok()
+            .and_then(|digits| digits.parse().ok())
+            .ok_or_else(|| invalid_data("invalid image header"))
+    }
+}
+
+/// The camera pointed at a picture that never changes.
+pub struct StillImage {
+    image: Box<[u8]>,
+}
+
+impl StillImage {
+    /// Loads a binary PGM (P5) or PPM (P6) image, of any size.
+    pub fn load(path: &Path) -> io::Result<StillImage> {
+        StillImage::parse(&fs::read(path)?)
+    }
+
+    pub fn parse(data: &[u8]) -> io::Result<StillImage> {
+        let channels = match data.get(..2) {
+            Some(b"P5") => 1,
+            Some(b"P6") => 3,
+            _ => return Err(invalid_data("not a binary PGM or PPM image")),
+        };
+        let mut header = HeaderReader { data, pos: 2 };
+        let width = header.number()?;
+        let height = header.number()?;
+        let max_value = header.number()?;
+        if max_value == 0 || max_value > 0xFFFF {
+            return Err(invalid_data("invalid maximum value in image header"));
+        }
+        // A single whitespace character separates the header from the pixels.
+        let pixels = &data[(header.pos + 1).min(data.len())..];
+        let sample_size = if max_value > 0xFF { 2 } else { 1 };
+        let pixel_size = channels * sample_size;
+        let size = width
+            .checked_mul(height)
+            .and_then(|count| count.checked_mul(pixel_size))
+            .ok_or_else(|| invalid_data("image is too large"))?;
+        if width == 0 || height == 0 || pixels.len() < size {
+            return Err(invalid_data("image is truncated"));
+        }
+
+        let sample = |i: usize| {
+            let value = if sample_size == 2 {
+                (pixels[i] as usize) << 8 | pixels[i + 1] as usize
+            } else {
+                pixels[i] as usize
+            };
+            value * 255 / max_value
+        };
+        let gray: Vec<u8> = (0..width * height)
+            .map(|i| {
+                let start = i * pixel_size;
+                let sum: usize = (0..channels).map(|c| sample(start + c * sample_size)).sum();
+                (sum / channels).min(255) as u8
+            })
+            .collect();
+        let mut image = vec![0; IMAGE_WIDTH * IMAGE_HEIGHT].into_boxed_slice();
+        camera::scale_to_image(&gray, width, height, &mut image);
+        Ok(StillImage { image })
+    }
+}
+
+impl ImageSource for StillImage {
+    fn capture(&self, image: &mut [u8], _captures: u32) {
+        image.copy_from_slice(&self.image);
+    }
+}
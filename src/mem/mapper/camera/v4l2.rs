@@ -0,0 +1,434 @@
+use crate::mem::mapper::camera::{self, ImageSource, TestPattern, IMAGE_HEIGHT, IMAGE_WIDTH};
+
+use std::cell::Cell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read};
+use std::mem;
+use std::os::raw::{c_int, c_ulong, c_void};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::slice;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+extern "C" {
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: c_int,
+        flags: c_int,
+        fd: c_int,
+        offset: i64,
+    ) -> *mut c_void;
+    fn munmap(addr: *mut c_void, len: usize) -> c_int;
+}
+
+const PROT_READ: c_int = 0x1;
+const PROT_WRITE: c_int = 0x2;
+const MAP_SHARED: c_int = 0x1;
+
+/// `struct v4l2_capability`.
+#[repr(C)]
+struct Capability {
+    driver: [u8; 16],
+    card: [u8; 32],
+    bus_info: [u8; 32],
+    version: u32,
+    capabilities: u32,
+    device_caps: u32,
+    reserved: [u32; 3],
+}
+
+/// `struct v4l2_format` holding a `struct v4l2_pix_format`. The union it's in is 8-byte aligned
+/// and 200 bytes long.
+#[repr(C)]
+struct Format {
+    buffer_type: u32,
+    padding: u32,
+    width: u32,
+    height: u32,
+    pixel_format: u32,
+    field: u32,
+    bytes_per_line: u32,
+    size_image: u32,
+    colorspace: u32,
+    private: u32,
+    flags: u32,
+    ycbcr_encoding: u32,
+    quantization: u32,
+    transfer_function: u32,
+    rest: [u8; 152],
+}
+
+/// `struct v4l2_requestbuffers`.
+#[repr(C)]
+struct RequestBuffers {
+    count: u32,
+    buffer_type: u32,
+    memory: u32,
+    capabilities: u32,
+    flags: u8,
+    reserved: [u8; 3],
+}
+
+/// `struct v4l2_buffer`, with the `struct timeval` of 64-bit targets and the `m` union as the
+/// offset of mmap buffers.
+#[repr(C)]
+struct Buffer {
+    index: u32,
+    buffer_type: u32,
+    bytes_used: u32,
+    flags: u32,
+    field: u32,
+    timestamp: [i64; 2],
+    timecode: [u32; 4],
+    sequence: u32,
+    memory: u32,
+    offset: u64,
+    length: u32,
+    reserved2: u32,
+    request_fd: u32,
+}
+
+const fn ioctl_request(read: bool, write: bool, number: u8, size: usize) -> c_ulong {
+    let direction = (read as c_ulong) << 1 | write as c_ulong;
+    direction << 30 | (size as c_ulong) << 16 | (b'V' as c_ulong) << 8 | number as c_ulong
+}
+
+const VIDIOC_QUERYCAP: c_ulong = ioctl_request(true, false, 0, mem::size_of::<Capability>());
+const VIDIOC_S_FMT: c_ulong = ioctl_request(true, true, 5, mem::size_of::<Format>());
+const VIDIOC_REQBUFS: c_ulong = ioctl_request(true, true, 8, mem::size_of::<RequestBuffers>());
+const VIDIOC_QUERYBUF: c_ulong = ioctl_request(true, true, 9, mem::size_of::<Buffer>());
+const VIDIOC_QBUF: c_ulong = ioctl_request(true, true, 15, mem::size_of::<Buffer>());
+const VIDIOC_DQBUF: c_ulong = ioctl_request(true, true, 17, mem::size_of::<Buffer>());
+const VIDIOC_STREAMON: c_ulong = ioctl_request(false, true, 18, mem::size_of::<c_int>());
+const VIDIOC_STREAMOFF: c_ulong = ioctl_request(false, true, 19, mem::size_of::<c_int>());
+
+const CAP_VIDEO_CAPTURE: u32 = 0x0000_0001;
+const CAP_READWRITE: u32 = 0x0100_0000;
+const CAP_STREAMING: u32 = 0x0400_0000;
+const CAP_DEVICE_CAPS: u32 = 0x8000_0000;
+const BUF_TYPE_VIDEO_CAPTURE: u32 = 1;
+const MEMORY_MMAP: u32 = 1;
+
+/// Buffers the driver fills in turn while streaming.
+const STREAM_BUFFER_COUNT: u32 = 4;
+
+const fn fourcc(code: &[u8; 4]) -> u32 {
+    code[0] as u32 | (code[1] as u32) << 8 | (code[2] as u32) << 16 | (code[3] as u32) << 24
+}
+
+const PIX_FMT_GREY: u32 = fourcc(b"GREY");
+const PIX_FMT_YUYV: u32 = fourcc(b"YUYV");
+
+/// Sends `request` to the device, retrying if a signal interrupts it. `T` has to be the struct
+/// `request` encodes the size of. They are all plain integers, so all zeroes is a valid value to
+/// start from.
+fn device_request<T>(fd: c_int, request: c_ulong, arg: &mut T) -> io::Result<()> {
+    loop {
+        if unsafe { ioctl(fd, request, arg as *mut T) } >= 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// What the device sends, after negotiating.
+struct FrameFormat {
+    width: usize,
+    height: usize,
+    bytes_per_line: usize,
+    frame_size: usize,
+    /// YUYV, of which only the Y bytes are used, rather than plain grayscale.
+    yuyv: bool,
+}
+
+/// Opens a capture device and picks a format. Returns the capabilities along with it.
+fn open_device(path: &Path) -> io::Result<(File, u32, FrameFormat)> {
+    let file = OpenOptions::new().read(true).write(true).open(path)?;
+    let fd = file.as_raw_fd();
+
+    let mut capability: Capability = unsafe { mem::zeroed() };
+    device_request(fd, VIDIOC_QUERYCAP, &mut capability)?;
+    let caps = if capability.capabilities & CAP_DEVICE_CAPS != 0 {
+        capability.device_caps
+    } else {
+        capability.capabilities
+    };
+    if caps & CAP_VIDEO_CAPTURE == 0 {
+        return Err(io::Error::other("not a video capture device"));
+    }
+    if caps & (CAP_STREAMING | CAP_READWRITE) == 0 {
+        return Err(io::Error::other("the device supports neither streaming nor read()"));
+    }
+
+    for &pixel_format in [PIX_FMT_GREY, PIX_FMT_YUYV].iter() {
+        let mut format: Format = unsafe { mem::zeroed() };
+        format.buffer_type = BUF_TYPE_VIDEO_CAPTURE;
+        // The driver picks its closest size.
+        format.width = (IMAGE_WIDTH * 2) as u32;
+        format.height = (IMAGE_HEIGHT * 2) as u32;
+        format.pixel_format = pixel_format;
+        if device_request(fd, VIDIOC_S_FMT, &mut format).is_err() {
+            continue;
+        }
+        if format.pixel_format != pixel_format {
+            continue;
+        }
+        let yuyv = pixel_format == PIX_FMT_YUYV;
+        let width = format.width as usize;
+        let min_line = if yuyv { width * 2 } else { width };
+        let bytes_per_line = (format.bytes_per_line as usize).max(min_line);
+        let frame_size = (format.size_image as usize).max(bytes_per_line * format.height as usize);
+        return Ok((
+            file,
+            caps,
+            FrameFormat {
+                width,
+                height: format.height as usize,
+                bytes_per_line,
+                frame_size,
+                yuyv,
+            },
+        ));
+    }
+    Err(io::Error::other("the device can't send grayscale or YUYV frames"))
+}
+
+/// A buffer of the driver mapped into memory.
+struct MappedBuffer {
+    data: *mut c_void,
+    len: usize,
+}
+
+impl Drop for MappedBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.data, self.len);
+        }
+    }
+}
+
+/// Frames shared with the driver through mmap buffers, which every webcam supports.
+struct Stream {
+    file: File,
+    buffers: Vec<MappedBuffer>,
+    /// The buffer of the last frame, given back to the driver when the next one is taken.
+    dequeued: Option<Buffer>,
+}
+
+impl Stream {
+    fn start(file: File) -> io::Result<Stream> {
+        let fd = file.as_raw_fd();
+        let mut request: RequestBuffers = unsafe { mem::zeroed() };
+        request.count = STREAM_BUFFER_COUNT;
+        request.buffer_type = BUF_TYPE_VIDEO_CAPTURE;
+        request.memory = MEMORY_MMAP;
+        device_request(fd, VIDIOC_REQBUFS, &mut request)?;
+        if request.count == 0 {
+            return Err(io::Error::other("the device has no buffers to stream to"));
+        }
+
+        // Made right away, so that the buffers are unmapped if anything fails.
+        let mut stream = Stream {
+            file,
+            buffers: Vec::new(),
+            dequeued: None,
+        };
+        for index in 0..request.count {
+            let mut buffer = Stream::empty_buffer();
+            buffer.index = index;
+            device_request(fd, VIDIOC_QUERYBUF, &mut buffer)?;
+            let len = buffer.length as usize;
+            let data = unsafe {
+                mmap(
+                    ptr::null_mut(),
+                    len,
+                    PROT_READ | PROT_WRITE,
+                    MAP_SHARED,
+                    fd,
+                    buffer.offset as i64,
+                )
+            };
+            // MAP_FAILED
+            if data as isize == -1 {
+                return Err(io::Error::last_os_error());
+            }
+            stream.buffers.push(MappedBuffer { data, len });
+            device_request(fd, VIDIOC_QBUF, &mut buffer)?;
+        }
+        let mut buffer_type = BUF_TYPE_VIDEO_CAPTURE as c_int;
+        device_request(fd, VIDIOC_STREAMON, &mut buffer_type)?;
+        Ok(stream)
+    }
+
+    fn empty_buffer() -> Buffer {
+        let mut buffer: Buffer = unsafe { mem::zeroed() };
+        buffer.buffer_type = BUF_TYPE_VIDEO_CAPTURE;
+        buffer.memory = MEMORY_MMAP;
+        buffer
+    }
+
+    /// Waits for the driver to fill a buffer.
+    fn next_frame(&mut self) -> io::Result<&[u8]> {
+        let fd = self.file.as_raw_fd();
+        if let Some(mut buffer) = self.dequeued.take() {
+            device_request(fd, VIDIOC_QBUF, &mut buffer)?;
+        }
+        let mut buffer = Stream::empty_buffer();
+        device_request(fd, VIDIOC_DQBUF, &mut buffer)?;
+        let mapped = self
+            .buffers
+            .get(buffer.index as usize)
+            .ok_or_else(|| io::Error::other("the device filled an unknown buffer"))?;
+        let len = (buffer.bytes_used as usize).min(mapped.len);
+        self.dequeued = Some(buffer);
+        // The driver doesn't touch the buffer until it's queued again.
+        Ok(unsafe { slice::from_raw_parts(mapped.data as *const u8, len) })
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let fd = self.file.as_raw_fd();
+        let mut buffer_type = BUF_TYPE_VIDEO_CAPTURE as c_int;
+        let _ = device_request(fd, VIDIOC_STREAMOFF, &mut buffer_type);
+        // The driver only frees its buffers once they're unmapped, after which the device can be
+        // read from again.
+        self.buffers.clear();
+        let mut request: RequestBuffers = unsafe { mem::zeroed() };
+        request.buffer_type = BUF_TYPE_VIDEO_CAPTURE;
+        request.memory = MEMORY_MMAP;
+        let _ = device_request(fd, VIDIOC_REQBUFS, &mut request);
+    }
+}
+
+/// Where frames come from: streaming if the device supports it, `read()` otherwise.
+enum FrameReader {
+    Stream(Stream),
+    Read { file: File, buffer: Vec<u8> },
+}
+
+impl FrameReader {
+    fn next_frame(&mut self) -> io::Result<&[u8]> {
+        match *self {
+            FrameReader::Stream(ref mut stream) => stream.next_frame(),
+            FrameReader::Read {
+                ref mut file,
+                ref mut buffer,
+            } => {
+                let len = file.read(buffer)?;
+                Ok(&buffer[..len])
+            }
+        }
+    }
+}
+
+/// Reads frames until the camera is dropped, keeping the last one in `frame`.
+fn capture_frames(path: &Path, frame: &Arc<Mutex<Option<Box<[u8]>>>>) -> io::Result<()> {
+    let (file, caps, format) = open_device(path)?;
+    let mut reader = if caps & CAP_STREAMING != 0 {
+        match (Stream::start(file.try_clone()?), caps & CAP_READWRITE != 0) {
+            (Ok(stream), _) => FrameReader::Stream(stream),
+            (Err(_), true) => FrameReader::Read {
+                file,
+                buffer: vec![0; format.frame_size],
+            },
+            (Err(e), false) => return Err(e),
+        }
+    } else {
+        FrameReader::Read {
+            file,
+            buffer: vec![0; format.frame_size],
+        }
+    };
+    let mut gray = vec![0; format.width * format.height];
+    // The camera holds the other reference.
+    while Arc::strong_count(frame) > 1 {
+        let data = reader.next_frame()?;
+        if data.len() < format.bytes_per_line * format.height {
+            continue;
+        }
+        for (y, row) in gray.chunks_mut(format.width).enumerate() {
+            let line = &data[y * format.bytes_per_line..];
+            for (x, pixel) in row.iter_mut().enumerate() {
+                *pixel = if format.yuyv { line[x * 2] } else { line[x] };
+            }
+        }
+        let mut image = vec![0; IMAGE_WIDTH * IMAGE_HEIGHT].into_boxed_slice();
+        camera::scale_to_image(&gray, format.width, format.height, &mut image);
+        *frame.lock().unwrap() = Some(image);
+    }
+    Ok(())
+}
+
+/// A Video4Linux capture device, such as a webcam. The device is only opened on the first
+/// capture, then read from in the background so that captures don't wait for a frame. Falls back
+/// to a `TestPattern` if it can't be used.
+pub struct V4l2Camera {
+    path: PathBuf,
+    /// Last frame of the device, scaled down.
+    frame: Arc<Mutex<Option<Box<[u8]>>>>,
+    started: Cell<bool>,
+    failed: Arc<AtomicBool>,
+    fallback: TestPattern,
+}
+
+impl V4l2Camera {
+    pub fn new(path: &Path) -> V4l2Camera {
+        V4l2Camera {
+            path: path.to_owned(),
+            frame: Arc::new(Mutex::new(None)),
+            started: Cell::new(false),
+            failed: Arc::new(AtomicBool::new(false)),
+            fallback: TestPattern,
+        }
+    }
+
+    fn start(&self) {
+        let path = self.path.clone();
+        let frame = self.frame.clone();
+        let failed = self.failed.clone();
+        thread::spawn(move || {
+            if let Err(e) = capture_frames(&path, &frame) {
+                println!(
+                    "Unable to capture from {}: {}. Using a test pattern instead.",
+                    path.display(),
+                    e
+                );
+                failed.store(true, Ordering::Relaxed);
+            }
+        });
+    }
+}
+
+impl ImageSource for V4l2Camera {
+    fn capture(&self, image: &mut [u8], captures: u32) {
+        if !self.started.get() {
+            self.started.set(true);
+            self.start();
+        }
+        if self.failed.load(Ordering::Relaxed) {
+            return self.fallback.capture(image, captures);
+        }
+        match *self.frame.lock().unwrap() {
+            Some(ref frame) => image.copy_from_slice(frame),
+            // Gray until the first frame arrives.
+            None => {
+                for pixel in image.iter_mut() {
+                    *pixel = 0x80;
+                }
+            }
+        }
+    }
+
+    fn is_live(&self) -> bool {
+        true
+    }
+}
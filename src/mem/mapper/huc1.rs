@@ -0,0 +1,142 @@
+use crate::mem::cartridge::CartridgeError;
+use crate::mem::mapper::infrared::Infrared;
+use crate::mem::mapper::{check_sizes, CartridgeContext, Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+use std::rc::Rc;
+
+pub struct Huc1Mapper {
+    /// Mapped to the ROM area. Up to 1 MiB in size.
+    rom: Box<[u8]>,
+    /// Mapped to the RAM area. Up to 32 KiB in size.
+    ram: Box<[u8]>,
+
+    current_rom_bank: u8,
+    current_ram_bank: u8,
+    /// When set, the RAM area accesses the infrared port instead of RAM.
+    ir_selected: bool,
+    infrared: Rc<dyn Infrared>,
+
+    has_battery: bool,
+    /// True is SRAM has been written to since the last time it was saved.
+    ram_modified: bool,
+}
+
+impl Huc1Mapper {
+    pub fn new(
+        rom: Box<[u8]>,
+        ram: Box<[u8]>,
+        has_battery: bool,
+        context: &CartridgeContext,
+    ) -> Result<Huc1Mapper, CartridgeError> {
+        check_sizes("HuC1", &rom, 1 << 20, &ram, 32 << 10)?;
+
+        Ok(Huc1Mapper {
+            rom,
+            ram,
+            current_rom_bank: 1,
+            current_ram_bank: 0,
+            ir_selected: false,
+            infrared: context.infrared.clone(),
+            has_battery,
+            ram_modified: false,
+        })
+    }
+
+    fn rom_mask(&self) -> usize {
+        self.rom.len() - 1
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let offset = self.current_ram_bank as usize * RAM_BANK_SIZE + (address & 0x1FFF) as usize;
+        offset & (self.ram.len() - 1)
+    }
+}
+
+impl Mapper for Huc1Mapper {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address & 0x4000 == 0 {
+            0
+        } else {
+            self.current_rom_bank
+        };
+        let offset = bank as usize * ROM_BANK_SIZE + (address & 0x3FFF) as usize;
+
+        self.rom[offset & self.rom_mask()]
+    }
+
+    fn write_rom(&mut self, address: u16, data: u8) {
+        match (address >> 13) & 0b11 {
+            0 => {
+                // RAM/IR select. RAM is always enabled otherwise.
+                self.ir_selected = data & 0xF == 0xE;
+            }
+            1 => {
+                // ROM bank
+                self.current_rom_bank = data & 0x3F;
+                if self.current_rom_bank == 0 {
+                    self.current_rom_bank = 1;
+                }
+            }
+            2 => {
+                // RAM bank
+                self.current_ram_bank = data & 0x3;
+            }
+            3 => {
+                // unused
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if self.ir_selected {
+            0xC0 | self.infrared.receiving() as u8
+        } else if !self.ram.is_empty() {
+            self.ram[self.ram_offset(address)]
+        } else {
+            0xFF
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) {
+        if self.ir_selected {
+            self.infrared.set_led(data & 1 != 0);
+        } else if !self.ram.is_empty() {
+            let offset = self.ram_offset(address);
+            self.ram[offset] = data;
+            self.ram_modified = true;
+        }
+    }
+
+    fn save_battery(&mut self) -> Vec<u8> {
+        if self.has_battery && self.ram_modified {
+            self.ram_modified = false;
+            Vec::from(&*self.ram)
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn set_context(&mut self, context: &CartridgeContext) {
+        self.infrared = context.infrared.clone();
+    }
+}
+
+impl SaveState for Huc1Mapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.current_rom_bank);
+        writer.write_u8(self.current_ram_bank);
+        writer.write_bool(self.ir_selected);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.current_rom_bank = reader.read_u8()?;
+        self.current_ram_bank = reader.read_u8()?;
+        self.ir_selected = reader.read_bool()?;
+        reader.read_bytes_into(&mut self.ram)?;
+        self.ram_modified = true;
+        Ok(())
+    }
+}
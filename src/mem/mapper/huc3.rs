@@ -0,0 +1,264 @@
+use crate::clock::Clock;
+use crate::mem::cartridge::CartridgeError;
+use crate::mem::mapper::infrared::Infrared;
+use crate::mem::mapper::{check_sizes, CartridgeContext, Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+use std::rc::Rc;
+
+/// Bytes saved after the RAM in the battery file to keep the clock.
+pub const RTC_SAVE_SIZE: usize = 8;
+
+/// What the RAM area accesses, selected by writing to 0x0000-0x1FFF.
+const MODE_RAM_READ_ONLY: u8 = 0x0;
+const MODE_RAM: u8 = 0xA;
+const MODE_RTC_COMMAND: u8 = 0xB;
+const MODE_RTC_RESPONSE: u8 = 0xC;
+const MODE_RTC_SEMAPHORE: u8 = 0xD;
+const MODE_IR: u8 = 0xE;
+
+const RTC_READ: u8 = 0x1;
+const RTC_WRITE: u8 = 0x3;
+const RTC_SET_ADDRESS_LOW: u8 = 0x4;
+const RTC_SET_ADDRESS_HIGH: u8 = 0x5;
+const RTC_EXTENDED: u8 = 0x6;
+const RTC_EXTENDED_READ_TIME: u8 = 0x0;
+const RTC_EXTENDED_WRITE_TIME: u8 = 0x1;
+const RTC_EXTENDED_STATUS: u8 = 0x2;
+
+/// The time is copied to and from RTC memory as the minute of the day in 3 nibbles, then the day
+/// counter in 3 nibbles, least significant first.
+const RTC_MINUTES_ADDR: usize = 0x0;
+const RTC_DAYS_ADDR: usize = 0x3;
+const SECONDS_PER_DAY: i64 = 24 * 60 * 60;
+
+pub struct Huc3Mapper {
+    /// Mapped to the ROM area. Up to 2 MiB in size.
+    rom: Box<[u8]>,
+    /// Mapped to the RAM area. Up to 128 KiB in size.
+    ram: Box<[u8]>,
+
+    current_rom_bank: u8,
+    current_ram_bank: u8,
+    /// One of the `MODE_` constants.
+    mode: u8,
+    infrared: Rc<dyn Infrared>,
+
+    clock: Rc<dyn Clock>,
+    /// Seconds from the host clock to the time set by the game.
+    rtc_offset: i64,
+    /// Memory of the RTC chip, one nibble per address.
+    rtc_memory: [u8; 0x100],
+    rtc_address: u8,
+    /// Command and result nibbles of the last RTC command.
+    rtc_response: u8,
+
+    has_battery: bool,
+    /// True is SRAM or the clock have changed since the last time they were saved.
+    ram_modified: bool,
+}
+
+impl Huc3Mapper {
+    /// `rtc_save` is what follows the RAM in the battery file. The clock starts from day 0 if
+    /// it's empty.
+    pub fn new(
+        rom: Box<[u8]>,
+        ram: Box<[u8]>,
+        rtc_save: &[u8],
+        has_battery: bool,
+        context: &CartridgeContext,
+    ) -> Result<Huc3Mapper, CartridgeError> {
+        check_sizes("HuC3", &rom, 2 << 20, &ram, 128 << 10)?;
+
+        let rtc_offset = if rtc_save.len() >= RTC_SAVE_SIZE {
+            let mut bytes = [0; RTC_SAVE_SIZE];
+            bytes.copy_from_slice(&rtc_save[..RTC_SAVE_SIZE]);
+            i64::from_le_bytes(bytes)
+        } else {
+            -context.clock.now().to_timespec().sec
+        };
+        Ok(Huc3Mapper {
+            rom,
+            ram,
+            current_rom_bank: 1,
+            current_ram_bank: 0,
+            mode: MODE_RAM_READ_ONLY,
+            infrared: context.infrared.clone(),
+            clock: context.clock.clone(),
+            rtc_offset,
+            rtc_memory: [0; 0x100],
+            rtc_address: 0,
+            rtc_response: 0,
+            has_battery,
+            ram_modified: false,
+        })
+    }
+
+    fn rom_mask(&self) -> usize {
+        self.rom.len() - 1
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let offset = self.current_ram_bank as usize * RAM_BANK_SIZE + (address & 0x1FFF) as usize;
+        offset & (self.ram.len() - 1)
+    }
+
+    /// Seconds since day 0 of the cartridge clock.
+    fn rtc_seconds(&self) -> i64 {
+        self.clock.now().to_timespec().sec + self.rtc_offset
+    }
+
+    fn read_rtc_nibbles(&self, addr: usize) -> i64 {
+        (0..3).fold(0, |value, i| value | (self.rtc_memory[addr + i] as i64) << (i * 4))
+    }
+
+    fn write_rtc_nibbles(&mut self, addr: usize, value: i64) {
+        for i in 0..3 {
+            self.rtc_memory[addr + i] = (value >> (i * 4)) as u8 & 0xF;
+        }
+    }
+
+    fn rtc_command(&mut self, data: u8) {
+        let command = (data >> 4) & 0x7;
+        let argument = data & 0xF;
+        let mut result = 0;
+        match command {
+            RTC_READ => {
+                result = self.rtc_memory[self.rtc_address as usize];
+                self.rtc_address = self.rtc_address.wrapping_add(1);
+            }
+            RTC_WRITE => {
+                self.rtc_memory[self.rtc_address as usize] = argument;
+                self.rtc_address = self.rtc_address.wrapping_add(1);
+            }
+            RTC_SET_ADDRESS_LOW => self.rtc_address = (self.rtc_address & 0xF0) | argument,
+            RTC_SET_ADDRESS_HIGH => self.rtc_address = (self.rtc_address & 0x0F) | argument << 4,
+            RTC_EXTENDED => match argument {
+                RTC_EXTENDED_READ_TIME => {
+                    let seconds = self.rtc_seconds();
+                    let days = seconds.div_euclid(SECONDS_PER_DAY);
+                    let minutes = seconds.rem_euclid(SECONDS_PER_DAY) / 60;
+                    self.write_rtc_nibbles(RTC_MINUTES_ADDR, minutes);
+                    self.write_rtc_nibbles(RTC_DAYS_ADDR, days);
+                }
+                RTC_EXTENDED_WRITE_TIME => {
+                    let minutes = self.read_rtc_nibbles(RTC_MINUTES_ADDR);
+                    let days = self.read_rtc_nibbles(RTC_DAYS_ADDR);
+                    let seconds = days * SECONDS_PER_DAY + minutes * 60;
+                    self.rtc_offset += seconds - self.rtc_seconds();
+                    self.ram_modified = true;
+                }
+                RTC_EXTENDED_STATUS => result = 1,
+                // The alarm and the speaker aren't emulated.
+                _ => (),
+            },
+            _ => (),
+        }
+        self.rtc_response = command << 4 | result;
+    }
+}
+
+impl Mapper for Huc3Mapper {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address & 0x4000 == 0 {
+            0
+        } else {
+            self.current_rom_bank
+        };
+        let offset = bank as usize * ROM_BANK_SIZE + (address & 0x3FFF) as usize;
+
+        self.rom[offset & self.rom_mask()]
+    }
+
+    fn write_rom(&mut self, address: u16, data: u8) {
+        match (address >> 13) & 0b11 {
+            0 => {
+                // RAM/RTC/IR select
+                self.mode = data & 0xF;
+            }
+            1 => {
+                // ROM bank
+                self.current_rom_bank = data & 0x7F;
+                if self.current_rom_bank == 0 {
+                    self.current_rom_bank = 1;
+                }
+            }
+            2 => {
+                // RAM bank
+                self.current_ram_bank = data & 0xF;
+            }
+            3 => {
+                // unused
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        match self.mode {
+            MODE_RAM_READ_ONLY | MODE_RAM if !self.ram.is_empty() => {
+                self.ram[self.ram_offset(address)]
+            }
+            MODE_RTC_RESPONSE => self.rtc_response,
+            // Commands complete right away, so the RTC is always ready.
+            MODE_RTC_SEMAPHORE => 0x1,
+            MODE_IR => 0xC0 | self.infrared.receiving() as u8,
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) {
+        match self.mode {
+            MODE_RAM if !self.ram.is_empty() => {
+                let offset = self.ram_offset(address);
+                self.ram[offset] = data;
+                self.ram_modified = true;
+            }
+            MODE_RTC_COMMAND => self.rtc_command(data),
+            MODE_IR => self.infrared.set_led(data & 1 != 0),
+            _ => (),
+        }
+    }
+
+    fn save_battery(&mut self) -> Vec<u8> {
+        if self.has_battery && self.ram_modified {
+            self.ram_modified = false;
+            let mut data = Vec::from(&*self.ram);
+            data.extend_from_slice(&self.rtc_offset.to_le_bytes());
+            data
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn set_context(&mut self, context: &CartridgeContext) {
+        self.clock = context.clock.clone();
+        self.infrared = context.infrared.clone();
+    }
+}
+
+impl SaveState for Huc3Mapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.current_rom_bank);
+        writer.write_u8(self.current_ram_bank);
+        writer.write_u8(self.mode);
+        writer.write_bytes(&self.ram);
+        writer.write_u64(self.rtc_offset as u64);
+        writer.write_bytes(&self.rtc_memory);
+        writer.write_u8(self.rtc_address);
+        writer.write_u8(self.rtc_response);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.current_rom_bank = reader.read_u8()?;
+        self.current_ram_bank = reader.read_u8()?;
+        self.mode = reader.read_u8()?;
+        reader.read_bytes_into(&mut self.ram)?;
+        self.rtc_offset = reader.read_u64()? as i64;
+        reader.read_bytes_into(&mut self.rtc_memory)?;
+        self.rtc_address = reader.read_u8()?;
+        self.rtc_response = reader.read_u8()?;
+        self.ram_modified = true;
+        Ok(())
+    }
+}
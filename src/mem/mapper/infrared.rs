@@ -0,0 +1,36 @@
+use std::cell::Cell;
+
+/// Whatever the infrared port of a cartridge is pointed at, such as the port of another
+/// instance's cartridge.
+pub trait Infrared {
+    /// Called when the cartridge turns its LED on or off.
+    fn set_led(&self, on: bool);
+    /// Whether the receiver of the cartridge sees light.
+    fn receiving(&self) -> bool;
+}
+
+/// Nothing in front of the port, no light ever comes in.
+pub struct NoInfrared;
+
+impl Infrared for NoInfrared {
+    fn set_led(&self, _on: bool) {}
+    fn receiving(&self) -> bool {
+        false
+    }
+}
+
+/// The port pointed at a mirror, the cartridge sees its own LED.
+#[derive(Default)]
+pub struct Mirror {
+    led: Cell<bool>,
+}
+
+impl Infrared for Mirror {
+    fn set_led(&self, on: bool) {
+        self.led.set(on);
+    }
+
+    fn receiving(&self) -> bool {
+        self.led.get()
+    }
+}
@@ -1,4 +1,6 @@
-use crate::mem::mapper::{Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::mem::cartridge::{CartridgeError, NINTENDO_LOGO};
+use crate::mem::mapper::{check_sizes, Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Mbc1Mapper {
     /// Mapped to the ROM area. Up to 2 MiB in size.
@@ -6,33 +8,51 @@ pub struct Mbc1Mapper {
     /// Mapped to the RAM area. Up to 32 KiB in size.
     ram: Box<[u8]>,
 
-    current_rom_bank: u8,
-    current_ram_bank: u8,
+    /// Lower ROM bank bits, written at 0x2000-0x3FFF. Never 0.
+    bank1: u8,
+    /// Written at 0x4000-0x5FFF. Selects the RAM bank, or the upper ROM bank bits on carts with
+    /// more than 512 KiB of ROM.
+    bank2: u8,
     ram_enabled: bool,
-    ram_banking_enabled: bool,
+    /// In advanced mode, `bank2` also switches the 0x0000-0x3FFF ROM area and the RAM area.
+    advanced_mode: bool,
 
+    /// MBC1M multicarts leave the top bit of `bank1` unconnected and wire `bank2` one bit lower,
+    /// so that it selects one of the four 256 KiB games.
+    multicart: bool,
     has_battery: bool,
     /// True is SRAM has been written to since the last time it was saved.
     ram_modified: bool,
 }
 
+/// Bank of the second game of an MBC1M multicart, which has the logo like the menu in bank 0.
+const MULTICART_GAME_BANK: usize = 0x10;
+
 impl Mbc1Mapper {
-    pub fn new(rom: Box<[u8]>, ram: Box<[u8]>, has_battery: bool) -> Mbc1Mapper {
-        assert!(rom.len() <= 2 << 20);
-        assert!(rom.len().is_power_of_two());
-        assert!(ram.len() <= 32 << 10);
-        assert!(ram.is_empty() || ram.len().is_power_of_two());
+    pub fn new(
+        rom: Box<[u8]>,
+        ram: Box<[u8]>,
+        has_battery: bool,
+    ) -> Result<Mbc1Mapper, CartridgeError> {
+        check_sizes("MBC1", &rom, 2 << 20, &ram, 32 << 10)?;
 
-        Mbc1Mapper {
+        Ok(Mbc1Mapper {
+            multicart: Mbc1Mapper::is_multicart(&rom),
             rom: rom,
             ram: ram,
-            current_rom_bank: 1,
-            current_ram_bank: 0,
+            bank1: 1,
+            bank2: 0,
             ram_enabled: false,
-            ram_banking_enabled: false,
+            advanced_mode: false,
             has_battery: has_battery,
             ram_modified: false,
-        }
+        })
+    }
+
+    /// Multicarts look like 1 MiB MBC1 games, but have a header with the logo every 256 KiB.
+    fn is_multicart(rom: &[u8]) -> bool {
+        let logo_addr = MULTICART_GAME_BANK * ROM_BANK_SIZE + 0x104;
+        rom.len() == 1 << 20 && rom[logo_addr..logo_addr + NINTENDO_LOGO.len()] == NINTENDO_LOGO
     }
 
     fn rom_mask(&self) -> usize {
@@ -42,16 +62,33 @@ impl Mbc1Mapper {
     fn ram_mask(&self) -> usize {
         self.ram.len() - 1
     }
+
+    /// The ROM bank bits that `bank2` drives.
+    fn upper_rom_bank(&self) -> usize {
+        let shift = if self.multicart { 4 } else { 5 };
+        (self.bank2 as usize) << shift
+    }
+
+    fn rom_bank(&self, address: u16) -> usize {
+        if address & 0x4000 != 0 {
+            let lower_mask = if self.multicart { 0xF } else { 0x1F };
+            self.upper_rom_bank() | (self.bank1 & lower_mask) as usize
+        } else if self.advanced_mode {
+            self.upper_rom_bank()
+        } else {
+            0
+        }
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let bank = if self.advanced_mode { self.bank2 } else { 0 };
+        (bank as usize * RAM_BANK_SIZE + (address & 0x1FFF) as usize) & self.ram_mask()
+    }
 }
 
 impl Mapper for Mbc1Mapper {
     fn read_rom(&self, address: u16) -> u8 {
-        let bank = if address & 0x4000 == 0 {
-            0
-        } else {
-            self.current_rom_bank
-        };
-        let offset = bank as usize * ROM_BANK_SIZE + (address & 0x3FFF) as usize;
+        let offset = self.rom_bank(address) * ROM_BANK_SIZE + (address & 0x3FFF) as usize;
 
         self.rom[offset & self.rom_mask()]
     }
@@ -63,31 +100,20 @@ impl Mapper for Mbc1Mapper {
                 self.ram_enabled = data & 0xF == 0xA;
             }
             1 => {
-                // ROM bank
-                let mut new_bank = data & 0x1F;
-                if new_bank == 0 {
-                    new_bank = 1;
+                // ROM bank. Bank 0 can't be selected here, but multicarts only see the lower 4
+                // bits after the check, so writing 0x10 maps the first bank of the current game.
+                self.bank1 = data & 0x1F;
+                if self.bank1 == 0 {
+                    self.bank1 = 1;
                 }
-
-                self.current_rom_bank = (self.current_rom_bank & !0x1F) | new_bank;
             }
             2 => {
                 // RAM bank / upper ROM bank
-                let new_bank = data & 0x3;
-                if self.ram_banking_enabled {
-                    self.current_ram_bank = new_bank;
-                } else {
-                    self.current_rom_bank = (self.current_rom_bank & !0x60) | (new_bank << 5);
-                }
+                self.bank2 = data & 0x3;
             }
             3 => {
-                // ROM/RAM mode
-                self.ram_banking_enabled = data & 0x1 == 0x1;
-                if self.ram_banking_enabled {
-                    self.current_rom_bank &= 0x1F;
-                } else {
-                    self.current_ram_bank = 0;
-                }
+                // Banking mode
+                self.advanced_mode = data & 0x1 == 0x1;
             }
             _ => unreachable!(),
         }
@@ -95,9 +121,7 @@ impl Mapper for Mbc1Mapper {
 
     fn read_ram(&self, address: u16) -> u8 {
         if self.ram_enabled && !self.ram.is_empty() {
-            let offset =
-                self.current_ram_bank as usize * RAM_BANK_SIZE + (address & 0x1FFF) as usize;
-            self.ram[offset & self.ram_mask()]
+            self.ram[self.ram_offset(address)]
         } else {
             0xFF
         }
@@ -105,10 +129,8 @@ impl Mapper for Mbc1Mapper {
 
     fn write_ram(&mut self, address: u16, data: u8) {
         if self.ram_enabled && !self.ram.is_empty() {
-            let offset =
-                self.current_ram_bank as usize * RAM_BANK_SIZE + (address & 0x1FFF) as usize;
-            let ram_mask = self.ram_mask();
-            self.ram[offset & ram_mask] = data;
+            let offset = self.ram_offset(address);
+            self.ram[offset] = data;
             self.ram_modified = true;
         }
     }
@@ -122,3 +144,23 @@ impl Mapper for Mbc1Mapper {
         }
     }
 }
+
+impl SaveState for Mbc1Mapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.bank1);
+        writer.write_u8(self.bank2);
+        writer.write_bool(self.ram_enabled);
+        writer.write_bool(self.advanced_mode);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.bank1 = reader.read_u8()? & 0x1F;
+        self.bank2 = reader.read_u8()? & 0x3;
+        self.ram_enabled = reader.read_bool()?;
+        self.advanced_mode = reader.read_bool()?;
+        reader.read_bytes_into(&mut self.ram)?;
+        self.ram_modified = true;
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
-use crate::mem::mapper::{Mapper, ROM_BANK_SIZE};
+use crate::mem::cartridge::CartridgeError;
+use crate::mem::mapper::{check_sizes, Mapper, ROM_BANK_SIZE};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Mbc2Mapper {
     /// Mapped to the ROM area. Up to 256 KiB in size.
@@ -16,19 +18,22 @@ pub struct Mbc2Mapper {
 }
 
 impl Mbc2Mapper {
-    pub fn new(rom: Box<[u8]>, ram: Box<[u8]>, has_battery: bool) -> Mbc2Mapper {
-        assert!(rom.len() <= 256 << 10);
-        assert!(rom.len().is_power_of_two());
-        assert!(ram.len() == 512);
+    pub fn new(
+        rom: Box<[u8]>,
+        ram: Box<[u8]>,
+        has_battery: bool,
+    ) -> Result<Mbc2Mapper, CartridgeError> {
+        // The 512 nibbles of RAM are built into the MBC2.
+        check_sizes("MBC2", &rom, 256 << 10, &ram, 512)?;
 
-        Mbc2Mapper {
+        Ok(Mbc2Mapper {
             rom: rom,
             ram: ram,
             current_rom_bank: 1,
             ram_enabled: false,
             has_battery: has_battery,
             ram_modified: false,
-        }
+        })
     }
 
     fn rom_mask(&self) -> usize {
@@ -105,3 +110,19 @@ impl Mapper for Mbc2Mapper {
         }
     }
 }
+
+impl SaveState for Mbc2Mapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.current_rom_bank);
+        writer.write_bool(self.ram_enabled);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.current_rom_bank = reader.read_u8()?;
+        self.ram_enabled = reader.read_bool()?;
+        reader.read_bytes_into(&mut self.ram)?;
+        self.ram_modified = true;
+        Ok(())
+    }
+}
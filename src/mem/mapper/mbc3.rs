@@ -1,5 +1,7 @@
 use crate::mem::mapper::rtc::Rtc;
-use crate::mem::mapper::{Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::mem::cartridge::CartridgeError;
+use crate::mem::mapper::{check_sizes, CartridgeContext, Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Mbc3Mapper {
     /// Mapped to the ROM area. Up to 2 MiB in size.
@@ -19,13 +21,16 @@ pub struct Mbc3Mapper {
 }
 
 impl Mbc3Mapper {
-    pub fn new(rom: Box<[u8]>, ram: Box<[u8]>, has_battery: bool, has_rtc: bool) -> Mbc3Mapper {
-        assert!(rom.len() <= 2 << 20);
-        assert!(rom.len().is_power_of_two());
-        assert!(ram.len() <= 64 << 10);
-        assert!(ram.is_empty() || ram.len().is_power_of_two());
-
-        Mbc3Mapper {
+    pub fn new(
+        rom: Box<[u8]>,
+        ram: Box<[u8]>,
+        has_battery: bool,
+        has_rtc: bool,
+        context: &CartridgeContext,
+    ) -> Result<Mbc3Mapper, CartridgeError> {
+        check_sizes("MBC3", &rom, 2 << 20, &ram, 64 << 10)?;
+
+        Ok(Mbc3Mapper {
             rom: rom,
             ram: ram,
             current_rom_bank: 1,
@@ -33,8 +38,12 @@ impl Mbc3Mapper {
             ram_enabled: false,
             has_battery: has_battery,
             ram_modified: false,
-            rtc: if has_rtc { Some(Rtc::new()) } else { None },
-        }
+            rtc: if has_rtc {
+                Some(Rtc::new(context.clock.clone()))
+            } else {
+                None
+            },
+        })
     }
 
     fn rom_mask(&self) -> usize {
@@ -138,4 +147,34 @@ impl Mapper for Mbc3Mapper {
             Vec::new()
         }
     }
+
+    fn set_context(&mut self, context: &CartridgeContext) {
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.set_clock(context.clock.clone());
+        }
+    }
+}
+
+impl SaveState for Mbc3Mapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.current_rom_bank);
+        writer.write_u8(self.current_ram_bank);
+        writer.write_bool(self.ram_enabled);
+        writer.write_bytes(&self.ram);
+        if let Some(ref rtc) = self.rtc {
+            rtc.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.current_rom_bank = reader.read_u8()?;
+        self.current_ram_bank = reader.read_u8()?;
+        self.ram_enabled = reader.read_bool()?;
+        reader.read_bytes_into(&mut self.ram)?;
+        if let Some(ref mut rtc) = self.rtc {
+            rtc.load_state(reader)?;
+        }
+        self.ram_modified = true;
+        Ok(())
+    }
 }
@@ -1,4 +1,6 @@
-use crate::mem::mapper::{Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::mem::cartridge::CartridgeError;
+use crate::mem::mapper::{check_sizes, Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct Mbc5Mapper {
     /// Mapped to the ROM area. Up to 8 MiB in size.
@@ -19,13 +21,14 @@ pub struct Mbc5Mapper {
 }
 
 impl Mbc5Mapper {
-    pub fn new(rom: Box<[u8]>, ram: Box<[u8]>, has_battery: bool) -> Mbc5Mapper {
-        assert!(rom.len() <= 8 << 20);
-        assert!(rom.len().is_power_of_two());
-        assert!(ram.len() <= 128 << 10);
-        assert!(ram.is_empty() || ram.len().is_power_of_two());
+    pub fn new(
+        rom: Box<[u8]>,
+        ram: Box<[u8]>,
+        has_battery: bool,
+    ) -> Result<Mbc5Mapper, CartridgeError> {
+        check_sizes("MBC5", &rom, 8 << 20, &ram, 128 << 10)?;
 
-        Mbc5Mapper {
+        Ok(Mbc5Mapper {
             rom: rom,
             ram: ram,
             current_rom_bank: 1,
@@ -34,7 +37,7 @@ impl Mbc5Mapper {
             has_battery: has_battery,
             ram_modified: false,
             rumble_on: false,
-        }
+        })
     }
 
     fn rom_mask(&self) -> usize {
@@ -116,3 +119,23 @@ impl Mapper for Mbc5Mapper {
         }
     }
 }
+
+impl SaveState for Mbc5Mapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.current_rom_bank);
+        writer.write_u8(self.current_ram_bank);
+        writer.write_bool(self.ram_enabled);
+        writer.write_bool(self.rumble_on);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.current_rom_bank = reader.read_u16()?;
+        self.current_ram_bank = reader.read_u8()?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rumble_on = reader.read_bool()?;
+        reader.read_bytes_into(&mut self.ram)?;
+        self.ram_modified = true;
+        Ok(())
+    }
+}
@@ -0,0 +1,294 @@
+use crate::mem::cartridge::CartridgeError;
+use crate::mem::mapper::{check_sizes, Mapper};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+/// Size of the flash chip, saved after the RAM in the battery file.
+pub const FLASH_SIZE: usize = 1 << 20;
+/// The ROM and flash are switched in two 8 KiB windows.
+const ROM_WINDOW_SIZE: usize = 0x2000;
+/// The RAM is switched in two 4 KiB windows.
+const RAM_WINDOW_SIZE: usize = 0x1000;
+
+/// Flash commands are written to these addresses, as seen by the chip.
+const FLASH_COMMAND_ADDR: usize = 0x5555;
+const FLASH_UNLOCK_ADDR: usize = 0x2AAA;
+const FLASH_SECTOR_SIZE: usize = 0x20000;
+/// Programming goes 128 bytes at a time.
+const FLASH_PAGE_SIZE: usize = 0x80;
+/// What the chip answers in ID mode: Macronix, MX29F008.
+const FLASH_MANUFACTURER_ID: u8 = 0xC2;
+const FLASH_DEVICE_ID: u8 = 0x81;
+
+#[derive(Copy, Clone, PartialEq)]
+enum FlashState {
+    Ready = 0,
+    /// 0xAA was written to the command address.
+    Unlock1 = 1,
+    /// Followed by 0x55 to the unlock address, the next write is a command.
+    Unlock2 = 2,
+    /// The erase command needs to be unlocked again.
+    Erase = 3,
+    EraseUnlock1 = 4,
+    EraseUnlock2 = 5,
+    /// Writes program bytes until the end of the page.
+    Program = 6,
+}
+
+/// One of the two switchable windows of the ROM area.
+#[derive(Copy, Clone)]
+struct RomWindow {
+    bank: u8,
+    /// Maps the flash instead of the ROM.
+    flash: bool,
+}
+
+pub struct Mbc6Mapper {
+    /// The first 16 KiB are fixed at 0x0000-0x3FFF, the rest is switched in 8 KiB banks at
+    /// 0x4000-0x5FFF and 0x6000-0x7FFF. Up to 1 MiB in size.
+    rom: Box<[u8]>,
+    /// Switched in 4 KiB banks at 0xA000-0xAFFF and 0xB000-0xBFFF. Up to 32 KiB in size.
+    ram: Box<[u8]>,
+    flash: Box<[u8]>,
+
+    rom_windows: [RomWindow; 2],
+    ram_banks: [u8; 2],
+    ram_enabled: bool,
+    flash_enabled: bool,
+    /// Erasing and programming only have an effect while this is set.
+    flash_write_enabled: bool,
+    flash_state: FlashState,
+    /// Reads from the flash return its IDs instead of its contents.
+    flash_id_mode: bool,
+
+    has_battery: bool,
+    /// True is SRAM or the flash have been written to since the last time they were saved.
+    ram_modified: bool,
+}
+
+impl Mbc6Mapper {
+    /// `flash_save` is what follows the RAM in the battery file. The flash starts erased if it's
+    /// empty.
+    pub fn new(
+        rom: Box<[u8]>,
+        ram: Box<[u8]>,
+        flash_save: &[u8],
+        has_battery: bool,
+    ) -> Result<Mbc6Mapper, CartridgeError> {
+        check_sizes("MBC6", &rom, 1 << 20, &ram, 32 << 10)?;
+
+        let mut flash = vec![0xFF; FLASH_SIZE].into_boxed_slice();
+        let copy_len = flash_save.len().min(FLASH_SIZE);
+        flash[..copy_len].copy_from_slice(&flash_save[..copy_len]);
+        Ok(Mbc6Mapper {
+            rom,
+            ram,
+            flash,
+            rom_windows: [
+                RomWindow {
+                    bank: 2,
+                    flash: false,
+                },
+                RomWindow {
+                    bank: 3,
+                    flash: false,
+                },
+            ],
+            ram_banks: [0, 1],
+            ram_enabled: false,
+            flash_enabled: false,
+            flash_write_enabled: false,
+            flash_state: FlashState::Ready,
+            flash_id_mode: false,
+            has_battery,
+            ram_modified: false,
+        })
+    }
+
+    fn rom_window(&self, address: u16) -> RomWindow {
+        self.rom_windows[(address as usize >> 13) & 1]
+    }
+
+    fn window_offset(window: RomWindow, address: u16) -> usize {
+        window.bank as usize * ROM_WINDOW_SIZE + (address as usize & (ROM_WINDOW_SIZE - 1))
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let bank = self.ram_banks[(address as usize >> 12) & 1];
+        let offset = bank as usize * RAM_WINDOW_SIZE + (address as usize & (RAM_WINDOW_SIZE - 1));
+        offset & (self.ram.len() - 1)
+    }
+
+    fn read_flash(&self, offset: usize) -> u8 {
+        if self.flash_id_mode {
+            match offset & 1 {
+                0 => FLASH_MANUFACTURER_ID,
+                _ => FLASH_DEVICE_ID,
+            }
+        } else {
+            self.flash[offset & (FLASH_SIZE - 1)]
+        }
+    }
+
+    fn erase_flash(&mut self, start: usize, len: usize) {
+        if self.flash_write_enabled {
+            for byte in &mut self.flash[start..start + len] {
+                *byte = 0xFF;
+            }
+            self.ram_modified = true;
+        }
+    }
+
+    fn write_flash(&mut self, offset: usize, data: u8) {
+        let offset = offset & (FLASH_SIZE - 1);
+        if self.flash_state == FlashState::Program {
+            // Programming can only clear bits, erasing sets them back.
+            if self.flash_write_enabled {
+                self.flash[offset] &= data;
+                self.ram_modified = true;
+            }
+            if offset % FLASH_PAGE_SIZE == FLASH_PAGE_SIZE - 1 {
+                self.flash_state = FlashState::Ready;
+            }
+            return;
+        }
+
+        let command_addr = offset & 0x7FFF;
+        self.flash_state = match (self.flash_state, command_addr, data) {
+            (_, _, 0xF0) => {
+                self.flash_id_mode = false;
+                FlashState::Ready
+            }
+            (FlashState::Ready, FLASH_COMMAND_ADDR, 0xAA) => FlashState::Unlock1,
+            (FlashState::Unlock1, FLASH_UNLOCK_ADDR, 0x55) => FlashState::Unlock2,
+            (FlashState::Unlock2, FLASH_COMMAND_ADDR, 0x90) => {
+                self.flash_id_mode = true;
+                FlashState::Ready
+            }
+            (FlashState::Unlock2, FLASH_COMMAND_ADDR, 0x80) => FlashState::Erase,
+            (FlashState::Unlock2, FLASH_COMMAND_ADDR, 0xA0) => FlashState::Program,
+            (FlashState::Erase, FLASH_COMMAND_ADDR, 0xAA) => FlashState::EraseUnlock1,
+            (FlashState::EraseUnlock1, FLASH_UNLOCK_ADDR, 0x55) => FlashState::EraseUnlock2,
+            (FlashState::EraseUnlock2, _, 0x30) => {
+                let sector = offset & !(FLASH_SECTOR_SIZE - 1);
+                self.erase_flash(sector, FLASH_SECTOR_SIZE);
+                FlashState::Ready
+            }
+            (FlashState::EraseUnlock2, FLASH_COMMAND_ADDR, 0x10) => {
+                self.erase_flash(0, FLASH_SIZE);
+                FlashState::Ready
+            }
+            _ => FlashState::Ready,
+        };
+    }
+}
+
+impl Mapper for Mbc6Mapper {
+    fn read_rom(&self, address: u16) -> u8 {
+        if address < 0x4000 {
+            return self.rom[address as usize & (self.rom.len() - 1)];
+        }
+        let window = self.rom_window(address);
+        let offset = Mbc6Mapper::window_offset(window, address);
+        if !window.flash {
+            self.rom[offset & (self.rom.len() - 1)]
+        } else if self.flash_enabled {
+            self.read_flash(offset)
+        } else {
+            0xFF
+        }
+    }
+
+    fn write_rom(&mut self, address: u16, data: u8) {
+        match address {
+            0x0000..=0x03FF => self.ram_enabled = data & 0xF == 0xA,
+            0x0400..=0x07FF => self.ram_banks[0] = data & 0x7,
+            0x0800..=0x0BFF => self.ram_banks[1] = data & 0x7,
+            0x0C00..=0x0FFF => self.flash_enabled = data & 1 != 0,
+            0x1000 => self.flash_write_enabled = data & 1 != 0,
+            0x2000..=0x27FF => self.rom_windows[0].bank = data & 0x7F,
+            0x2800..=0x2FFF => self.rom_windows[0].flash = data & 0x08 != 0,
+            0x3000..=0x37FF => self.rom_windows[1].bank = data & 0x7F,
+            0x3800..=0x3FFF => self.rom_windows[1].flash = data & 0x08 != 0,
+            0x4000..=0x7FFF => {
+                let window = self.rom_window(address);
+                if window.flash && self.flash_enabled {
+                    self.write_flash(Mbc6Mapper::window_offset(window, address), data);
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if self.ram_enabled && !self.ram.is_empty() {
+            self.ram[self.ram_offset(address)]
+        } else {
+            0xFF
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) {
+        if self.ram_enabled && !self.ram.is_empty() {
+            let offset = self.ram_offset(address);
+            self.ram[offset] = data;
+            self.ram_modified = true;
+        }
+    }
+
+    fn save_battery(&mut self) -> Vec<u8> {
+        if self.has_battery && self.ram_modified {
+            self.ram_modified = false;
+            let mut data = Vec::with_capacity(self.ram.len() + FLASH_SIZE);
+            data.extend_from_slice(&self.ram);
+            data.extend_from_slice(&self.flash);
+            data
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+impl SaveState for Mbc6Mapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for window in self.rom_windows.iter() {
+            writer.write_u8(window.bank);
+            writer.write_bool(window.flash);
+        }
+        writer.write_u8(self.ram_banks[0]);
+        writer.write_u8(self.ram_banks[1]);
+        writer.write_bool(self.ram_enabled);
+        writer.write_bool(self.flash_enabled);
+        writer.write_bool(self.flash_write_enabled);
+        writer.write_u8(self.flash_state as u8);
+        writer.write_bool(self.flash_id_mode);
+        writer.write_bytes(&self.ram);
+        writer.write_bytes(&self.flash);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        for window in self.rom_windows.iter_mut() {
+            window.bank = reader.read_u8()?;
+            window.flash = reader.read_bool()?;
+        }
+        self.ram_banks[0] = reader.read_u8()?;
+        self.ram_banks[1] = reader.read_u8()?;
+        self.ram_enabled = reader.read_bool()?;
+        self.flash_enabled = reader.read_bool()?;
+        self.flash_write_enabled = reader.read_bool()?;
+        self.flash_state = match reader.read_u8()? {
+            0 => FlashState::Ready,
+            1 => FlashState::Unlock1,
+            2 => FlashState::Unlock2,
+            3 => FlashState::Erase,
+            4 => FlashState::EraseUnlock1,
+            5 => FlashState::EraseUnlock2,
+            6 => FlashState::Program,
+            _ => return Err(StateError::Corrupt("invalid MBC6 flash state")),
+        };
+        self.flash_id_mode = reader.read_bool()?;
+        reader.read_bytes_into(&mut self.ram)?;
+        reader.read_bytes_into(&mut self.flash)?;
+        self.ram_modified = true;
+        Ok(())
+    }
+}
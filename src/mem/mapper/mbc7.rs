@@ -0,0 +1,417 @@
+use crate::mem::cartridge::CartridgeError;
+use crate::mem::mapper::{check_sizes, CartridgeContext, Mapper, ROM_BANK_SIZE};
+use crate::peripherals::tilt::Tilt;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Size of the 93LC56 EEPROM, which takes the place of SRAM in the battery file.
+pub const EEPROM_SIZE: usize = 256;
+const EEPROM_WORDS: usize = EEPROM_SIZE / 2;
+
+/// What the accelerometer reports when level, and how much it moves away from it at 1 g.
+const ACCELEROMETER_CENTER: f32 = 0x81D0 as f32;
+const ACCELEROMETER_RANGE: f32 = 0x70 as f32;
+/// The latched values after erasing them, until the next latch.
+const ACCELEROMETER_ERASED: u16 = 0x8000;
+
+/// Registers of the 0xA000-0xAFFF area, selected by bits 4-7 of the address.
+const REG_ERASE_LATCH: u16 = 0x0;
+const REG_LATCH: u16 = 0x1;
+const REG_X_LOW: u16 = 0x2;
+const REG_X_HIGH: u16 = 0x3;
+const REG_Y_LOW: u16 = 0x4;
+const REG_Y_HIGH: u16 = 0x5;
+const REG_UNUSED_LOW: u16 = 0x6;
+const REG_EEPROM: u16 = 0x8;
+
+/// Pins of the EEPROM register.
+const EEPROM_CS: u8 = 1 << 7;
+const EEPROM_CLK: u8 = 1 << 6;
+const EEPROM_DI: u8 = 1 << 1;
+const EEPROM_DO: u8 = 1 << 0;
+
+/// Commands are a start bit followed by a 2-bit opcode and an 8-bit address, of which only the
+/// low 7 bits select a word.
+const COMMAND_BITS: u8 = 10;
+const OP_EXTENDED: u16 = 0b00;
+const OP_WRITE: u16 = 0b01;
+const OP_READ: u16 = 0b10;
+const OP_ERASE: u16 = 0b11;
+/// Extended commands are told apart by the top 2 bits of the address.
+const EXT_EWDS: u16 = 0b00;
+const EXT_WRAL: u16 = 0b01;
+const EXT_ERAL: u16 = 0b10;
+const EXT_EWEN: u16 = 0b11;
+
+#[derive(Copy, Clone, PartialEq)]
+enum EepromState {
+    /// Waiting for a start bit.
+    Idle = 0,
+    /// Shifting in the opcode and address.
+    Command = 1,
+    /// Shifting out words, moving on to the next one after every 16 bits.
+    Read = 2,
+    /// Shifting in the word to write at the address.
+    Write = 3,
+    /// Shifting in the word to write everywhere.
+    WriteAll = 4,
+}
+
+/// A 93LC56 serial EEPROM organized in 16-bit words, driven bit by bit through its pins.
+/// Writes complete right away, so it never reports being busy.
+struct Eeprom {
+    /// Words stored little-endian.
+    data: Box<[u8]>,
+    modified: bool,
+
+    cs: bool,
+    clk: bool,
+    di: bool,
+    /// Data out, high when ready.
+    do_: bool,
+    state: EepromState,
+    /// Bits shifted in or left to shift out.
+    shift: u16,
+    bits: u8,
+    address: u8,
+    /// Set by EWEN, cleared by EWDS. Erasing and writing are ignored otherwise.
+    write_enabled: bool,
+}
+
+impl Eeprom {
+    fn new(data: Box<[u8]>) -> Eeprom {
+        Eeprom {
+            data,
+            modified: false,
+            cs: false,
+            clk: false,
+            di: false,
+            do_: true,
+            state: EepromState::Idle,
+            shift: 0,
+            bits: 0,
+            address: 0,
+            write_enabled: false,
+        }
+    }
+
+    fn word(&self, address: u8) -> u16 {
+        let i = (address as usize % EEPROM_WORDS) * 2;
+        self.data[i] as u16 | (self.data[i + 1] as u16) << 8
+    }
+
+    fn set_word(&mut self, address: u8, word: u16) {
+        if self.write_enabled {
+            let i = (address as usize % EEPROM_WORDS) * 2;
+            self.data[i] = word as u8;
+            self.data[i + 1] = (word >> 8) as u8;
+            self.modified = true;
+        }
+    }
+
+    fn read_pins(&self) -> u8 {
+        let mut pins = 0;
+        if self.cs {
+            pins |= EEPROM_CS;
+        }
+        if self.clk {
+            pins |= EEPROM_CLK;
+        }
+        if self.di {
+            pins |= EEPROM_DI;
+        }
+        if self.do_ {
+            pins |= EEPROM_DO;
+        }
+        pins
+    }
+
+    fn write_pins(&mut self, pins: u8) {
+        let cs = pins & EEPROM_CS != 0;
+        let clk = pins & EEPROM_CLK != 0;
+        self.di = pins & EEPROM_DI != 0;
+        if !cs {
+            // Deselecting aborts whatever command was going on.
+            self.state = EepromState::Idle;
+            self.do_ = true;
+        } else if clk && !self.clk {
+            self.clock_bit();
+        }
+        self.cs = cs;
+        self.clk = clk;
+    }
+
+    /// Handles a rising edge of the clock while selected.
+    fn clock_bit(&mut self) {
+        match self.state {
+            EepromState::Idle => {
+                if self.di {
+                    self.state = EepromState::Command;
+                    self.shift = 0;
+                    self.bits = 0;
+                }
+            }
+            EepromState::Command => {
+                self.shift = self.shift << 1 | self.di as u16;
+                self.bits += 1;
+                if self.bits == COMMAND_BITS {
+                    self.command();
+                }
+            }
+            EepromState::Read => {
+                self.do_ = self.shift & 0x8000 != 0;
+                self.shift <<= 1;
+                self.bits += 1;
+                if self.bits == 16 {
+                    self.address = self.address.wrapping_add(1) & 0x7F;
+                    self.shift = self.word(self.address);
+                    self.bits = 0;
+                }
+            }
+            EepromState::Write | EepromState::WriteAll => {
+                self.shift = self.shift << 1 | self.di as u16;
+                self.bits += 1;
+                if self.bits == 16 {
+                    if self.state == EepromState::Write {
+                        self.set_word(self.address, self.shift);
+                    } else {
+                        for address in 0..EEPROM_WORDS as u8 {
+                            self.set_word(address, self.shift);
+                        }
+                    }
+                    self.state = EepromState::Idle;
+                    self.do_ = true;
+                }
+            }
+        }
+    }
+
+    fn command(&mut self) {
+        let opcode = (self.shift >> 8) & 0b11;
+        let extended = (self.shift >> 6) & 0b11;
+        self.address = self.shift as u8 & 0x7F;
+        self.shift = 0;
+        self.bits = 0;
+        self.state = EepromState::Idle;
+        match opcode {
+            OP_READ => {
+                // A dummy 0 comes before the data.
+                self.do_ = false;
+                self.shift = self.word(self.address);
+                self.state = EepromState::Read;
+            }
+            OP_WRITE => self.state = EepromState::Write,
+            OP_ERASE => self.set_word(self.address, 0xFFFF),
+            OP_EXTENDED => match extended {
+                EXT_EWDS => self.write_enabled = false,
+                EXT_WRAL => self.state = EepromState::WriteAll,
+                EXT_ERAL => {
+                    for address in 0..EEPROM_WORDS as u8 {
+                        self.set_word(address, 0xFFFF);
+                    }
+                }
+                EXT_EWEN => self.write_enabled = true,
+                _ => unreachable!(),
+            },
+            _ => unreachable!(),
+        }
+    }
+}
+
+pub struct Mbc7Mapper {
+    /// Mapped to the ROM area. Up to 2 MiB in size.
+    rom: Box<[u8]>,
+    eeprom: Eeprom,
+
+    current_rom_bank: u8,
+    /// The 0xA000-0xAFFF registers are only accessible with both enables set.
+    ram_enabled_1: bool,
+    ram_enabled_2: bool,
+
+    tilt: Rc<Cell<Tilt>>,
+    /// Set by erasing the latched values, a latch only happens after one.
+    latch_ready: bool,
+    latched_x: u16,
+    latched_y: u16,
+
+    has_battery: bool,
+}
+
+impl Mbc7Mapper {
+    /// `ram` is the contents of the EEPROM, `EEPROM_SIZE` bytes.
+    pub fn new(
+        rom: Box<[u8]>,
+        ram: Box<[u8]>,
+        has_battery: bool,
+        context: &CartridgeContext,
+    ) -> Result<Mbc7Mapper, CartridgeError> {
+        check_sizes("MBC7", &rom, 2 << 20, &ram, EEPROM_SIZE)?;
+
+        Ok(Mbc7Mapper {
+            rom,
+            eeprom: Eeprom::new(ram),
+            current_rom_bank: 1,
+            ram_enabled_1: false,
+            ram_enabled_2: false,
+            tilt: context.tilt.clone(),
+            latch_ready: false,
+            latched_x: ACCELEROMETER_ERASED,
+            latched_y: ACCELEROMETER_ERASED,
+            has_battery,
+        })
+    }
+
+    fn rom_mask(&self) -> usize {
+        self.rom.len() - 1
+    }
+
+    fn registers_enabled(&self) -> bool {
+        self.ram_enabled_1 && self.ram_enabled_2
+    }
+
+    fn accelerometer_value(g: f32) -> u16 {
+        (ACCELEROMETER_CENTER + g * ACCELEROMETER_RANGE).round() as u16
+    }
+
+    fn latch(&mut self) {
+        let tilt = self.tilt.get().clamped();
+        self.latched_x = Mbc7Mapper::accelerometer_value(tilt.x);
+        self.latched_y = Mbc7Mapper::accelerometer_value(tilt.y);
+    }
+}
+
+impl Mapper for Mbc7Mapper {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address & 0x4000 == 0 {
+            0
+        } else {
+            self.current_rom_bank
+        };
+        let offset = bank as usize * ROM_BANK_SIZE + (address & 0x3FFF) as usize;
+
+        self.rom[offset & self.rom_mask()]
+    }
+
+    fn write_rom(&mut self, address: u16, data: u8) {
+        match (address >> 13) & 0b11 {
+            0 => {
+                // RAM enable 1
+                self.ram_enabled_1 = data == 0x0A;
+            }
+            1 => {
+                // ROM bank
+                self.current_rom_bank = data & 0x7F;
+            }
+            2 => {
+                // RAM enable 2
+                self.ram_enabled_2 = data == 0x40;
+            }
+            3 => {
+                // unused
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.registers_enabled() || address >= 0xB000 {
+            return 0xFF;
+        }
+        match (address >> 4) & 0xF {
+            REG_X_LOW => self.latched_x as u8,
+            REG_X_HIGH => (self.latched_x >> 8) as u8,
+            REG_Y_LOW => self.latched_y as u8,
+            REG_Y_HIGH => (self.latched_y >> 8) as u8,
+            REG_UNUSED_LOW => 0x00,
+            REG_EEPROM => self.eeprom.read_pins(),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) {
+        if !self.registers_enabled() || address >= 0xB000 {
+            return;
+        }
+        match (address >> 4) & 0xF {
+            REG_ERASE_LATCH if data == 0x55 => {
+                self.latch_ready = true;
+                self.latched_x = ACCELEROMETER_ERASED;
+                self.latched_y = ACCELEROMETER_ERASED;
+            }
+            REG_LATCH if data == 0xAA && self.latch_ready => {
+                self.latch_ready = false;
+                self.latch();
+            }
+            REG_EEPROM => self.eeprom.write_pins(data),
+            _ => (),
+        }
+    }
+
+    fn save_battery(&mut self) -> Vec<u8> {
+        if self.has_battery && self.eeprom.modified {
+            self.eeprom.modified = false;
+            Vec::from(&*self.eeprom.data)
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn set_context(&mut self, context: &CartridgeContext) {
+        self.tilt = context.tilt.clone();
+    }
+}
+
+impl SaveState for Mbc7Mapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.current_rom_bank);
+        writer.write_bool(self.ram_enabled_1);
+        writer.write_bool(self.ram_enabled_2);
+        writer.write_bool(self.latch_ready);
+        writer.write_u16(self.latched_x);
+        writer.write_u16(self.latched_y);
+
+        let eeprom = &self.eeprom;
+        writer.write_bytes(&eeprom.data);
+        writer.write_bool(eeprom.cs);
+        writer.write_bool(eeprom.clk);
+        writer.write_bool(eeprom.di);
+        writer.write_bool(eeprom.do_);
+        writer.write_u8(eeprom.state as u8);
+        writer.write_u16(eeprom.shift);
+        writer.write_u8(eeprom.bits);
+        writer.write_u8(eeprom.address);
+        writer.write_bool(eeprom.write_enabled);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.current_rom_bank = reader.read_u8()?;
+        self.ram_enabled_1 = reader.read_bool()?;
+        self.ram_enabled_2 = reader.read_bool()?;
+        self.latch_ready = reader.read_bool()?;
+        self.latched_x = reader.read_u16()?;
+        self.latched_y = reader.read_u16()?;
+
+        let eeprom = &mut self.eeprom;
+        reader.read_bytes_into(&mut eeprom.data)?;
+        eeprom.cs = reader.read_bool()?;
+        eeprom.clk = reader.read_bool()?;
+        eeprom.di = reader.read_bool()?;
+        eeprom.do_ = reader.read_bool()?;
+        eeprom.state = match reader.read_u8()? {
+            0 => EepromState::Idle,
+            1 => EepromState::Command,
+            2 => EepromState::Read,
+            3 => EepromState::Write,
+            4 => EepromState::WriteAll,
+            _ => return Err(StateError::Corrupt("invalid MBC7 EEPROM state")),
+        };
+        eeprom.shift = reader.read_u16()?;
+        eeprom.bits = reader.read_u8()?;
+        eeprom.address = reader.read_u8()?;
+        eeprom.write_enabled = reader.read_bool()?;
+        eeprom.modified = true;
+        Ok(())
+    }
+}
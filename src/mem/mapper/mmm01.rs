@@ -0,0 +1,236 @@
+use crate::mem::cartridge::CartridgeError;
+use crate::mem::mapper::{check_sizes, Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+/// The menu lives in the last 32 KiB of the ROM, which is all that's mapped until it locks.
+pub const MENU_SIZE: usize = 0x8000;
+
+/// Multicart mapper. On power-on it shows the menu from the end of the ROM. The menu then picks
+/// a game by setting the upper bank bits and masks, and locks them; from then on the game sees an
+/// MBC1 limited to its own part of the ROM and RAM.
+pub struct Mmm01Mapper {
+    /// Mapped to the ROM area. Up to 8 MiB in size.
+    rom: Box<[u8]>,
+    /// Mapped to the RAM area. Up to 128 KiB in size.
+    ram: Box<[u8]>,
+
+    /// Set by the menu, after which only the bits the game owns can be written.
+    locked: bool,
+    ram_enabled: bool,
+    /// Bits 0-4 of the ROM bank, the ones the game writes like on an MBC1.
+    rom_bank_low: u8,
+    /// Bits 5-6 and 7-8 of the ROM bank, only written by the menu.
+    rom_bank_mid: u8,
+    rom_bank_high: u8,
+    /// Which of bits 1-4 of `rom_bank_low` stay as the menu left them, shifted down by one.
+    rom_bank_mask: u8,
+    /// Bits 0-1 of the RAM bank, written by the game in MBC1 RAM banking mode.
+    ram_bank_low: u8,
+    /// Bits 2-3 of the RAM bank, only written by the menu.
+    ram_bank_high: u8,
+    /// Which bits of `ram_bank_low` stay as the menu left them.
+    ram_bank_mask: u8,
+    /// MBC1 banking mode: the RAM bank register selects the RAM bank when set.
+    ram_banking_mode: bool,
+    /// Keeps the game from changing `ram_banking_mode`.
+    banking_mode_locked: bool,
+
+    has_battery: bool,
+    /// True is SRAM has been written to since the last time it was saved.
+    ram_modified: bool,
+}
+
+impl Mmm01Mapper {
+    /// `rom` has the menu at the end, as on the cartridge.
+    pub fn new(
+        rom: Box<[u8]>,
+        ram: Box<[u8]>,
+        has_battery: bool,
+    ) -> Result<Mmm01Mapper, CartridgeError> {
+        check_sizes("MMM01", &rom, 8 << 20, &ram, 128 << 10)?;
+
+        Ok(Mmm01Mapper {
+            rom,
+            ram,
+            locked: false,
+            ram_enabled: false,
+            rom_bank_low: 0,
+            rom_bank_mid: 0,
+            rom_bank_high: 0,
+            rom_bank_mask: 0,
+            ram_bank_low: 0,
+            ram_bank_high: 0,
+            ram_bank_mask: 0,
+            ram_banking_mode: false,
+            banking_mode_locked: false,
+            has_battery,
+            ram_modified: false,
+        })
+    }
+
+    fn rom_mask(&self) -> usize {
+        self.rom.len() - 1
+    }
+
+    /// Bits of `rom_bank_low` the game can't change.
+    fn locked_rom_bits(&self) -> u8 {
+        if self.locked {
+            self.rom_bank_mask << 1
+        } else {
+            0
+        }
+    }
+
+    fn locked_ram_bits(&self) -> u8 {
+        if self.locked {
+            self.ram_bank_mask
+        } else {
+            0
+        }
+    }
+
+    /// The bank at 0x4000-0x7FFF. The one at 0x0000-0x3FFF is the same with the bits the game
+    /// owns cleared, which is the start of the game.
+    fn rom_bank(&self) -> usize {
+        let game_bits = 0x1F & !self.locked_rom_bits();
+        let mut low = self.rom_bank_low;
+        // Like on the MBC1, bank 0 of the game maps bank 1 instead.
+        if low & game_bits == 0 {
+            low |= 1;
+        }
+        (self.rom_bank_high as usize) << 7 | (self.rom_bank_mid as usize) << 5 | low as usize
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let low = if self.ram_banking_mode {
+            self.ram_bank_low
+        } else {
+            self.ram_bank_low & self.locked_ram_bits()
+        };
+        let bank = (self.ram_bank_high << 2 | low) as usize;
+        let offset = bank * RAM_BANK_SIZE + (address & 0x1FFF) as usize;
+        offset & (self.ram.len() - 1)
+    }
+}
+
+impl Mapper for Mmm01Mapper {
+    fn read_rom(&self, address: u16) -> u8 {
+        if !self.locked {
+            let menu_start = self.rom.len().saturating_sub(MENU_SIZE);
+            return self.rom[(menu_start + (address & 0x7FFF) as usize) & self.rom_mask()];
+        }
+
+        let bank = if address & 0x4000 == 0 {
+            self.rom_bank() & !(0x1F & !self.locked_rom_bits() as usize)
+        } else {
+            self.rom_bank()
+        };
+        let offset = bank * ROM_BANK_SIZE + (address & 0x3FFF) as usize;
+
+        self.rom[offset & self.rom_mask()]
+    }
+
+    fn write_rom(&mut self, address: u16, data: u8) {
+        match (address >> 13) & 0b11 {
+            0 => {
+                // RAM enable, then the RAM bank mask and lock for the menu
+                self.ram_enabled = data & 0xF == 0xA;
+                if !self.locked {
+                    self.ram_bank_mask = (data >> 4) & 0b11;
+                    self.locked = data & 0x40 != 0;
+                }
+            }
+            1 => {
+                // ROM bank, bits 0-4 then 5-6 for the menu
+                let locked_bits = self.locked_rom_bits();
+                self.rom_bank_low =
+                    (self.rom_bank_low & locked_bits) | (data & 0x1F & !locked_bits);
+                if !self.locked {
+                    self.rom_bank_mid = (data >> 5) & 0b11;
+                }
+            }
+            2 => {
+                // RAM bank bits 0-1, then RAM bank bits 2-3, ROM bank bits 7-8 and the banking
+                // mode lock for the menu
+                let locked_bits = self.locked_ram_bits();
+                self.ram_bank_low =
+                    (self.ram_bank_low & locked_bits) | (data & 0b11 & !locked_bits);
+                if !self.locked {
+                    self.ram_bank_high = (data >> 2) & 0b11;
+                    self.rom_bank_high = (data >> 4) & 0b11;
+                    self.banking_mode_locked = data & 0x40 != 0;
+                }
+            }
+            3 => {
+                // Banking mode, then the ROM bank mask for the menu. Bit 6 multiplexes the bank
+                // bits for some collections, which isn't emulated.
+                if !self.banking_mode_locked || !self.locked {
+                    self.ram_banking_mode = data & 1 != 0;
+                }
+                if !self.locked {
+                    self.rom_bank_mask = (data >> 2) & 0xF;
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if self.ram_enabled && !self.ram.is_empty() {
+            self.ram[self.ram_offset(address)]
+        } else {
+            0xFF
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) {
+        if self.ram_enabled && !self.ram.is_empty() {
+            let offset = self.ram_offset(address);
+            self.ram[offset] = data;
+            self.ram_modified = true;
+        }
+    }
+
+    fn save_battery(&mut self) -> Vec<u8> {
+        if self.has_battery && self.ram_modified {
+            self.ram_modified = false;
+            Vec::from(&*self.ram)
+        } else {
+            Vec::new()
+        }
+    }
+}
+
+impl SaveState for Mmm01Mapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.locked);
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.rom_bank_low);
+        writer.write_u8(self.rom_bank_mid);
+        writer.write_u8(self.rom_bank_high);
+        writer.write_u8(self.rom_bank_mask);
+        writer.write_u8(self.ram_bank_low);
+        writer.write_u8(self.ram_bank_high);
+        writer.write_u8(self.ram_bank_mask);
+        writer.write_bool(self.ram_banking_mode);
+        writer.write_bool(self.banking_mode_locked);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.locked = reader.read_bool()?;
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank_low = reader.read_u8()?;
+        self.rom_bank_mid = reader.read_u8()?;
+        self.rom_bank_high = reader.read_u8()?;
+        self.rom_bank_mask = reader.read_u8()?;
+        self.ram_bank_low = reader.read_u8()?;
+        self.ram_bank_high = reader.read_u8()?;
+        self.ram_bank_mask = reader.read_u8()?;
+        self.ram_banking_mode = reader.read_bool()?;
+        self.banking_mode_locked = reader.read_bool()?;
+        reader.read_bytes_into(&mut self.ram)?;
+        self.ram_modified = true;
+        Ok(())
+    }
+}
@@ -1,14 +1,77 @@
+pub mod camera;
+pub mod huc1;
+pub mod huc3;
+pub mod infrared;
 pub mod mbc1;
 pub mod mbc2;
 pub mod mbc3;
 pub mod mbc5;
+pub mod mbc6;
+pub mod mbc7;
+pub mod mmm01;
+pub mod pocket_camera;
 pub mod rom;
 pub mod rtc;
+pub mod tama5;
+
+use crate::clock::{Clock, HostClock};
+use crate::mem::cartridge::CartridgeError;
+use crate::mem::mapper::camera::{ImageSource, TestPattern};
+use crate::mem::mapper::infrared::{Infrared, NoInfrared};
+use crate::peripherals::tilt::Tilt;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+use std::cell::Cell;
+use std::rc::Rc;
 
 const ROM_BANK_SIZE: usize = 0x4000;
 const RAM_BANK_SIZE: usize = 0x2000;
 
-pub trait Mapper {
+/// What cartridge hardware is connected to on the host side.
+#[derive(Clone)]
+pub struct CartridgeContext {
+    pub clock: Rc<dyn Clock>,
+    pub infrared: Rc<dyn Infrared>,
+    /// Read by the accelerometer, updated by the frontend every frame.
+    pub tilt: Rc<Cell<Tilt>>,
+    pub camera: Rc<dyn ImageSource>,
+}
+
+impl Default for CartridgeContext {
+    fn default() -> Self {
+        CartridgeContext {
+            clock: Rc::new(HostClock),
+            infrared: Rc::new(NoInfrared),
+            tilt: Rc::new(Cell::new(Tilt::default())),
+            camera: Rc::new(TestPattern),
+        }
+    }
+}
+
+/// Checks that the ROM and RAM are powers of two that `mapper` can address.
+fn check_sizes(
+    mapper: &'static str,
+    rom: &[u8],
+    max_rom_size: usize,
+    ram: &[u8],
+    max_ram_size: usize,
+) -> Result<(), CartridgeError> {
+    if rom.len() > max_rom_size || !rom.len().is_power_of_two() {
+        return Err(CartridgeError::RomSize {
+            mapper,
+            size: rom.len(),
+        });
+    }
+    if ram.len() > max_ram_size || !(ram.is_empty() || ram.len().is_power_of_two()) {
+        return Err(CartridgeError::RamSize {
+            mapper,
+            size: ram.len(),
+        });
+    }
+    Ok(())
+}
+
+/// Mappers are part of the save state: their bank registers, SRAM and any extra hardware.
+pub trait Mapper: SaveState {
     /// Handles a read from the 0x0000-0x7FFF ROM/MBC area.
     fn read_rom(&self, address: u16) -> u8;
     /// Handles a write to the 0x0000-0x7FFF ROM/MBC area.
@@ -21,6 +84,13 @@ pub trait Mapper {
 
     /// Saves battery-backed SRAM, if any.
     fn save_battery(&mut self) -> Vec<u8>;
+
+    /// Called when the host side of the cartridge hardware is replaced.
+    fn set_context(&mut self, _context: &CartridgeContext) {}
+
+    /// Called with the cycles emulated after every instruction, for hardware that works on its
+    /// own.
+    fn tick(&mut self, _cycles: u32) {}
 }
 
 /// Mapper that simulates having no cartridge inserted.
@@ -41,3 +111,10 @@ impl Mapper for NullMapper {
         Vec::new()
     }
 }
+
+impl SaveState for NullMapper {
+    fn save_state(&self, _writer: &mut StateWriter) {}
+    fn load_state(&mut self, _reader: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+}
@@ -0,0 +1,301 @@
+use crate::mem::cartridge::CartridgeError;
+use crate::mem::mapper::camera::{ImageSource, IMAGE_HEIGHT, IMAGE_WIDTH};
+use crate::mem::mapper::{check_sizes, CartridgeContext, Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+use std::rc::Rc;
+
+/// Size of SRAM, regardless of the header.
+pub const RAM_SIZE: usize = 128 << 10;
+
+/// Setting bit 4 of the RAM bank maps the camera registers instead of RAM.
+const CAMERA_SELECT: u8 = 0x10;
+/// The registers repeat every 0x80 bytes of the RAM area.
+const REGISTER_COUNT: usize = 0x36;
+
+/// Bit 0 starts a capture and stays set until it's done.
+const REG_CONTROL: usize = 0x0;
+/// Bit 7 is N, bits 5-6 VH, bits 0-4 the gain.
+const REG_EDGE_GAIN: usize = 0x1;
+const REG_EXPOSURE_HIGH: usize = 0x2;
+const REG_EXPOSURE_LOW: usize = 0x3;
+/// Bits 4-6 select the edge enhancement ratio, bit 3 inverts the image.
+const REG_EDGE_RATIO_INVERT: usize = 0x4;
+/// 4x4 thresholds for the three darker shades, 3 bytes per pixel of the matrix.
+const REG_MATRIX: usize = 0x6;
+
+const CONTROL_CAPTURE: u8 = 0x01;
+const EDGE_N: u8 = 0x80;
+const INVERT: u8 = 0x08;
+
+/// The captured image is stored as 16x14 tiles in RAM bank 0.
+const IMAGE_ADDR: usize = 0x100;
+
+/// Capture durations are in cycles of the 1 MiHz sensor clock.
+const CAPTURE_BASE_CYCLES: u32 = 32446;
+/// Added when N is cleared.
+const CAPTURE_NO_N_CYCLES: u32 = 512;
+const CAPTURE_CYCLES_PER_EXPOSURE_STEP: u32 = 16;
+const CPU_CYCLES_PER_SENSOR_CYCLE: u32 = 4;
+
+/// The exposure at which the image comes through unchanged.
+const EXPOSURE_UNITY: f32 = 0x1000 as f32;
+/// Each step of the gain adds half a decibel.
+const GAIN_DB_PER_STEP: f32 = 0.5;
+const EDGE_RATIOS: [f32; 8] = [0.5, 0.75, 1.0, 1.25, 2.0, 3.0, 4.0, 5.0];
+
+pub struct PocketCameraMapper {
+    /// Mapped to the ROM area. Up to 1 MiB in size.
+    rom: Box<[u8]>,
+    /// Mapped to the RAM area in 16 banks.
+    ram: Box<[u8]>,
+
+    current_rom_bank: u8,
+    /// Bank and `CAMERA_SELECT`.
+    current_ram_bank: u8,
+    ram_enabled: bool,
+
+    camera: Rc<dyn ImageSource>,
+    registers: [u8; REGISTER_COUNT],
+    /// Cycles until the capture in progress is done, 0 when idle.
+    capture_cycles_left: u32,
+    /// Captures finished so far. Passed to the camera, so that the test pattern moves the same way
+    /// after loading a state.
+    captures: u32,
+
+    has_battery: bool,
+    /// True is SRAM has been written to since the last time it was saved.
+    ram_modified: bool,
+}
+
+impl PocketCameraMapper {
+    pub fn new(
+        rom: Box<[u8]>,
+        ram: Box<[u8]>,
+        has_battery: bool,
+        context: &CartridgeContext,
+    ) -> Result<PocketCameraMapper, CartridgeError> {
+        check_sizes("Pocket Camera", &rom, 1 << 20, &ram, RAM_SIZE)?;
+
+        Ok(PocketCameraMapper {
+            rom,
+            ram,
+            current_rom_bank: 1,
+            current_ram_bank: 0,
+            ram_enabled: false,
+            camera: context.camera.clone(),
+            registers: [0; REGISTER_COUNT],
+            capture_cycles_left: 0,
+            captures: 0,
+            has_battery,
+            ram_modified: false,
+        })
+    }
+
+    fn rom_mask(&self) -> usize {
+        self.rom.len() - 1
+    }
+
+    fn camera_selected(&self) -> bool {
+        self.current_ram_bank & CAMERA_SELECT != 0
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let bank = (self.current_ram_bank & 0xF) as usize;
+        let offset = bank * RAM_BANK_SIZE + (address & 0x1FFF) as usize;
+        offset & (self.ram.len() - 1)
+    }
+
+    fn exposure(&self) -> u32 {
+        (self.registers[REG_EXPOSURE_HIGH] as u32) << 8 | self.registers[REG_EXPOSURE_LOW] as u32
+    }
+
+    fn start_capture(&mut self) {
+        let n_cycles = if self.registers[REG_EDGE_GAIN] & EDGE_N != 0 {
+            0
+        } else {
+            CAPTURE_NO_N_CYCLES
+        };
+        let sensor_cycles =
+            CAPTURE_BASE_CYCLES + n_cycles + CAPTURE_CYCLES_PER_EXPOSURE_STEP * self.exposure();
+        self.capture_cycles_left = sensor_cycles * CPU_CYCLES_PER_SENSOR_CYCLE;
+    }
+
+    /// Brightness of every pixel the sensor sends out, after exposure, gain and edge enhancement.
+    fn sensor_output(&self) -> Vec<f32> {
+        let mut raw = vec![0; IMAGE_WIDTH * IMAGE_HEIGHT];
+        self.camera.capture(&mut raw, self.captures);
+
+        let gain_db = (self.registers[REG_EDGE_GAIN] & 0x1F) as f32 * GAIN_DB_PER_STEP;
+        let scale = self.exposure() as f32 / EXPOSURE_UNITY * 10f32.powf(gain_db / 20.0);
+        let exposed: Vec<f32> = raw.iter().map(|&pixel| pixel as f32 * scale).collect();
+
+        let pixel = |x: isize, y: isize| {
+            let x = x.clamp(0, IMAGE_WIDTH as isize - 1) as usize;
+            let y = y.clamp(0, IMAGE_HEIGHT as isize - 1) as usize;
+            exposed[y * IMAGE_WIDTH + x]
+        };
+        let vh = (self.registers[REG_EDGE_GAIN] >> 5) & 0b11;
+        let ratio = EDGE_RATIOS[(self.registers[REG_EDGE_RATIO_INVERT] >> 4) as usize & 0x7];
+        let invert = self.registers[REG_EDGE_RATIO_INVERT] & INVERT != 0;
+        let mut output = Vec::with_capacity(exposed.len());
+        for y in 0..IMAGE_HEIGHT as isize {
+            for x in 0..IMAGE_WIDTH as isize {
+                let center = pixel(x, y);
+                let horizontal = 2.0 * center - pixel(x - 1, y) - pixel(x + 1, y);
+                let vertical = 2.0 * center - pixel(x, y - 1) - pixel(x, y + 1);
+                let edge = match vh {
+                    0 => 0.0,
+                    1 => horizontal,
+                    2 => vertical,
+                    _ => horizontal + vertical,
+                };
+                let value = (center + edge * ratio).clamp(0.0, 255.0);
+                output.push(if invert { 255.0 - value } else { value });
+            }
+        }
+        output
+    }
+
+    /// Dithers the sensor output to the 4 shades with the threshold matrix, and stores it as
+    /// tiles.
+    fn finish_capture(&mut self) {
+        let output = self.sensor_output();
+        self.captures = self.captures.wrapping_add(1);
+        for y in 0..IMAGE_HEIGHT {
+            for x in 0..IMAGE_WIDTH {
+                let thresholds = REG_MATRIX + ((y % 4) * 4 + x % 4) * 3;
+                let value = output[y * IMAGE_WIDTH + x];
+                let shade = self.registers[thresholds..thresholds + 3]
+                    .iter()
+                    .take_while(|&&threshold| value >= threshold as f32)
+                    .count();
+                let color = 3 - shade as u8;
+
+                let tile = (y / 8) * (IMAGE_WIDTH / 8) + x / 8;
+                let row = IMAGE_ADDR + tile * 16 + (y % 8) * 2;
+                let bit = 7 - (x % 8);
+                self.ram[row] = (self.ram[row] & !(1 << bit)) | (color & 1) << bit;
+                self.ram[row + 1] = (self.ram[row + 1] & !(1 << bit)) | (color >> 1) << bit;
+            }
+        }
+        self.ram_modified = true;
+    }
+}
+
+impl Mapper for PocketCameraMapper {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address & 0x4000 == 0 {
+            0
+        } else {
+            self.current_rom_bank
+        };
+        let offset = bank as usize * ROM_BANK_SIZE + (address & 0x3FFF) as usize;
+
+        self.rom[offset & self.rom_mask()]
+    }
+
+    fn write_rom(&mut self, address: u16, data: u8) {
+        match (address >> 13) & 0b11 {
+            0 => {
+                // RAM write enable
+                self.ram_enabled = data & 0xF == 0xA;
+            }
+            1 => {
+                // ROM bank
+                self.current_rom_bank = data & 0x3F;
+            }
+            2 => {
+                // RAM bank or camera registers
+                self.current_ram_bank = data & 0x1F;
+            }
+            3 => {
+                // unused
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if self.camera_selected() {
+            match address as usize & 0x7F {
+                REG_CONTROL => {
+                    let busy = self.capture_cycles_left > 0;
+                    (self.registers[REG_CONTROL] & !CONTROL_CAPTURE) | busy as u8
+                }
+                // The other registers are write-only.
+                _ => 0x00,
+            }
+        } else if self.capture_cycles_left > 0 {
+            // The sensor has the RAM to itself while capturing.
+            0x00
+        } else {
+            // Reading doesn't need the RAM to be enabled.
+            self.ram[self.ram_offset(address)]
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) {
+        if self.camera_selected() {
+            let register = address as usize & 0x7F;
+            if register == REG_CONTROL {
+                self.registers[REG_CONTROL] = data & 0x7;
+                if data & CONTROL_CAPTURE != 0 && self.capture_cycles_left == 0 {
+                    self.start_capture();
+                }
+            } else if register < REGISTER_COUNT {
+                self.registers[register] = data;
+            }
+        } else if self.ram_enabled && self.capture_cycles_left == 0 {
+            let offset = self.ram_offset(address);
+            self.ram[offset] = data;
+            self.ram_modified = true;
+        }
+    }
+
+    fn save_battery(&mut self) -> Vec<u8> {
+        if self.has_battery && self.ram_modified {
+            self.ram_modified = false;
+            Vec::from(&*self.ram)
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn set_context(&mut self, context: &CartridgeContext) {
+        self.camera = context.camera.clone();
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if self.capture_cycles_left > 0 {
+            self.capture_cycles_left = self.capture_cycles_left.saturating_sub(cycles);
+            if self.capture_cycles_left == 0 {
+                self.finish_capture();
+            }
+        }
+    }
+}
+
+impl SaveState for PocketCameraMapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.current_rom_bank);
+        writer.write_u8(self.current_ram_bank);
+        writer.write_bool(self.ram_enabled);
+        writer.write_bytes(&self.registers);
+        writer.write_u32(self.capture_cycles_left);
+        writer.write_u32(self.captures);
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.current_rom_bank = reader.read_u8()?;
+        self.current_ram_bank = reader.read_u8()?;
+        self.ram_enabled = reader.read_bool()?;
+        reader.read_bytes_into(&mut self.registers)?;
+        self.capture_cycles_left = reader.read_u32()?;
+        self.captures = reader.read_u32()?;
+        reader.read_bytes_into(&mut self.ram)?;
+        self.ram_modified = true;
+        Ok(())
+    }
+}
@@ -1,4 +1,6 @@
-use crate::mem::mapper::Mapper;
+use crate::mem::cartridge::CartridgeError;
+use crate::mem::mapper::{check_sizes, Mapper};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 pub struct RomMapper {
     /// Mapped to the ROM area. Up to 32 KiB in size.
@@ -12,18 +14,19 @@ pub struct RomMapper {
 }
 
 impl RomMapper {
-    pub fn new(rom: Box<[u8]>, ram: Box<[u8]>, has_battery: bool) -> RomMapper {
-        assert!(rom.len() <= 32 << 10);
-        assert!(rom.len().is_power_of_two());
-        assert!(ram.len() <= 8 << 10);
-        assert!(ram.len() == 0 || ram.len().is_power_of_two());
+    pub fn new(
+        rom: Box<[u8]>,
+        ram: Box<[u8]>,
+        has_battery: bool,
+    ) -> Result<RomMapper, CartridgeError> {
+        check_sizes("ROM", &rom, 32 << 10, &ram, 8 << 10)?;
 
-        RomMapper {
+        Ok(RomMapper {
             rom: rom,
             ram: ram,
             has_battery: has_battery,
             ram_modified: false,
-        }
+        })
     }
 
     fn rom_mask(&self) -> usize {
@@ -72,3 +75,15 @@ impl Mapper for RomMapper {
         }
     }
 }
+
+impl SaveState for RomMapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.ram)?;
+        self.ram_modified = true;
+        Ok(())
+    }
+}
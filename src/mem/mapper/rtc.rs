@@ -1,8 +1,11 @@
 use std::cmp;
-use time;
+use std::rc::Rc;
+use crate::clock::Clock;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
-#[derive(Default)]
 pub struct Rtc {
+    clock: Rc<dyn Clock>,
+
     seconds: u8,
     minutes: u8,
     hours: u8,
@@ -13,12 +16,23 @@ pub struct Rtc {
 }
 
 impl Rtc {
-    pub fn new() -> Rtc {
-        let mut rtc: Rtc = Default::default();
+    pub fn new(clock: Rc<dyn Clock>) -> Rtc {
+        let mut rtc = Rtc {
+            clock,
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            day_counter_lsb: 0,
+            misc_bits: 0,
+        };
         rtc.latch();
         rtc
     }
 
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
+    }
+
     pub fn read(&self, address: u8) -> u8 {
         match address {
             0x8 => self.seconds,
@@ -48,10 +62,10 @@ impl Rtc {
             return;
         }
 
-        // Since we don't actually count up the time, and just fabricate it from the host time, the
+        // Since we don't actually count up the time, and just fabricate it from the clock, the
         // day counter will never be above 365, and the carry bit also won't be set on overflow.
         // TODO: Do games rely on being able to adjust the time?
-        let now = time::now();
+        let now = self.clock.now();
         self.seconds = cmp::min(now.tm_sec, 59) as u8;
         self.minutes = cmp::min(now.tm_min, 59) as u8;
         self.hours = cmp::min(now.tm_hour, 23) as u8;
@@ -59,3 +73,22 @@ impl Rtc {
         self.misc_bits = (now.tm_yday & 0x100 >> 8) as u8;
     }
 }
+
+impl SaveState for Rtc {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.seconds);
+        writer.write_u8(self.minutes);
+        writer.write_u8(self.hours);
+        writer.write_u8(self.day_counter_lsb);
+        writer.write_u8(self.misc_bits);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.seconds = reader.read_u8()?;
+        self.minutes = reader.read_u8()?;
+        self.hours = reader.read_u8()?;
+        self.day_counter_lsb = reader.read_u8()?;
+        self.misc_bits = reader.read_u8()?;
+        Ok(())
+    }
+}
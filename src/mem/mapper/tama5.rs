@@ -0,0 +1,369 @@
+use crate::clock::Clock;
+use crate::mem::cartridge::CartridgeError;
+use crate::mem::mapper::{check_sizes, CartridgeContext, Mapper, ROM_BANK_SIZE};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+use std::rc::Rc;
+use time::{self, Timespec, Tm};
+
+/// Size of the EEPROM, which takes the place of SRAM in the battery file.
+pub const EEPROM_SIZE: usize = 32;
+/// Bytes saved after the EEPROM in the battery file to keep the clock.
+pub const RTC_SAVE_SIZE: usize = 9;
+
+/// The registers are written a nibble at a time through 0xA000, after selecting one through
+/// 0xA001.
+const REG_BANK_LOW: u8 = 0x0;
+const REG_BANK_HIGH: u8 = 0x1;
+const REG_WRITE_LOW: u8 = 0x4;
+const REG_WRITE_HIGH: u8 = 0x5;
+/// Bit 0 is bit 4 of the EEPROM address, bits 1-3 the command.
+const REG_COMMAND: u8 = 0x6;
+/// Writing it runs the command.
+const REG_ADDRESS_LOW: u8 = 0x7;
+const REGISTER_COUNT: usize = 0x8;
+/// Read-only.
+const REG_ACTIVE: u8 = 0xA;
+const REG_READ_LOW: u8 = 0xC;
+const REG_READ_HIGH: u8 = 0xD;
+
+const COMMAND_EEPROM_WRITE: u8 = 0x0;
+const COMMAND_EEPROM_READ: u8 = 0x1;
+/// The address selects one of the `RTC_` operations.
+const COMMAND_RTC: u8 = 0x2;
+/// Accesses a register of the clock chip: the address selects the page, even to write and odd
+/// to read, and the low and high write nibbles the register and the value.
+const COMMAND_RTC_REGISTER: u8 = 0x4;
+
+const RTC_STOP: u8 = 0x00;
+const RTC_START: u8 = 0x01;
+const RTC_WRITE_MINUTES: u8 = 0x04;
+const RTC_WRITE_HOURS: u8 = 0x05;
+const RTC_READ_MINUTES: u8 = 0x06;
+const RTC_READ_HOURS: u8 = 0x07;
+
+/// The clock chip has 13 registers per page. Page 0 is the time as BCD digits: seconds, minutes,
+/// hours, weekday, day, month and year. The other pages hold the alarm and free memory, which
+/// are kept but have no effect.
+const RTC_PAGE_SIZE: usize = 13;
+const RTC_PAGES: usize = 4;
+const RTC_SECOND_1: usize = 0;
+const RTC_MINUTE_1: usize = 2;
+const RTC_HOUR_1: usize = 4;
+const RTC_WEEKDAY: usize = 6;
+const RTC_DAY_1: usize = 7;
+const RTC_MONTH_1: usize = 9;
+const RTC_YEAR_1: usize = 11;
+
+pub struct Tama5Mapper {
+    /// Mapped to the ROM area. Up to 512 KiB in size.
+    rom: Box<[u8]>,
+    /// Read and written a byte at a time through the registers.
+    eeprom: Box<[u8]>,
+
+    /// Selected through 0xA001.
+    selected: u8,
+    registers: [u8; REGISTER_COUNT],
+    /// Result of the last read command.
+    read_value: u8,
+
+    clock: Rc<dyn Clock>,
+    /// Seconds from the host clock to the time of the cartridge.
+    rtc_offset: i64,
+    /// The time of the cartridge while it's stopped.
+    rtc_stopped_at: Option<i64>,
+    /// Pages 1 to 3 of the clock chip.
+    rtc_pages: [[u8; RTC_PAGE_SIZE]; RTC_PAGES - 1],
+
+    has_battery: bool,
+    /// True is the EEPROM or the clock have changed since the last time they were saved.
+    ram_modified: bool,
+}
+
+impl Tama5Mapper {
+    /// `ram` is the contents of the EEPROM, `EEPROM_SIZE` bytes. `rtc_save` is what follows it
+    /// in the battery file.
+    pub fn new(
+        rom: Box<[u8]>,
+        ram: Box<[u8]>,
+        rtc_save: &[u8],
+        has_battery: bool,
+        context: &CartridgeContext,
+    ) -> Result<Tama5Mapper, CartridgeError> {
+        check_sizes("TAMA5", &rom, 512 << 10, &ram, EEPROM_SIZE)?;
+
+        let (rtc_offset, rtc_stopped_at) = if rtc_save.len() >= RTC_SAVE_SIZE {
+            let mut bytes = [0; 8];
+            bytes.copy_from_slice(&rtc_save[..8]);
+            let seconds = i64::from_le_bytes(bytes);
+            match rtc_save[8] {
+                0 => (0, Some(seconds)),
+                _ => (seconds, None),
+            }
+        } else {
+            // Starts from the host time, in its timezone.
+            (context.clock.now().tm_utcoff as i64, None)
+        };
+        Ok(Tama5Mapper {
+            rom,
+            eeprom: ram,
+            selected: 0,
+            registers: [0; REGISTER_COUNT],
+            read_value: 0,
+            clock: context.clock.clone(),
+            rtc_offset,
+            rtc_stopped_at,
+            rtc_pages: [[0; RTC_PAGE_SIZE]; RTC_PAGES - 1],
+            has_battery,
+            ram_modified: false,
+        })
+    }
+
+    fn rom_mask(&self) -> usize {
+        self.rom.len() - 1
+    }
+
+    fn rom_bank(&self) -> usize {
+        (self.registers[REG_BANK_HIGH as usize] as usize) << 4
+            | self.registers[REG_BANK_LOW as usize] as usize
+    }
+
+    /// The byte made of the two write nibbles.
+    fn write_value(&self) -> u8 {
+        self.registers[REG_WRITE_HIGH as usize] << 4 | self.registers[REG_WRITE_LOW as usize]
+    }
+
+    fn address(&self) -> u8 {
+        (self.registers[REG_COMMAND as usize] & 1) << 4 | self.registers[REG_ADDRESS_LOW as usize]
+    }
+
+    /// Seconds since the Unix epoch on the cartridge clock.
+    fn rtc_seconds(&self) -> i64 {
+        match self.rtc_stopped_at {
+            Some(seconds) => seconds,
+            None => self.clock.now().to_timespec().sec + self.rtc_offset,
+        }
+    }
+
+    fn set_rtc_seconds(&mut self, seconds: i64) {
+        match self.rtc_stopped_at {
+            Some(_) => self.rtc_stopped_at = Some(seconds),
+            None => self.rtc_offset = seconds - self.clock.now().to_timespec().sec,
+        }
+        self.ram_modified = true;
+    }
+
+    /// Page 0 of the clock chip, from the cartridge time.
+    fn time_page(&self) -> [u8; RTC_PAGE_SIZE] {
+        let tm = time::at_utc(Timespec::new(self.rtc_seconds(), 0));
+        let mut page = [0; RTC_PAGE_SIZE];
+        let fields = [
+            (RTC_SECOND_1, tm.tm_sec),
+            (RTC_MINUTE_1, tm.tm_min),
+            (RTC_HOUR_1, tm.tm_hour),
+            (RTC_DAY_1, tm.tm_mday),
+            (RTC_MONTH_1, tm.tm_mon + 1),
+            (RTC_YEAR_1, tm.tm_year % 100),
+        ];
+        for &(register, value) in fields.iter() {
+            page[register] = (value % 10) as u8;
+            page[register + 1] = (value / 10) as u8;
+        }
+        page[RTC_WEEKDAY] = tm.tm_wday as u8;
+        page
+    }
+
+    /// Sets the cartridge time from the digits of `page`. The weekday follows from the date.
+    fn set_time_page(&mut self, page: &[u8; RTC_PAGE_SIZE]) {
+        let value = |register: usize| (page[register + 1] * 10 + page[register]) as i32;
+        let tm = Tm {
+            tm_sec: value(RTC_SECOND_1),
+            tm_min: value(RTC_MINUTE_1),
+            tm_hour: value(RTC_HOUR_1),
+            tm_mday: value(RTC_DAY_1),
+            tm_mon: value(RTC_MONTH_1) - 1,
+            tm_year: 100 + value(RTC_YEAR_1),
+            tm_wday: 0,
+            tm_yday: 0,
+            tm_isdst: 0,
+            tm_utcoff: 0,
+            tm_nsec: 0,
+        };
+        self.set_rtc_seconds(tm.to_timespec().sec);
+    }
+
+    fn set_rtc_running(&mut self, running: bool) {
+        let seconds = self.rtc_seconds();
+        if running {
+            self.rtc_stopped_at = None;
+            self.rtc_offset = seconds - self.clock.now().to_timespec().sec;
+        } else {
+            self.rtc_stopped_at = Some(seconds);
+        }
+        self.ram_modified = true;
+    }
+
+    /// Replaces two BCD digits of the time.
+    fn write_time_digits(&mut self, register: usize, value: u8) {
+        let mut page = self.time_page();
+        page[register] = value & 0xF;
+        page[register + 1] = value >> 4;
+        self.set_time_page(&page);
+    }
+
+    fn time_digits(&self, register: usize) -> u8 {
+        let page = self.time_page();
+        page[register + 1] << 4 | page[register]
+    }
+
+    fn run_command(&mut self) {
+        let address = self.address();
+        let value = self.write_value();
+        match self.registers[REG_COMMAND as usize] >> 1 {
+            COMMAND_EEPROM_WRITE => {
+                self.eeprom[address as usize % EEPROM_SIZE] = value;
+                self.ram_modified = true;
+            }
+            COMMAND_EEPROM_READ => self.read_value = self.eeprom[address as usize % EEPROM_SIZE],
+            COMMAND_RTC => match address {
+                RTC_STOP => self.set_rtc_running(false),
+                RTC_START => {
+                    // Starting the clock resets the seconds.
+                    let mut page = self.time_page();
+                    page[RTC_SECOND_1] = 0;
+                    page[RTC_SECOND_1 + 1] = 0;
+                    self.set_time_page(&page);
+                    self.set_rtc_running(true);
+                }
+                RTC_WRITE_MINUTES => self.write_time_digits(RTC_MINUTE_1, value),
+                RTC_WRITE_HOURS => self.write_time_digits(RTC_HOUR_1, value),
+                RTC_READ_MINUTES => self.read_value = self.time_digits(RTC_MINUTE_1),
+                RTC_READ_HOURS => self.read_value = self.time_digits(RTC_HOUR_1),
+                // The alarm isn't emulated.
+                _ => (),
+            },
+            COMMAND_RTC_REGISTER => {
+                let register = self.registers[REG_WRITE_LOW as usize] as usize;
+                let page = (address as usize & 0xF) >> 1;
+                let read = address & 1 != 0;
+                if register >= RTC_PAGE_SIZE || page >= RTC_PAGES {
+                    return;
+                }
+                let nibble = self.registers[REG_WRITE_HIGH as usize];
+                match (page, read) {
+                    (0, false) => {
+                        let mut time = self.time_page();
+                        time[register] = nibble;
+                        self.set_time_page(&time);
+                    }
+                    (0, true) => self.read_value = self.time_page()[register],
+                    (_, false) => self.rtc_pages[page - 1][register] = nibble,
+                    (_, true) => self.read_value = self.rtc_pages[page - 1][register],
+                }
+            }
+            _ => (),
+        }
+    }
+}
+
+impl Mapper for Tama5Mapper {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = if address & 0x4000 == 0 {
+            0
+        } else {
+            self.rom_bank()
+        };
+        let offset = bank * ROM_BANK_SIZE + (address & 0x3FFF) as usize;
+
+        self.rom[offset & self.rom_mask()]
+    }
+
+    fn write_rom(&mut self, _address: u16, _data: u8) {
+        // Everything goes through the registers in the RAM area.
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if address & 0x1FFF != 0 {
+            return 0xFF;
+        }
+        match self.selected {
+            // Always ready for the next command.
+            REG_ACTIVE => 0xF1,
+            REG_READ_LOW => 0xF0 | (self.read_value & 0xF),
+            REG_READ_HIGH => 0xF0 | (self.read_value >> 4),
+            _ => 0xFF,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, data: u8) {
+        match address & 0x1FFF {
+            0 => {
+                let register = self.selected;
+                if register as usize >= REGISTER_COUNT {
+                    return;
+                }
+                self.registers[register as usize] = data & 0xF;
+                if register == REG_ADDRESS_LOW {
+                    self.run_command();
+                }
+            }
+            1 => self.selected = data & 0xF,
+            _ => (),
+        }
+    }
+
+    fn save_battery(&mut self) -> Vec<u8> {
+        if self.has_battery && self.ram_modified {
+            self.ram_modified = false;
+            let mut data = Vec::from(&*self.eeprom);
+            match self.rtc_stopped_at {
+                Some(seconds) => {
+                    data.extend_from_slice(&seconds.to_le_bytes());
+                    data.push(0);
+                }
+                None => {
+                    data.extend_from_slice(&self.rtc_offset.to_le_bytes());
+                    data.push(1);
+                }
+            }
+            data
+        } else {
+            Vec::new()
+        }
+    }
+
+    fn set_context(&mut self, context: &CartridgeContext) {
+        self.clock = context.clock.clone();
+    }
+}
+
+impl SaveState for Tama5Mapper {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.selected);
+        writer.write_bytes(&self.registers);
+        writer.write_u8(self.read_value);
+        writer.write_bytes(&self.eeprom);
+        writer.write_u64(self.rtc_offset as u64);
+        writer.write_bool(self.rtc_stopped_at.is_some());
+        writer.write_u64(self.rtc_stopped_at.unwrap_or(0) as u64);
+        for page in self.rtc_pages.iter() {
+            writer.write_bytes(page);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.selected = reader.read_u8()?;
+        reader.read_bytes_into(&mut self.registers)?;
+        self.read_value = reader.read_u8()?;
+        reader.read_bytes_into(&mut self.eeprom)?;
+        self.rtc_offset = reader.read_u64()? as i64;
+        let stopped = reader.read_bool()?;
+        let stopped_at = reader.read_u64()? as i64;
+        self.rtc_stopped_at = if stopped { Some(stopped_at) } else { None };
+        for page in self.rtc_pages.iter_mut() {
+            reader.read_bytes_into(page)?;
+        }
+        self.ram_modified = true;
+        Ok(())
+    }
+}
@@ -4,9 +4,14 @@ pub mod cartridge;
 use std::cell::RefCell;
 use std::rc::Rc;
 use crate::mem::mapper::Mapper;
+pub use crate::mem::mapper::camera::{self, ImageSource};
+pub use crate::mem::mapper::CartridgeContext;
+pub use crate::mem::mapper::infrared::{self, Infrared};
 use super::cpu::ioregister::{BGPD_REGISTER_ADDR, BGPI_REGISTER_ADDR, OBPD_REGISTER_ADDR,
                              OBPI_REGISTER_ADDR, SVBK_REGISTER_ADDR, VBK_REGISTER_ADDR};
+use super::gebemula::GBMode;
 use super::peripherals::sound::AudioController;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 const VRAM_BANK_SIZE: usize = 0x2000;
 const VRAM_BANKS: usize = 2;
@@ -29,6 +34,9 @@ pub struct Memory {
     hram: [u8; HRAM_SIZE],
     interrupts_enable: u8,
     cartridge: Box<dyn Mapper>,
+    cartridge_context: CartridgeContext,
+    /// Hardware model to emulate regardless of the cartridge header.
+    forced_mode: Option<GBMode>,
     bootstrap_enabled: bool,
     can_access_vram: bool,
     can_access_oam: bool,
@@ -51,6 +59,8 @@ impl Memory {
             hram: [0; HRAM_SIZE],
             interrupts_enable: 0x0,
             cartridge: Box::new(mapper::NullMapper),
+            cartridge_context: CartridgeContext::default(),
+            forced_mode: None,
             bootstrap_enabled: true,
             can_access_vram: true,
             can_access_oam: true,
@@ -99,8 +109,7 @@ impl Memory {
         self.read_byte(SVBK_REGISTER_ADDR) & 0b111
     }
     fn is_color(&self) -> bool {
-        let tmp = self.cartridge.read_rom(0x143);
-        tmp == 0x80 || tmp == 0xC0
+        GBMode::get(self) == GBMode::Color
     }
 
     pub fn write_byte(&mut self, address: u16, value: u8) {
@@ -250,40 +259,6 @@ impl Memory {
 
     pub fn disable_bootstrap(&mut self) {
         self.bootstrap_enabled = false;
-        /*
-        self.write_byte(0xFF05, 0x00);
-        self.write_byte(0xFF06, 0x00);
-        self.write_byte(0xFF07, 0x00);
-        self.write_byte(0xFF10, 0x80);
-        self.write_byte(0xFF11, 0xBF);
-        self.write_byte(0xFF12, 0xF3);
-        self.write_byte(0xFF14, 0xBF);
-        self.write_byte(0xFF16, 0x3F);
-        self.write_byte(0xFF17, 0x00);
-        self.write_byte(0xFF19, 0xBF);
-        self.write_byte(0xFF1A, 0x7F);
-        self.write_byte(0xFF1B, 0xFF);
-        self.write_byte(0xFF1C, 0x9F);
-        self.write_byte(0xFF1E, 0xBF);
-        self.write_byte(0xFF20, 0xFF);
-        self.write_byte(0xFF21, 0x00);
-        self.write_byte(0xFF22, 0x00);
-        self.write_byte(0xFF23, 0xBF);
-        self.write_byte(0xFF24, 0x77);
-        self.write_byte(0xFF25, 0xF3);
-        self.write_byte(0xFF26, 0xF1);
-        self.write_byte(0xFF40, 0x91);
-        self.write_byte(0xFF42, 0x00);
-        self.write_byte(0xFF43, 0x00);
-        self.write_byte(0xFF45, 0x00);
-        self.write_byte(0xFF47, 0xFC);
-        self.write_byte(0xFF48, 0xFF);
-        self.write_byte(0xFF49, 0xFF);
-        self.write_byte(0xFF4A, 0x00);
-        self.write_byte(0xFF4B, 0x00);
-        self.write_byte(0xFF4F, 0x00);
-        self.write_byte(0xFFFF, 0x00);
-        */
     }
 
     pub fn load_bootstrap_rom(&mut self, rom: &[u8]) {
@@ -296,15 +271,75 @@ impl Memory {
         self.cartridge.read_rom(addr)
     }
 
-    pub fn load_cartridge(&mut self, rom: &[u8], battery: &[u8]) {
-        self.cartridge = cartridge::load_cartridge(rom, battery);
+    /// Keeps the current cartridge if `rom` can't be loaded.
+    pub fn load_cartridge(
+        &mut self,
+        rom: &[u8],
+        battery: &[u8],
+    ) -> Result<(), cartridge::CartridgeError> {
+        self.cartridge = cartridge::load_cartridge(rom, battery, &self.cartridge_context)?;
 
         for i in 0x100..0x200 {
             self.bootstrap_rom[i] = self.cartridge.read_rom(i as u16);
         }
+        Ok(())
+    }
+
+    pub fn cartridge_context(&self) -> &CartridgeContext {
+        &self.cartridge_context
+    }
+
+    /// Lets the cartridge hardware run for `cycles`.
+    pub fn update_cartridge(&mut self, cycles: u32) {
+        self.cartridge.tick(cycles);
+    }
+
+    pub fn set_cartridge_context(&mut self, context: CartridgeContext) {
+        self.cartridge.set_context(&context);
+        self.cartridge_context = context;
+    }
+
+    pub fn forced_mode(&self) -> Option<GBMode> {
+        self.forced_mode
+    }
+
+    pub fn set_forced_mode(&mut self, mode: Option<GBMode>) {
+        self.forced_mode = mode;
     }
 
     pub fn save_battery(&mut self) -> Vec<u8> {
         self.cartridge.save_battery()
     }
 }
+
+impl SaveState for Memory {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.vram);
+        writer.write_bytes(&self.wram);
+        writer.write_bytes(&self.oam);
+        writer.write_bytes(&self.io_registers);
+        writer.write_bytes(&self.hram);
+        writer.write_u8(self.interrupts_enable);
+        writer.write_bool(self.bootstrap_enabled);
+        writer.write_bool(self.can_access_vram);
+        writer.write_bool(self.can_access_oam);
+        writer.write_bytes(&self.bg_palette_data);
+        writer.write_bytes(&self.sprite_palette_data);
+        self.cartridge.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.vram)?;
+        reader.read_bytes_into(&mut self.wram)?;
+        reader.read_bytes_into(&mut self.oam)?;
+        reader.read_bytes_into(&mut self.io_registers)?;
+        reader.read_bytes_into(&mut self.hram)?;
+        self.interrupts_enable = reader.read_u8()?;
+        self.bootstrap_enabled = reader.read_bool()?;
+        self.can_access_vram = reader.read_bool()?;
+        self.can_access_oam = reader.read_bool()?;
+        reader.read_bytes_into(&mut self.bg_palette_data)?;
+        reader.read_bytes_into(&mut self.sprite_palette_data)?;
+        self.cartridge.load_state(reader)
+    }
+}
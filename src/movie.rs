@@ -0,0 +1,180 @@
+use crate::peripherals::joypad::JoypadKey;
+use crate::peripherals::tilt::Tilt;
+use crate::state::{StateError, StateReader, StateWriter};
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// Identifies a gebemula input movie file.
+pub const MOVIE_MAGIC: &[u8; 4] = b"GBMV";
+/// Bumped every time the layout of the movie file changes.
+pub const MOVIE_VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum MovieError {
+    Io(io::Error),
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// The movie was recorded with a different ROM.
+    RomMismatch,
+    /// The movie has no frames to play.
+    Empty,
+    /// The Pocket Camera is fed from a real device, which a movie can't reproduce.
+    LiveCamera,
+    /// The movie or its starting state is damaged.
+    State(StateError),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MovieError::Io(ref e) => write!(f, "{}", e),
+            MovieError::BadMagic => write!(f, "not a gebemula movie"),
+            MovieError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported movie version {} (expected {})",
+                v, MOVIE_VERSION
+            ),
+            MovieError::RomMismatch => write!(f, "movie was recorded with a different ROM"),
+            MovieError::Empty => write!(f, "movie has no frames"),
+            MovieError::LiveCamera => write!(f, "movies can't use a live camera"),
+            MovieError::State(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<io::Error> for MovieError {
+    fn from(e: io::Error) -> Self {
+        MovieError::Io(e)
+    }
+}
+
+impl From<StateError> for MovieError {
+    fn from(e: StateError) -> Self {
+        MovieError::State(e)
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum MovieStart {
+    PowerOn,
+    SaveState,
+}
+
+/// Bytes per frame in a movie file: the keys, then both tilt axes.
+const INPUT_SIZE: usize = 9;
+
+/// Everything the player controls during a frame.
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct MovieInput {
+    pub keys: JoypadKey,
+    pub tilt: Tilt,
+}
+
+/// The joypad state and tilt of every frame of a run, along with everything else needed to replay
+/// it exactly: the game, the machine state it started from and the time the cartridge clock
+/// started at.
+pub struct Movie {
+    /// CRC-32 of the whole ROM file.
+    pub rom_checksum: u32,
+    pub start: MovieStart,
+    /// Seconds since the Unix epoch (UTC) the cartridge clock starts at.
+    pub start_time: i64,
+    /// Save state the movie starts from. Power-on movies have it too, taken right after the
+    /// restart, so that SRAM and the RTC are reproduced as well.
+    pub start_state: Vec<u8>,
+    /// One entry per frame.
+    pub inputs: Vec<MovieInput>,
+    /// CRC-32 of the save state at the end of the recording, to detect desyncs.
+    pub end_state_checksum: Option<u32>,
+}
+
+impl Movie {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::new();
+        for &byte in MOVIE_MAGIC.iter() {
+            writer.write_u8(byte);
+        }
+        writer.write_u32(MOVIE_VERSION);
+        writer.write_u32(self.rom_checksum);
+        writer.write_bool(self.start == MovieStart::SaveState);
+        writer.write_u64(self.start_time as u64);
+        writer.write_bytes(&self.start_state);
+        let mut inputs = Vec::with_capacity(self.inputs.len() * INPUT_SIZE);
+        for input in self.inputs.iter() {
+            inputs.push(input.keys.bits());
+            inputs.extend_from_slice(&input.tilt.x.to_bits().to_le_bytes());
+            inputs.extend_from_slice(&input.tilt.y.to_bits().to_le_bytes());
+        }
+        writer.write_bytes(&inputs);
+        writer.write_bool(self.end_state_checksum.is_some());
+        writer.write_u32(self.end_state_checksum.unwrap_or(0));
+        writer.into_inner()
+    }
+
+    pub fn from_bytes(data: &[u8]) -> Result<Movie, MovieError> {
+        let mut reader = StateReader::new(data);
+        for &byte in MOVIE_MAGIC.iter() {
+            if reader.read_u8()? != byte {
+                return Err(MovieError::BadMagic);
+            }
+        }
+        let version = reader.read_u32()?;
+        if version != MOVIE_VERSION {
+            return Err(MovieError::UnsupportedVersion(version));
+        }
+        let rom_checksum = reader.read_u32()?;
+        let start = if reader.read_bool()? {
+            MovieStart::SaveState
+        } else {
+            MovieStart::PowerOn
+        };
+        let start_time = reader.read_u64()? as i64;
+        let start_state = reader.read_bytes()?.to_vec();
+        let input_data = reader.read_bytes()?;
+        if input_data.len() % INPUT_SIZE != 0 {
+            return Err(MovieError::State(StateError::Corrupt("movie inputs")));
+        }
+        let axis = |bytes: &[u8]| {
+            let mut bits = [0; 4];
+            bits.copy_from_slice(bytes);
+            f32::from_bits(u32::from_le_bytes(bits))
+        };
+        let inputs = input_data
+            .chunks(INPUT_SIZE)
+            .map(|input| MovieInput {
+                keys: JoypadKey::from_bits_truncate(input[0]),
+                tilt: Tilt {
+                    x: axis(&input[1..5]),
+                    y: axis(&input[5..9]),
+                },
+            })
+            .collect();
+        let has_end_state_checksum = reader.read_bool()?;
+        let end_state_checksum = reader.read_u32()?;
+        Ok(Movie {
+            rom_checksum,
+            start,
+            start_time,
+            start_state,
+            inputs,
+            end_state_checksum: if has_end_state_checksum {
+                Some(end_state_checksum)
+            } else {
+                None
+            },
+        })
+    }
+
+    pub fn load(path: &Path) -> Result<Movie, MovieError> {
+        let mut data = Vec::new();
+        File::open(path)?.read_to_end(&mut data)?;
+        Movie::from_bytes(&data)
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        File::create(path)?.write_all(&self.to_bytes())
+    }
+}
@@ -1,5 +1,6 @@
 use super::super::mem::Memory;
 use super::super::cpu::{interrupt, ioregister};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 bitflags! {
     pub struct JoypadKey: u8 {
@@ -53,3 +54,14 @@ impl Joypad {
         ioregister::joypad_set_buttons(buttons, memory);
     }
 }
+
+impl SaveState for Joypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.keys.bits);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.keys = JoypadKey::from_bits_truncate(reader.read_u8()?);
+        Ok(())
+    }
+}
@@ -1,6 +1,7 @@
 use super::super::mem::Memory;
 use super::super::cpu::{interrupt, ioregister};
 use super::super::graphics::{self, Graphics};
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 
 const STAT_MODE_0_DURATION_CYCLES: u32 = 201;
 const STAT_MODE_1_DURATION_CYCLES: u32 = 456;
@@ -33,6 +34,16 @@ impl StatMode {
             StatMode::VRam => STAT_MODE_3_DURATION_CYCLES,
         }
     }
+
+    fn from_mode_number(mode: u8) -> Option<StatMode> {
+        match mode {
+            0b00 => Some(StatMode::HBlank),
+            0b01 => Some(StatMode::VBlank),
+            0b10 => Some(StatMode::OAM),
+            0b11 => Some(StatMode::VRam),
+            _ => None,
+        }
+    }
 }
 
 pub struct LCD {
@@ -132,3 +143,17 @@ impl LCD {
         cycles
     }
 }
+
+impl SaveState for LCD {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.curr_stat_mode.mode_number());
+        writer.write_bool(self.cgb_dma_requested);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.curr_stat_mode = StatMode::from_mode_number(reader.read_u8()?)
+            .ok_or(StateError::Corrupt("invalid lcd mode"))?;
+        self.cgb_dma_requested = reader.read_bool()?;
+        Ok(())
+    }
+}
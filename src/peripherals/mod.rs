@@ -1,3 +1,5 @@
 pub mod lcd;
 pub mod joypad;
+pub mod serial;
 pub mod sound;
+pub mod tilt;
@@ -0,0 +1,27 @@
+use crate::peripherals::serial::SerialLink;
+
+/// A cable with its output plugged back into its input: every byte sent is received back.
+pub struct LoopbackLink {
+    last_sent: u8,
+}
+
+impl Default for LoopbackLink {
+    fn default() -> Self {
+        LoopbackLink { last_sent: 0xFF }
+    }
+}
+
+impl SerialLink for LoopbackLink {
+    fn start_transfer(&mut self, out: u8) {
+        self.last_sent = out;
+    }
+
+    fn finish_transfer(&mut self) -> u8 {
+        self.last_sent
+    }
+
+    fn poll_external(&mut self, _out: Option<u8>) -> Option<u8> {
+        // Nothing drives the clock line, so externally clocked transfers never happen.
+        None
+    }
+}
@@ -0,0 +1,163 @@
+pub mod capture;
+pub mod loopback;
+pub mod tcp;
+
+use super::super::mem::Memory;
+use super::super::cpu::{interrupt, ioregister};
+use crate::gebemula::GBMode;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
+
+/// Cycles per bit with the internal 8192 Hz clock.
+const NORMAL_CLOCK_BIT_CYCLES: u32 = ioregister::CPU_FREQUENCY_HZ / 8192;
+/// Cycles per bit with the CGB-only 262144 Hz clock.
+const FAST_CLOCK_BIT_CYCLES: u32 = ioregister::CPU_FREQUENCY_HZ / 262144;
+
+/// The other end of the link cable.
+pub trait SerialLink {
+    /// Called when we start a transfer with our internal clock, with the byte we shift out.
+    fn start_transfer(&mut self, out: u8);
+    /// Called once all 8 bits of a transfer started with `start_transfer` have been clocked.
+    /// Returns the byte shifted in from the other side.
+    fn finish_transfer(&mut self) -> u8;
+    /// Polled while we aren't clocking a transfer ourselves. `out` is the byte we would shift out
+    /// if we are waiting for an external clock, or None if we aren't. Returns the byte shifted in
+    /// if the other side clocked a whole transfer.
+    fn poll_external(&mut self, out: Option<u8>) -> Option<u8>;
+    /// Called before anything else on every update, with the cycles elapsed since the last one.
+    fn tick(&mut self, _cycles: u32) {}
+}
+
+/// Nothing is plugged into the link port: the input line stays high and no external clock ever
+/// arrives.
+pub struct DisconnectedLink;
+
+impl SerialLink for DisconnectedLink {
+    fn start_transfer(&mut self, _out: u8) {}
+    fn finish_transfer(&mut self) -> u8 {
+        0xFF
+    }
+    fn poll_external(&mut self, _out: Option<u8>) -> Option<u8> {
+        None
+    }
+}
+
+#[derive(Copy, Clone, PartialEq)]
+enum TransferState {
+    Idle,
+    /// Clocked by us, finishes once all 8 bits have been shifted.
+    Internal { cycles_left: u32 },
+    /// Waiting for the other side to clock the transfer.
+    External,
+}
+
+pub struct Serial {
+    link: Box<dyn SerialLink>,
+    state: TransferState,
+}
+
+impl Default for Serial {
+    fn default() -> Self {
+        Serial {
+            link: Box::new(DisconnectedLink),
+            state: TransferState::Idle,
+        }
+    }
+}
+
+impl Serial {
+    pub fn set_link(&mut self, link: Box<dyn SerialLink>) {
+        self.link = link;
+    }
+
+    pub fn restart(&mut self) {
+        self.state = TransferState::Idle;
+    }
+
+    /// Must be called whenever SC is written.
+    pub fn control_written(&mut self, memory: &mut Memory) {
+        let sc = memory.read_byte(ioregister::SC_REGISTER_ADDR);
+        if sc & 0b1000_0000 == 0 {
+            self.state = TransferState::Idle;
+        } else if sc & 0b1 == 0b1 {
+            if let TransferState::Internal { .. } = self.state {
+                return;
+            }
+            let fast = sc & 0b10 != 0 && GBMode::get(memory) == GBMode::Color;
+            let bit_cycles = if fast {
+                FAST_CLOCK_BIT_CYCLES
+            } else {
+                NORMAL_CLOCK_BIT_CYCLES
+            };
+            self.link
+                .start_transfer(memory.read_byte(ioregister::SB_REGISTER_ADDR));
+            self.state = TransferState::Internal {
+                cycles_left: 8 * bit_cycles,
+            };
+        } else {
+            self.state = TransferState::External;
+        }
+    }
+
+    pub fn update(&mut self, cycles: u32, memory: &mut Memory) {
+        self.link.tick(cycles);
+        match self.state {
+            TransferState::Internal { cycles_left } => {
+                // The other side may still try to clock a transfer of its own.
+                self.link.poll_external(None);
+                if cycles_left > cycles {
+                    self.state = TransferState::Internal {
+                        cycles_left: cycles_left - cycles,
+                    };
+                } else {
+                    // SB isn't shifted bit by bit: the incoming byte replaces it once the transfer
+                    // is done, which is the only point where games look at it.
+                    let byte = self.link.finish_transfer();
+                    memory.write_byte(ioregister::SB_REGISTER_ADDR, byte);
+                    self.complete_transfer(memory);
+                }
+            }
+            TransferState::External => {
+                let sb = memory.read_byte(ioregister::SB_REGISTER_ADDR);
+                if let Some(byte) = self.link.poll_external(Some(sb)) {
+                    memory.write_byte(ioregister::SB_REGISTER_ADDR, byte);
+                    self.complete_transfer(memory);
+                }
+            }
+            TransferState::Idle => {
+                self.link.poll_external(None);
+            }
+        }
+    }
+
+    fn complete_transfer(&mut self, memory: &mut Memory) {
+        self.state = TransferState::Idle;
+        let sc = memory.read_byte(ioregister::SC_REGISTER_ADDR);
+        memory.write_byte(ioregister::SC_REGISTER_ADDR, sc & 0b0111_1111);
+        interrupt::request(interrupt::Interrupt::SerialIO, memory);
+    }
+}
+
+impl SaveState for Serial {
+    fn save_state(&self, writer: &mut StateWriter) {
+        match self.state {
+            TransferState::Idle => writer.write_u8(0),
+            TransferState::Internal { cycles_left } => {
+                writer.write_u8(1);
+                writer.write_u32(cycles_left);
+            }
+            TransferState::External => writer.write_u8(2),
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.state = match reader.read_u8()? {
+            0 => TransferState::Idle,
+            1 => TransferState::Internal {
+                cycles_left: reader.read_u32()?,
+            },
+            2 => TransferState::External,
+            _ => return Err(StateError::Corrupt("invalid serial transfer state")),
+        };
+        Ok(())
+    }
+}
@@ -0,0 +1,254 @@
+use crate::peripherals::serial::SerialLink;
+
+use std::collections::VecDeque;
+use std::io::{self, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+/// How many cycles one side may run ahead of what it knows about the other. A byte sent at cycle
+/// `c` is seen by the other side at its own cycle `c + LOOKAHEAD_CYCLES`, so the result of every
+/// transfer depends only on the emulated timelines and not on network latency. It's the duration
+/// of a transfer with the normal clock, which games already wait for between bytes.
+const LOOKAHEAD_CYCLES: u64 = 4096;
+/// How often we let the other side know how far we got when there's nothing else to send.
+const SYNC_INTERVAL_CYCLES: u64 = LOOKAHEAD_CYCLES / 2;
+/// How long the start of a transfer is remembered, for checking later transfers against it.
+const TRANSFER_HISTORY_CYCLES: u64 = 3 * LOOKAHEAD_CYCLES;
+
+const MSG_SYNC: u8 = 0;
+const MSG_TRANSFER: u8 = 1;
+const MSG_REPLY: u8 = 2;
+
+enum Message {
+    /// The other side has reached this cycle.
+    Sync(u64),
+    /// The other side started a transfer with its internal clock at this cycle.
+    Transfer(u64, u8),
+    /// The byte the other side shifted out during our last transfer that didn't collide.
+    Reply(u8),
+}
+
+/// Whether transfers started at these cycles, one on each side, collide. Neither side sees the
+/// other's transfer before starting its own then, so both read 0xFF and nobody replies. Both
+/// sides know both cycles, so they always agree.
+fn collide(cycle: u64, other_cycle: u64) -> bool {
+    cycle.max(other_cycle) - cycle.min(other_cycle) < LOOKAHEAD_CYCLES
+}
+
+fn read_message<R: Read>(reader: &mut R) -> io::Result<Message> {
+    let mut tag = [0u8; 1];
+    reader.read_exact(&mut tag)?;
+    let mut cycle = [0u8; 8];
+    let mut byte = [0u8; 1];
+    match tag[0] {
+        MSG_SYNC => {
+            reader.read_exact(&mut cycle)?;
+            Ok(Message::Sync(u64::from_le_bytes(cycle)))
+        }
+        MSG_TRANSFER => {
+            reader.read_exact(&mut cycle)?;
+            reader.read_exact(&mut byte)?;
+            Ok(Message::Transfer(u64::from_le_bytes(cycle), byte[0]))
+        }
+        MSG_REPLY => {
+            reader.read_exact(&mut byte)?;
+            Ok(Message::Reply(byte[0]))
+        }
+        t => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown link message {}", t),
+        )),
+    }
+}
+
+/// Link cable to another gebemula instance over TCP.
+///
+/// Both instances run in lockstep, never more than `LOOKAHEAD_CYCLES` apart. If both sides clock
+/// a transfer less than `LOOKAHEAD_CYCLES` apart, both read 0xFF.
+pub struct TcpLink {
+    stream: TcpStream,
+    incoming: Receiver<Message>,
+    connected: bool,
+    cycles: u64,
+    remote_cycles: u64,
+    last_sync_sent: u64,
+    /// Transfers clocked by the other side that we haven't answered yet, with the cycle at which
+    /// they started.
+    pending: VecDeque<(u64, u8)>,
+    /// Start cycles of the recent transfers of both sides, to find collisions.
+    transfers: VecDeque<u64>,
+    remote_transfers: VecDeque<u64>,
+    reply: Option<u8>,
+}
+
+impl TcpLink {
+    /// Waits for another instance to connect to `addr`.
+    pub fn listen<A: ToSocketAddrs>(addr: A) -> io::Result<TcpLink> {
+        let listener = TcpListener::bind(addr)?;
+        println!(
+            "Waiting for the link cable to be connected on {}...",
+            listener.local_addr()?
+        );
+        let (stream, peer) = listener.accept()?;
+        println!("Link cable connected to {}.", peer);
+        TcpLink::new(stream)
+    }
+
+    /// Connects to another instance waiting on `addr`.
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> io::Result<TcpLink> {
+        let stream = TcpStream::connect(addr)?;
+        println!("Link cable connected to {}.", stream.peer_addr()?);
+        TcpLink::new(stream)
+    }
+
+    fn new(stream: TcpStream) -> io::Result<TcpLink> {
+        stream.set_nodelay(true)?;
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let (sender, incoming) = mpsc::channel();
+        thread::spawn(move || {
+            while let Ok(message) = read_message(&mut reader) {
+                if sender.send(message).is_err() {
+                    break;
+                }
+            }
+        });
+        Ok(TcpLink {
+            stream,
+            incoming,
+            connected: true,
+            cycles: 0,
+            remote_cycles: 0,
+            last_sync_sent: 0,
+            pending: VecDeque::new(),
+            transfers: VecDeque::new(),
+            remote_transfers: VecDeque::new(),
+            reply: None,
+        })
+    }
+
+    fn disconnect(&mut self) {
+        if self.connected {
+            println!("Link cable disconnected.");
+            self.connected = false;
+            self.pending.clear();
+        }
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        if !self.connected {
+            return;
+        }
+        if self.stream.write_all(data).is_err() {
+            self.disconnect();
+        }
+    }
+
+    fn send_sync(&mut self) {
+        if self.last_sync_sent < self.cycles {
+            self.last_sync_sent = self.cycles;
+            let mut data = [MSG_SYNC; 9];
+            data[1..].copy_from_slice(&self.cycles.to_le_bytes());
+            self.send(&data);
+        }
+    }
+
+    fn send_reply(&mut self, byte: u8) {
+        self.send(&[MSG_REPLY, byte]);
+    }
+
+    fn handle(&mut self, message: Message) {
+        match message {
+            Message::Sync(cycle) => self.remote_cycles = self.remote_cycles.max(cycle),
+            Message::Transfer(cycle, byte) => {
+                self.remote_cycles = self.remote_cycles.max(cycle);
+                self.pending.push_back((cycle, byte));
+                self.remote_transfers.push_back(cycle);
+            }
+            Message::Reply(byte) => self.reply = Some(byte),
+        }
+    }
+
+    fn receive_available(&mut self) {
+        while self.connected {
+            match self.incoming.try_recv() {
+                Ok(message) => self.handle(message),
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => self.disconnect(),
+            }
+        }
+    }
+
+    fn receive_blocking(&mut self) {
+        match self.incoming.recv() {
+            Ok(message) => self.handle(message),
+            Err(_) => self.disconnect(),
+        }
+    }
+}
+
+impl SerialLink for TcpLink {
+    fn start_transfer(&mut self, out: u8) {
+        self.transfers.push_back(self.cycles);
+        self.reply = None;
+        let mut data = [MSG_TRANSFER; 10];
+        data[1..9].copy_from_slice(&self.cycles.to_le_bytes());
+        data[9] = out;
+        self.send(&data);
+        self.last_sync_sent = self.cycles;
+    }
+
+    fn finish_transfer(&mut self) -> u8 {
+        let start = self.transfers.back().cloned().unwrap_or(self.cycles);
+        self.send_sync();
+        // The other side either replies once it reaches our transfer, or started a colliding one
+        // before that. Both arrive in the order they were sent, and the other side can always run
+        // far enough to send one of them.
+        while self.connected && self.reply.is_none() {
+            if self.remote_transfers.iter().any(|&cycle| collide(start, cycle)) {
+                return 0xFF;
+            }
+            self.receive_blocking();
+        }
+        self.reply.take().unwrap_or(0xFF)
+    }
+
+    fn poll_external(&mut self, out: Option<u8>) -> Option<u8> {
+        // Transfers of the other side take effect `LOOKAHEAD_CYCLES` after they started, by which
+        // point any of ours that collides with them has started too.
+        while let Some(&(cycle, byte)) = self.pending.front() {
+            if cycle + LOOKAHEAD_CYCLES > self.cycles {
+                break;
+            }
+            self.pending.pop_front();
+            if self.transfers.iter().any(|&start| collide(start, cycle)) {
+                continue;
+            }
+            self.send_reply(out.unwrap_or(0xFF));
+            if out.is_some() {
+                return Some(byte);
+            }
+        }
+        None
+    }
+
+    fn tick(&mut self, cycles: u32) {
+        if !self.connected {
+            return;
+        }
+        self.cycles += cycles as u64;
+        let oldest = self.cycles.saturating_sub(TRANSFER_HISTORY_CYCLES);
+        self.transfers.retain(|&cycle| cycle >= oldest);
+        self.remote_transfers.retain(|&cycle| cycle >= oldest);
+        if self.cycles - self.last_sync_sent >= SYNC_INTERVAL_CYCLES {
+            self.send_sync();
+        }
+        self.receive_available();
+        if self.cycles >= self.remote_cycles + LOOKAHEAD_CYCLES {
+            self.send_sync();
+            while self.connected && self.cycles >= self.remote_cycles + LOOKAHEAD_CYCLES {
+                self.receive_blocking();
+            }
+        }
+    }
+}
@@ -1,6 +1,6 @@
 use super::super::cpu::ioregister::CPU_FREQUENCY_HZ;
+use crate::state::{SaveState, StateError, StateReader, StateWriter};
 use blip_buf::BlipBuf;
-use sdl2::audio::AudioSpecDesired;
 use std::fmt;
 use std::fmt::Debug;
 
@@ -45,12 +45,6 @@ const CUSTOM_WAVE_END_ADDR: u16 = 0xFF3F;
 pub const OUTPUT_FREQUENCY: u32 = CPU_FREQUENCY_HZ;
 pub const OUTPUT_CHANNELS: usize = 2;
 
-pub const AUDIO_DESIRED_SPEC: AudioSpecDesired = AudioSpecDesired {
-    freq: Some(48000),
-    channels: Some(2),
-    samples: None, // default sample size
-};
-
 fn make_blip_buf(sample_rate: u32) -> BlipBuf {
     let mut buf = BlipBuf::new(sample_rate * 2);
     buf.set_rates(CPU_FREQUENCY_HZ as f64, sample_rate as f64);
@@ -59,6 +53,72 @@ fn make_blip_buf(sample_rate: u32) -> BlipBuf {
 
 const NUM_CHANNELS: usize = 4;
 
+/// A pair of blip buffers for the left and right outputs.
+struct StereoBuffer {
+    buf_l: BlipBuf,
+    buf_r: BlipBuf,
+    previous_l: i32,
+    previous_r: i32,
+}
+
+impl StereoBuffer {
+    fn new(sample_rate: u32) -> StereoBuffer {
+        StereoBuffer {
+            buf_l: make_blip_buf(sample_rate),
+            buf_r: make_blip_buf(sample_rate),
+            previous_l: 0,
+            previous_r: 0,
+        }
+    }
+
+    fn add(&mut self, cycle: u32, l: i32, r: i32) {
+        self.buf_l.add_delta(cycle, l - self.previous_l);
+        self.previous_l = l;
+        self.buf_r.add_delta(cycle, r - self.previous_r);
+        self.previous_r = r;
+    }
+
+    /// Ends the frame at `cycles` and appends its samples to `output`, interleaved.
+    fn read_frame(&mut self, cycles: u32, output: &mut Vec<i16>) {
+        self.buf_l.end_frame(cycles);
+        self.buf_r.end_frame(cycles);
+
+        let samples_available = self.buf_l.samples_avail() as usize;
+        assert_eq!(samples_available, self.buf_r.samples_avail() as usize);
+        if samples_available <= 0 {
+            return;
+        }
+
+        let previous_len = output.len();
+        // The blip_buf crate incorrectly computes the output array size when using stereo, add an extra item to prevent that
+        output.resize(previous_len + samples_available * 2 + 1, 0);
+        {
+            let new_output = &mut output[previous_len..];
+            let samples_read_l = self.buf_l
+                .read_samples(&mut new_output[0..samples_available * 2], true);
+            let samples_read_r = self.buf_r
+                .read_samples(&mut new_output[1..samples_available * 2 + 1], true);
+            assert_eq!(samples_read_l, samples_read_r);
+            assert_eq!(samples_read_l, samples_available);
+        }
+        // Remove the item used for the workaround above
+        output.pop();
+    }
+
+    fn clear(&mut self) {
+        self.buf_l.clear();
+        self.buf_r.clear();
+        self.previous_l = 0;
+        self.previous_r = 0;
+    }
+}
+
+/// The output of a single voice, recorded on its own.
+struct Stem {
+    buffer: StereoBuffer,
+    samples: Vec<i16>,
+}
+
 #[derive(Copy, Clone)]
 struct SquareVoiceSettings {
     regs: [u8; 5],
@@ -93,6 +153,10 @@ impl SquareVoiceSettings {
     fn env_period(&self) -> u8 {
         (self.regs[2] & 0b0000_0111) >> 0
     }
+    // The DAC is off when the initial volume is 0 and the envelope decreases.
+    fn dac_enabled(&self) -> bool {
+        self.regs[2] & 0b1111_1000 != 0
+    }
 
     fn frequency_lsb(&self) -> u8 {
         self.regs[3]
@@ -167,27 +231,108 @@ impl WaveVoiceSettings {
     }
 }
 
+#[derive(Copy, Clone)]
+struct NoiseVoiceSettings {
+    regs: [u8; 5],
+}
+
+impl NoiseVoiceSettings {
+    fn sound_length(&self) -> u8 {
+        (self.regs[1] & 0b0011_1111) >> 0
+    }
+
+    // Envelope
+    fn initial_volume(&self) -> u8 {
+        (self.regs[2] & 0b1111_0000) >> 4
+    }
+    fn env_direction(&self) -> u8 {
+        (self.regs[2] & 0b0000_1000) >> 3
+    }
+    fn env_period(&self) -> u8 {
+        (self.regs[2] & 0b0000_0111) >> 0
+    }
+    fn dac_enabled(&self) -> bool {
+        self.regs[2] & 0b1111_1000 != 0
+    }
+
+    fn clock_shift(&self) -> u8 {
+        (self.regs[3] & 0b1111_0000) >> 4
+    }
+    fn width_mode(&self) -> bool {
+        (self.regs[3] & 0b0000_1000) >> 3 != 0
+    }
+    fn divisor_code(&self) -> u8 {
+        (self.regs[3] & 0b0000_0111) >> 0
+    }
+
+    fn trigger(&self) -> bool {
+        (self.regs[4] & 0b1000_0000) >> 7 != 0
+    }
+    fn length_enable(&self) -> bool {
+        (self.regs[4] & 0b0100_0000) >> 6 != 0
+    }
+}
+
+#[derive(Copy, Clone)]
+struct ControlSettings {
+    regs: [u8; 3],
+}
+
+impl ControlSettings {
+    fn vin_left(&self) -> bool {
+        (self.regs[0] & 0b1000_0000) >> 7 != 0
+    }
+    fn left_volume(&self) -> u8 {
+        (self.regs[0] & 0b0111_0000) >> 4
+    }
+    fn vin_right(&self) -> bool {
+        (self.regs[0] & 0b0000_1000) >> 3 != 0
+    }
+    fn right_volume(&self) -> u8 {
+        (self.regs[0] & 0b0000_0111) >> 0
+    }
+
+    // ch is 0-based
+    fn left_enabled(&self, ch: usize) -> bool {
+        (self.regs[1] >> (4 + ch)) & 1 != 0
+    }
+    fn right_enabled(&self, ch: usize) -> bool {
+        (self.regs[1] >> ch) & 1 != 0
+    }
+}
+
+/// Cycles between two frame sequencer steps (512 Hz).
+const SEQUENCER_PERIOD: u16 = (CPU_FREQUENCY_HZ / 512) as u16;
+
+/// Frame sequencer. Each of its 8 steps clocks some of the voice units:
+///
+/// Step   | 0 | 1 | 2 | 3 | 4 | 5 | 6 | 7
+/// Length | x |   | x |   | x |   | x |
+/// Sweep  |   |   | x |   |   |   | x |
+/// Volume |   |   |   |   |   |   |   | x
 struct Sequencer {
-    // These all step when the value == 0
-    length_step: u16, // mod 2
-    volume_step: u16, // mod 8
-    sweep_step: u16,  // mod 4
+    /// The step that will run next.
+    step: u8,
 }
 
 impl Sequencer {
     fn new() -> Sequencer {
-        Sequencer {
-            length_step: 0,
-            volume_step: 1,
-            sweep_step: 2,
-        }
+        Sequencer { step: 0 }
+    }
+
+    fn clocks_length(&self) -> bool {
+        self.step % 2 == 0
+    }
+    fn clocks_envelope(&self) -> bool {
+        self.step == 7
+    }
+    fn clocks_sweep(&self) -> bool {
+        self.step == 2 || self.step == 6
     }
 
     // Clocked at 512 Hz
     fn step(&mut self) {
-        self.length_step = (self.length_step + 1) % 2;
-        self.volume_step = (self.volume_step + 1) % 8;
-        self.sweep_step = (self.sweep_step + 1) % 4;
+        self.step = (self.step + 1) % 8;
     }
 }
 
@@ -221,6 +366,73 @@ fn adjust_volume_envelope(volume: &mut u8, direction: u8) {
     }
 }
 
+/// Clocks the envelope of a voice. A period of 0 stops the envelope, and its timer treats it as 8.
+fn step_volume_envelope(volume: &mut u8, counter: &mut u8, period: u8, direction: u8) {
+    if period == 0 {
+        return;
+    }
+    if *counter > 0 {
+        *counter -= 1;
+    }
+    if *counter == 0 {
+        *counter = period;
+        adjust_volume_envelope(volume, direction);
+    }
+}
+
+/// "Zombie mode": writing NRx2 while the voice is playing changes its volume in odd ways instead of
+/// just switching envelopes. Several games rely on this to change the volume of a note.
+fn zombie_volume_envelope(volume: &mut u8, old_regs: u8, new_regs: u8) {
+    let old_period = old_regs & 0b0000_0111;
+    let old_direction = (old_regs & 0b0000_1000) >> 3;
+    let new_direction = (new_regs & 0b0000_1000) >> 3;
+    // The volume is 4 bits wide, so every step wraps around.
+    if old_period == 0 && old_direction == 1 {
+        *volume = (*volume + 1) & 0x0F;
+    } else if old_direction == 0 {
+        *volume = (*volume + 2) & 0x0F;
+    }
+    if old_direction != new_direction {
+        *volume = 16u8.wrapping_sub(*volume) & 0x0F;
+    }
+}
+
+/// Clocks a length counter. Returns true if the voice should stop.
+fn step_length_counter(counter: &mut u16, enabled: bool) -> bool {
+    if enabled && *counter > 0 {
+        *counter -= 1;
+        return *counter == 0;
+    }
+    false
+}
+
+/// Applies the length counter side effects of a write to NRx4. Returns true if the voice should
+/// stop.
+fn write_length_control(
+    counter: &mut u16,
+    max_length: u16,
+    was_enabled: bool,
+    enabled: bool,
+    trigger: bool,
+    sequencer: &Sequencer,
+) -> bool {
+    // If the last sequencer step clocked the length counters, enabling one clocks it once more.
+    let extra_clock = !sequencer.clocks_length();
+    let mut stop = false;
+    if extra_clock && enabled && !was_enabled && *counter > 0 {
+        *counter -= 1;
+        stop = *counter == 0 && !trigger;
+    }
+    if trigger && *counter == 0 {
+        *counter = if enabled && extra_clock {
+            max_length - 1
+        } else {
+            max_length
+        };
+    }
+    stop
+}
+
 // Returns (new_frequency, overflow)
 fn compute_sweep(old_frequency: u16, regs: SquareVoiceSettings) -> (u16, bool) {
     let abs_delta = old_frequency >> regs.sweep_shift();
@@ -252,59 +464,63 @@ impl SquareVoice {
         }
     }
 
-    fn step_sweep(&mut self, regs: SquareVoiceSettings) {
+    fn sweep_timer_period(regs: SquareVoiceSettings) -> u8 {
+        match regs.sweep_period() {
+            0 => 8,
+            period => period,
+        }
+    }
+
+    /// Returns true if the sound should stop because the frequency overflowed.
+    fn step_sweep(&mut self, regs: SquareVoiceSettings) -> bool {
         if !self.has_sweep {
             unreachable!();
         }
 
-        if self.sweep_active && regs.sweep_period() != 0 {
-            if self.sweep_counter == 0 {
-                let (new_frequency, overflow) = compute_sweep(self.sweep_frequency, regs);
-                let (_, overflow2) = compute_sweep(new_frequency, regs);
-
-                if !overflow {
-                    self.sweep_frequency = new_frequency;
-                    self.frequency = new_frequency;
-                }
-                if overflow || overflow2 {
-                    // TODO disable channel
-                }
+        if self.sweep_counter > 0 {
+            self.sweep_counter -= 1;
+        }
+        if self.sweep_counter != 0 {
+            return false;
+        }
+        self.sweep_counter = SquareVoice::sweep_timer_period(regs);
 
-                self.sweep_counter = regs.sweep_period();
-            } else {
-                self.sweep_counter -= 1;
+        if self.sweep_active && regs.sweep_period() != 0 {
+            let (new_frequency, overflow) = compute_sweep(self.sweep_frequency, regs);
+            if overflow {
+                return true;
+            }
+            if regs.sweep_shift() != 0 {
+                self.sweep_frequency = new_frequency;
+                self.frequency = new_frequency;
+                // The new frequency is checked again right away, without being used.
+                let (_, overflow) = compute_sweep(new_frequency, regs);
+                return overflow;
             }
         }
+        false
     }
 
     fn step_envelope(&mut self, regs: SquareVoiceSettings) {
-        if regs.env_period() != 0 {
-            if self.envelope_counter == 0 {
-                adjust_volume_envelope(&mut self.volume, regs.env_direction());
-                self.envelope_counter = regs.env_period();
-            } else {
-                self.envelope_counter -= 1;
-            }
-        }
+        step_volume_envelope(
+            &mut self.volume,
+            &mut self.envelope_counter,
+            regs.env_period(),
+            regs.env_direction(),
+        );
     }
 
     /// Returns true if the sound should stop based on its length.
     fn step_length(&mut self, regs: SquareVoiceSettings) -> bool {
-        if regs.length_enable() {
-            if self.length_counter > 0 {
-                self.length_counter -= 1;
-            } else {
-                return true;
-            }
-        }
-        false
+        step_length_counter(&mut self.length_counter, regs.length_enable())
     }
 
     fn get_frequency_period(&self) -> u16 {
         (32 / 8) * (2048 - self.frequency)
     }
 
-    fn trigger(&mut self, regs: SquareVoiceSettings) {
+    /// Returns true if the sound should stop right away because the sweep overflowed.
+    fn trigger(&mut self, regs: SquareVoiceSettings) -> bool {
         self.volume = regs.initial_volume();
         self.frequency_counter = self.get_frequency_period();
         self.envelope_counter = regs.env_period();
@@ -313,21 +529,17 @@ impl SquareVoice {
             "trigger ch{}: regs={:?}\nvolume={} freq={} len={}",
             self.channel_num, regs, self.volume, self.frequency_counter, self.length_counter
         );*/
-        if self.length_counter == 0 {
-            self.length_counter = 64;
-        }
 
         if self.has_sweep {
             self.sweep_frequency = self.frequency;
-            self.sweep_counter = regs.sweep_period();
+            self.sweep_counter = SquareVoice::sweep_timer_period(regs);
             self.sweep_active = regs.sweep_shift() != 0 || regs.sweep_period() != 0;
             if regs.sweep_shift() != 0 {
                 let (_new_frequency, overflow) = compute_sweep(self.sweep_frequency, regs);
-                if overflow {
-                    // TODO: Disable channel
-                }
+                return overflow;
             }
         }
+        false
     }
 
     fn step(&mut self, _regs: SquareVoiceSettings) {
@@ -373,14 +585,7 @@ impl WaveVoice {
 
     /// Returns true if the sound should stop based on its length.
     fn step_length(&mut self, regs: WaveVoiceSettings) -> bool {
-        if regs.length_enable() {
-            if self.length_counter > 0 {
-                self.length_counter -= 1;
-            } else {
-                return true;
-            }
-        }
-        false
+        step_length_counter(&mut self.length_counter, regs.length_enable())
     }
 
     fn get_frequency_period(&self) -> u16 {
@@ -390,10 +595,6 @@ impl WaveVoice {
     fn trigger(&mut self, _regs: WaveVoiceSettings) {
         self.pos_counter = 0;
         self.frequency_counter = self.get_frequency_period();
-        
-        if self.length_counter == 0 {
-            self.length_counter = 256;
-        }
     }
 
     fn step(&mut self, _regs: WaveVoiceSettings) {
@@ -422,7 +623,78 @@ impl WaveVoice {
     }
 }
 
-struct NoiseVoice {}
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+struct NoiseVoice {
+    length_counter: u16,
+    frequency_counter: u32,
+    envelope_counter: u8,
+    volume: u8,
+    /// 15-bit linear feedback shift register.
+    lfsr: u16,
+}
+
+impl NoiseVoice {
+    fn new() -> Self {
+        NoiseVoice {
+            length_counter: 0,
+            frequency_counter: 0,
+            envelope_counter: 0,
+            volume: 0,
+            lfsr: 0x7FFF,
+        }
+    }
+
+    fn step_envelope(&mut self, regs: NoiseVoiceSettings) {
+        step_volume_envelope(
+            &mut self.volume,
+            &mut self.envelope_counter,
+            regs.env_period(),
+            regs.env_direction(),
+        );
+    }
+
+    /// Returns true if the sound should stop based on its length.
+    fn step_length(&mut self, regs: NoiseVoiceSettings) -> bool {
+        step_length_counter(&mut self.length_counter, regs.length_enable())
+    }
+
+    fn get_frequency_period(&self, regs: NoiseVoiceSettings) -> u32 {
+        NOISE_DIVISORS[regs.divisor_code() as usize] << regs.clock_shift()
+    }
+
+    fn trigger(&mut self, regs: NoiseVoiceSettings) {
+        self.volume = regs.initial_volume();
+        self.frequency_counter = self.get_frequency_period(regs);
+        self.envelope_counter = regs.env_period();
+        self.lfsr = 0x7FFF;
+    }
+
+    fn step(&mut self, regs: NoiseVoiceSettings) {
+        if self.frequency_counter > 0 {
+            self.frequency_counter -= 1;
+        } else {
+            self.frequency_counter = self.get_frequency_period(regs);
+            // Shifts of 14 and 15 stop the LFSR from being clocked at all.
+            if regs.clock_shift() < 14 {
+                let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+                self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+                if regs.width_mode() {
+                    self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+                }
+            }
+        }
+    }
+
+    // 4-bit output
+    fn sample(&self) -> u8 {
+        if self.lfsr & 1 == 0 {
+            self.volume
+        } else {
+            0
+        }
+    }
+}
 
 pub struct AudioController {
     /// All register values as written.
@@ -434,12 +706,12 @@ pub struct AudioController {
     /// 0x20-0x2F: Wave table (packed, 2 4-bit samples per byte)
     regs: [u8; 0x30],
 
-    buf_l: BlipBuf,
-    buf_r: BlipBuf,
-    previous_l: i32,
-    previous_r: i32,
+    output: StereoBuffer,
+    sample_rate: u32,
+    /// One per voice while stems are enabled, empty otherwise. Unaffected by debug muting.
+    stems: Vec<Stem>,
 
-    /// Cycles since last output to buf_l/buf_r
+    /// Cycles since last output to the buffers
     cur_cycle: u32,
 
     sequencer: Sequencer,
@@ -459,10 +731,9 @@ impl AudioController {
     pub fn new() -> AudioController {
         AudioController {
             regs: [0; 0x30],
-            buf_l: make_blip_buf(0),
-            buf_r: make_blip_buf(0),
-            previous_l: 0,
-            previous_r: 0,
+            output: StereoBuffer::new(0),
+            sample_rate: 0,
+            stems: Vec::new(),
 
             cur_cycle: 0,
 
@@ -474,15 +745,37 @@ impl AudioController {
             ch1: SquareVoice::new(1),
             ch2: SquareVoice::new(2),
             ch3: WaveVoice::new(),
-            ch4: NoiseVoice {},
+            ch4: NoiseVoice::new(),
 
             debug_enabled_channels: [true; NUM_CHANNELS],
         }
     }
 
     pub fn set_sample_rate(&mut self, output_sample_rate: u32) {
-        self.buf_l = make_blip_buf(output_sample_rate);
-        self.buf_r = make_blip_buf(output_sample_rate);
+        self.output = StereoBuffer::new(output_sample_rate);
+        self.sample_rate = output_sample_rate;
+        let enabled = !self.stems.is_empty();
+        self.set_stems_enabled(enabled);
+    }
+
+    /// Starts or stops rendering each voice to its own buffer, in addition to the mix.
+    pub fn set_stems_enabled(&mut self, enabled: bool) {
+        self.stems.clear();
+        if enabled {
+            for _ in 0..NUM_CHANNELS {
+                self.stems.push(Stem {
+                    buffer: StereoBuffer::new(self.sample_rate),
+                    samples: Vec::new(),
+                });
+            }
+        }
+    }
+
+    /// Appends the audio of voice `ch` (0-3) generated so far by `generate_audio` to `output`.
+    pub fn drain_stem(&mut self, ch: usize, output: &mut Vec<i16>) {
+        if let Some(stem) = self.stems.get_mut(ch) {
+            output.append(&mut stem.samples);
+        }
     }
 
     pub fn write_reg(&mut self, addr: u16, val: u8) {
@@ -492,6 +785,10 @@ impl AudioController {
                 println!("APU power={}", enable);
                 if self.apu_enabled && !enable {
                     self.power_down();
+                } else if !self.apu_enabled && enable {
+                    // The frame sequencer restarts so that the next step is 0.
+                    self.sequencer = Sequencer::new();
+                    self.sequencer_counter = 0;
                 }
                 self.apu_enabled = enable;
                 return;
@@ -504,12 +801,23 @@ impl AudioController {
             _ => return,
         }
 
-        self.regs[(addr - IO_START) as usize] = val;
+        let index = (addr - IO_START) as usize;
+        let old_val = self.regs[index];
+        self.regs[index] = val;
         match addr {
             NR11_REGISTER_ADDR => {
                 let regs = self.nr1x();
                 self.ch1.length_counter = 64 - regs.note_length() as u16;
             }
+            NR12_REGISTER_ADDR => {
+                let regs = self.nr1x();
+                if self.enabled_channels[0] {
+                    zombie_volume_envelope(&mut self.ch1.volume, old_val, val);
+                }
+                if !regs.dac_enabled() {
+                    self.enabled_channels[0] = false;
+                }
+            }
             NR13_REGISTER_ADDR => {
                 let regs = self.nr1x();
                 self.ch1.frequency &= !0xFF;
@@ -520,15 +828,35 @@ impl AudioController {
                 self.ch1.frequency &= !0x700;
                 self.ch1.frequency |= (regs.frequency_msb() as u16) << 8;
 
+                let mut stop = write_length_control(
+                    &mut self.ch1.length_counter,
+                    64,
+                    old_val & 0b0100_0000 != 0,
+                    regs.length_enable(),
+                    regs.trigger(),
+                    &self.sequencer,
+                );
                 if regs.trigger() {
-                    self.enabled_channels[0] = true;
-                    self.ch1.trigger(regs);
+                    self.enabled_channels[0] = regs.dac_enabled();
+                    stop |= self.ch1.trigger(regs);
+                }
+                if stop {
+                    self.enabled_channels[0] = false;
                 }
             },
             NR21_REGISTER_ADDR => {
                 let regs = self.nr2x();
                 self.ch2.length_counter = 64 - regs.note_length() as u16;
             }
+            NR22_REGISTER_ADDR => {
+                let regs = self.nr2x();
+                if self.enabled_channels[1] {
+                    zombie_volume_envelope(&mut self.ch2.volume, old_val, val);
+                }
+                if !regs.dac_enabled() {
+                    self.enabled_channels[1] = false;
+                }
+            }
             NR23_REGISTER_ADDR => {
                 let regs = self.nr2x();
                 self.ch2.frequency &= !0xFF;
@@ -539,10 +867,27 @@ impl AudioController {
                 self.ch2.frequency &= !0x700;
                 self.ch2.frequency |= (regs.frequency_msb() as u16) << 8;
 
+                let stop = write_length_control(
+                    &mut self.ch2.length_counter,
+                    64,
+                    old_val & 0b0100_0000 != 0,
+                    regs.length_enable(),
+                    regs.trigger(),
+                    &self.sequencer,
+                );
                 if regs.trigger() {
-                    self.enabled_channels[1] = true;
+                    self.enabled_channels[1] = regs.dac_enabled();
                     self.ch2.trigger(regs);
                 }
+                if stop {
+                    self.enabled_channels[1] = false;
+                }
+            }
+            NR30_REGISTER_ADDR => {
+                let regs = self.nr3x();
+                if !regs.output_on() {
+                    self.enabled_channels[2] = false;
+                }
             }
             NR31_REGISTER_ADDR => {
                 let regs = self.nr3x();
@@ -557,12 +902,54 @@ impl AudioController {
                 let regs = self.nr3x();
                 self.ch3.frequency &= !0x700;
                 self.ch3.frequency |= (regs.frequency_msb() as u16) << 8;
+
+                let stop = write_length_control(
+                    &mut self.ch3.length_counter,
+                    256,
+                    old_val & 0b0100_0000 != 0,
+                    regs.length_enable(),
+                    regs.trigger(),
+                    &self.sequencer,
+                );
                 if regs.trigger() {
-                    self.enabled_channels[2] = true;
+                    self.enabled_channels[2] = regs.output_on();
                     self.ch3.trigger(regs);
                 }
+                if stop {
+                    self.enabled_channels[2] = false;
+                }
+            }
+            NR41_REGISTER_ADDR => {
+                let regs = self.nr4x();
+                self.ch4.length_counter = 64 - regs.sound_length() as u16;
+            }
+            NR42_REGISTER_ADDR => {
+                let regs = self.nr4x();
+                if self.enabled_channels[3] {
+                    zombie_volume_envelope(&mut self.ch4.volume, old_val, val);
+                }
+                if !regs.dac_enabled() {
+                    self.enabled_channels[3] = false;
+                }
+            }
+            NR44_REGISTER_ADDR => {
+                let regs = self.nr4x();
+                let stop = write_length_control(
+                    &mut self.ch4.length_counter,
+                    64,
+                    old_val & 0b0100_0000 != 0,
+                    regs.length_enable(),
+                    regs.trigger(),
+                    &self.sequencer,
+                );
+                if regs.trigger() {
+                    self.enabled_channels[3] = regs.dac_enabled();
+                    self.ch4.trigger(regs);
+                }
+                if stop {
+                    self.enabled_channels[3] = false;
+                }
             }
-            //NR44_REGISTER_ADDR => self.trigger_ch4(),
             _ => {}
         }
     }
@@ -675,12 +1062,16 @@ impl AudioController {
         }
     }
 
-    fn nr4x(&self) -> &[u8; 5] {
-        array_ref![self.regs, 15, 5]
+    fn nr4x(&self) -> NoiseVoiceSettings {
+        NoiseVoiceSettings {
+            regs: *array_ref![self.regs, 15, 5],
+        }
     }
 
-    fn nr5x(&self) -> &[u8; 3] {
-        array_ref![self.regs, 20, 3]
+    fn nr5x(&self) -> ControlSettings {
+        ControlSettings {
+            regs: *array_ref![self.regs, 20, 3],
+        }
     }
 
     fn wave_table(&self) -> &[u8] {
@@ -689,12 +1080,12 @@ impl AudioController {
 
     pub fn run_for(&mut self, num_cycles: u32) {
         for _ in 0..num_cycles {
-            let (l, r) = self.step();
+            let ((l, r), voices) = self.step();
 
-            self.buf_l.add_delta(self.cur_cycle, l - self.previous_l);
-            self.previous_l = l;
-            self.buf_r.add_delta(self.cur_cycle, r - self.previous_r);
-            self.previous_r = r;
+            self.output.add(self.cur_cycle, l, r);
+            for (stem, &(l, r)) in self.stems.iter_mut().zip(voices.iter()) {
+                stem.buffer.add(self.cur_cycle, l, r);
+            }
 
             self.cur_cycle += 1;
         }
@@ -706,30 +1097,11 @@ impl AudioController {
             return;
         }
 
-        self.buf_l.end_frame(self.cur_cycle);
-        self.buf_r.end_frame(self.cur_cycle);
-        self.cur_cycle = 0;
-
-        let samples_available = self.buf_l.samples_avail() as usize;
-        assert_eq!(samples_available, self.buf_r.samples_avail() as usize);
-        if samples_available <= 0 {
-            return;
+        self.output.read_frame(self.cur_cycle, output);
+        for stem in self.stems.iter_mut() {
+            stem.buffer.read_frame(self.cur_cycle, &mut stem.samples);
         }
-
-        let previous_len = output.len();
-        // The blip_buf crate incorrectly computes the output array size when using stereo, add an extra item to prevent that
-        output.resize(previous_len + samples_available * 2 + 1, 0);
-        {
-            let new_output = &mut output[previous_len..];
-            let samples_read_l = self.buf_l
-                .read_samples(&mut new_output[0..samples_available * 2], true);
-            let samples_read_r = self.buf_r
-                .read_samples(&mut new_output[1..samples_available * 2 + 1], true);
-            assert_eq!(samples_read_l, samples_read_r);
-            assert_eq!(samples_read_l, samples_available);
-        }
-        // Remove the item used for the workaround above
-        output.pop();
+        self.cur_cycle = 0;
     }
 
     pub fn debug_toggle_channel(&mut self, ch: usize) -> bool {
@@ -738,18 +1110,21 @@ impl AudioController {
         self.debug_enabled_channels[ch]
     }
 
-    fn step(&mut self) -> (i32, i32) {
+    /// Returns the mixed output, followed by the output of each voice on its own.
+    fn step(&mut self) -> ((i32, i32), [(i32, i32); NUM_CHANNELS]) {
         if !self.apu_enabled {
-            return (0, 0);
+            return ((0, 0), [(0, 0); NUM_CHANNELS]);
         }
 
         let nr1x = self.nr1x();
         let nr2x = self.nr2x();
         let nr3x = self.nr3x();
+        let nr4x = self.nr4x();
 
-        self.sequencer_counter = (self.sequencer_counter + 1) % 8192;
+        self.sequencer_counter = (self.sequencer_counter + 1) % SEQUENCER_PERIOD;
         if self.sequencer_counter == 0 {
-            if self.sequencer.length_step == 0 {
+            // Length counters are clocked even while their voice is stopped.
+            if self.sequencer.clocks_length() {
                 if self.ch1.step_length(nr1x) {
                     self.enabled_channels[0] = false;
                 }
@@ -759,20 +1134,21 @@ impl AudioController {
                 if self.ch3.step_length(nr3x) {
                     self.enabled_channels[2] = false;
                 }
+                if self.ch4.step_length(nr4x) {
+                    self.enabled_channels[3] = false;
+                }
             }
 
-            if self.sequencer.volume_step == 0 {
-                self.ch1.step_envelope(nr1x);
-                self.ch2.step_envelope(nr2x);
-                // TODO self.ch3.step_envelope();
-                // TODO self.ch4.step_envelope();
+            if self.sequencer.clocks_sweep() && self.ch1.step_sweep(nr1x) {
+                self.enabled_channels[0] = false;
             }
 
-            if self.sequencer.sweep_step == 0 {
-                self.ch1.step_sweep(nr1x);
+            if self.sequencer.clocks_envelope() {
+                self.ch1.step_envelope(nr1x);
+                self.ch2.step_envelope(nr2x);
+                self.ch4.step_envelope(nr4x);
             }
 
-            // TODO: at 512Hz: self.sequencer.step();
             self.sequencer.step();
         }
 
@@ -785,26 +1161,168 @@ impl AudioController {
         if self.enabled_channels[2] {
             self.ch3.step(nr3x);
         }
-        // TODO ch4
+        if self.enabled_channels[3] {
+            self.ch4.step(nr4x);
+        }
+
+        let samples = [
+            self.ch1.sample(nr1x) as i32,
+            self.ch2.sample(nr2x) as i32,
+            self.ch3.sample(nr3x) as i32,
+            self.ch4.sample() as i32,
+        ];
 
-        let mut mixed = 0;
-        let ch1_val = self.ch1.sample(nr1x) as i32;
-        let ch2_val = self.ch2.sample(nr2x) as i32;
-        let ch3_val = self.ch3.sample(nr3x) as i32;
+        let nr5x = self.nr5x();
+        // Master volume goes from 1/8 (0) to 8/8 (7).
+        let left_volume = nr5x.left_volume() as i32 + 1;
+        let right_volume = nr5x.right_volume() as i32 + 1;
 
-        if self.debug_enabled_channels[0] && self.enabled_channels[0] {
-            mixed += (ch1_val - 7) * 0x200;
-        }
-        if self.debug_enabled_channels[1] && self.enabled_channels[1] {
-            mixed += (ch2_val - 7) * 0x200;
+        let mut voices = [(0, 0); NUM_CHANNELS];
+        for (ch, &sample) in samples.iter().enumerate() {
+            if !self.enabled_channels[ch] {
+                continue;
+            }
+            let value = (sample - 7) * 0x40;
+            if nr5x.left_enabled(ch) {
+                voices[ch].0 = value * left_volume;
+            }
+            if nr5x.right_enabled(ch) {
+                voices[ch].1 = value * right_volume;
+            }
         }
-        if self.debug_enabled_channels[2] && self.enabled_channels[2] {
-            if ch3_val != 0 {
-                println!("ch3: {}", ch3_val);
+
+        let mut left = 0;
+        let mut right = 0;
+        for (ch, &(l, r)) in voices.iter().enumerate() {
+            if self.debug_enabled_channels[ch] {
+                left += l;
+                right += r;
             }
-            mixed += (ch3_val - 7) * 0x200;
+        }
+        // VIN is the analog input from the cartridge connector. No supported cartridge drives it,
+        // so routing it to a terminal mixes in silence.
+        let vin = 0;
+        if nr5x.vin_left() {
+            left += vin * left_volume;
+        }
+        if nr5x.vin_right() {
+            right += vin * right_volume;
         }
 
-        (mixed, mixed)
+        ((left, right), voices)
+    }
+}
+
+impl SaveState for Sequencer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.step);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.step = reader.read_u8()? % 8;
+        Ok(())
+    }
+}
+
+impl SaveState for SquareVoice {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.sweep_active);
+        writer.write_u8(self.sweep_counter);
+        writer.write_u16(self.sweep_frequency);
+        writer.write_u16(self.length_counter);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.frequency_counter);
+        writer.write_u8(self.envelope_counter);
+        writer.write_u8(self.waveform_index);
+        writer.write_u8(self.volume);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.sweep_active = reader.read_bool()?;
+        self.sweep_counter = reader.read_u8()?;
+        self.sweep_frequency = reader.read_u16()?;
+        self.length_counter = reader.read_u16()?;
+        self.frequency = reader.read_u16()? & 0x7FF;
+        self.frequency_counter = reader.read_u16()?;
+        self.envelope_counter = reader.read_u8()?;
+        self.waveform_index = reader.read_u8()? % 8;
+        self.volume = reader.read_u8()? & 0xF;
+        Ok(())
+    }
+}
+
+impl SaveState for WaveVoice {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.custom_wave);
+        writer.write_u16(self.pos_counter);
+        writer.write_u16(self.length_counter);
+        writer.write_u16(self.frequency);
+        writer.write_u16(self.frequency_counter);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.custom_wave)?;
+        self.pos_counter = reader.read_u16()? % (CUSTOM_WAVE_SIZE as u16 * 2);
+        self.length_counter = reader.read_u16()?;
+        self.frequency = reader.read_u16()? & 0x7FF;
+        self.frequency_counter = reader.read_u16()?;
+        Ok(())
+    }
+}
+
+impl SaveState for NoiseVoice {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.length_counter);
+        writer.write_u32(self.frequency_counter);
+        writer.write_u8(self.envelope_counter);
+        writer.write_u8(self.volume);
+        writer.write_u16(self.lfsr);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        self.length_counter = reader.read_u16()?;
+        self.frequency_counter = reader.read_u32()?;
+        self.envelope_counter = reader.read_u8()?;
+        self.volume = reader.read_u8()? & 0xF;
+        self.lfsr = reader.read_u16()? & 0x7FFF;
+        Ok(())
+    }
+}
+
+impl SaveState for AudioController {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bytes(&self.regs);
+        self.sequencer.save_state(writer);
+        writer.write_u16(self.sequencer_counter);
+        writer.write_bool(self.apu_enabled);
+        for &enabled in self.enabled_channels.iter() {
+            writer.write_bool(enabled);
+        }
+        self.ch1.save_state(writer);
+        self.ch2.save_state(writer);
+        self.ch3.save_state(writer);
+        self.ch4.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError> {
+        reader.read_bytes_into(&mut self.regs)?;
+        self.sequencer.load_state(reader)?;
+        self.sequencer_counter = reader.read_u16()? % SEQUENCER_PERIOD;
+        self.apu_enabled = reader.read_bool()?;
+        for enabled in self.enabled_channels.iter_mut() {
+            *enabled = reader.read_bool()?;
+        }
+        self.ch1.load_state(reader)?;
+        self.ch2.load_state(reader)?;
+        self.ch3.load_state(reader)?;
+        self.ch4.load_state(reader)?;
+
+        // Samples queued before the load belong to the old timeline.
+        self.output.clear();
+        for stem in self.stems.iter_mut() {
+            stem.buffer.clear();
+        }
+        self.cur_cycle = 0;
+        Ok(())
     }
 }
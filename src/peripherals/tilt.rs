@@ -0,0 +1,74 @@
+/// How far the console is tilted, as seen by cartridges with an accelerometer. Both axes are in
+/// g, where 1.0 is tilted all the way: `x` is positive when tilted to the right, `y` when the
+/// top of the console is tilted away from the player.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Tilt {
+    pub x: f32,
+    pub y: f32,
+}
+
+impl Tilt {
+    pub fn new(x: f32, y: f32) -> Tilt {
+        Tilt { x, y }.clamped()
+    }
+
+    /// Limits both axes to what the accelerometer can report.
+    pub fn clamped(self) -> Tilt {
+        Tilt {
+            x: self.x.clamp(-1.0, 1.0),
+            y: self.y.clamp(-1.0, 1.0),
+        }
+    }
+
+    /// Parses `X,Y`.
+    pub fn parse(text: &str) -> Option<Tilt> {
+        let mut axes = text.split(',').map(|axis| axis.trim().parse::<f32>());
+        match (axes.next(), axes.next(), axes.next()) {
+            (Some(Ok(x)), Some(Ok(y)), None) if x.is_finite() && y.is_finite() => {
+                Some(Tilt::new(x, y))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Tilt values set from given frames on, for running without anyone holding the console.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct TiltScript {
+    /// Sorted by frame.
+    keyframes: Vec<(u64, Tilt)>,
+}
+
+impl TiltScript {
+    /// Parses whitespace-separated `[FRAME:]X,Y` entries, e.g. `0.5,0 120:-0.5,0.25`. Entries
+    /// without a frame apply from frame 0.
+    pub fn parse(text: &str) -> Result<TiltScript, String> {
+        let mut keyframes = Vec::new();
+        for entry in text.split_whitespace() {
+            let (frame, tilt) = match entry.find(':') {
+                Some(i) => (
+                    entry[..i]
+                        .parse()
+                        .map_err(|_| format!("invalid frame in tilt `{}`", entry))?,
+                    &entry[i + 1..],
+                ),
+                None => (0, entry),
+            };
+            let tilt = Tilt::parse(tilt)
+                .ok_or_else(|| format!("invalid tilt `{}`, expected [FRAME:]X,Y", entry))?;
+            keyframes.push((frame, tilt));
+        }
+        // Stable, so later entries for the same frame win.
+        keyframes.sort_by_key(|&(frame, _)| frame);
+        Ok(TiltScript { keyframes })
+    }
+
+    /// The tilt during `frame`: that of the last keyframe at or before it.
+    pub fn tilt_at(&self, frame: u64) -> Tilt {
+        self.keyframes
+            .iter()
+            .take_while(|&&(start, _)| start <= frame)
+            .last()
+            .map_or(Tilt::default(), |&(_, tilt)| tilt)
+    }
+}
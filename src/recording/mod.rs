@@ -0,0 +1,3 @@
+pub mod png;
+pub mod video;
+pub mod wav;
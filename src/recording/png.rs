@@ -0,0 +1,97 @@
+use crate::util::crc32;
+use std::io::{self, Write};
+
+/// Largest block a stored (uncompressed) deflate block can hold.
+const MAX_STORED_BLOCK: usize = 0xFFFF;
+
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+/// Wraps `data` in a zlib stream made of stored blocks. Screenshots are small, so compressing
+/// them isn't worth a dependency.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len() / MAX_STORED_BLOCK * 5 + 11);
+    out.extend_from_slice(&[0x78, 0x01]);
+    let mut chunks = data.chunks(MAX_STORED_BLOCK).peekable();
+    if chunks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(chunk) = chunks.next() {
+        let last = chunks.peek().is_none();
+        let len = chunk.len() as u16;
+        out.push(last as u8);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(chunk);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+pub fn write_chunk<W: Write>(out: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    out.write_all(&crc32(data, crc32(kind, 0)).to_be_bytes())
+}
+
+pub fn write_signature<W: Write>(out: &mut W) -> io::Result<()> {
+    out.write_all(b"\x89PNG\r\n\x1a\n")
+}
+
+pub fn write_header<W: Write>(out: &mut W, width: u32, height: u32) -> io::Result<()> {
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&width.to_be_bytes());
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    // 8 bits per channel, RGBA, default compression, filter and no interlacing.
+    ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+    write_chunk(out, b"IHDR", &ihdr)
+}
+
+/// Compressed image data of a `width` pixels wide RGBA image, as stored in IDAT/fdAT chunks.
+pub fn image_data(width: u32, rgba: &[u8]) -> Vec<u8> {
+    let stride = width as usize * 4;
+    let mut raw = Vec::with_capacity(rgba.len() + rgba.len() / stride);
+    for row in rgba.chunks(stride) {
+        raw.push(0); // no filter
+        raw.extend_from_slice(row);
+    }
+    zlib_stored(&raw)
+}
+
+/// Writes a `width`x`height` RGBA image as a PNG file.
+pub fn write_png<W: Write>(out: &mut W, width: u32, height: u32, rgba: &[u8]) -> io::Result<()> {
+    assert_eq!(rgba.len(), (width * height * 4) as usize);
+    write_signature(out)?;
+    write_header(out, width, height)?;
+    write_chunk(out, b"IDAT", &image_data(width, rgba))?;
+    write_chunk(out, b"IEND", &[])
+}
+
+/// Scales an RGBA image up by an integer `scale`, repeating each pixel.
+pub fn scale_rgba(width: u32, rgba: &[u8], scale: u32) -> Vec<u8> {
+    if scale <= 1 {
+        return rgba.to_vec();
+    }
+    let stride = width as usize * 4;
+    let scale = scale as usize;
+    let mut scaled = Vec::with_capacity(rgba.len() * scale * scale);
+    for row in rgba.chunks(stride) {
+        let mut scaled_row = Vec::with_capacity(stride * scale);
+        for pixel in row.chunks(4) {
+            for _ in 0..scale {
+                scaled_row.extend_from_slice(pixel);
+            }
+        }
+        for _ in 0..scale {
+            scaled.extend_from_slice(&scaled_row);
+        }
+    }
+    scaled
+}
@@ -0,0 +1,178 @@
+use crate::cpu::ioregister::CPU_FREQUENCY_HZ;
+use crate::recording::png;
+
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+/// The LCD refreshes every 70224 cycles of the 4194304 Hz clock, ~59.73 Hz. Frames don't take
+/// exactly that long to emulate, so formats with a fixed rate repeat or drop frames to follow the
+/// emulated time, which the audio follows too.
+const FRAME_CYCLES: u32 = 70_224;
+/// APNG frames last as long as they took to emulate, in units of 1/10000 s.
+const APNG_DELAY_DEN: u16 = 10_000;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub enum VideoFormat {
+    /// YUV4MPEG2 with 4:4:4 chroma, readable by most video tools.
+    Y4m,
+    /// Headerless RGB24 frames, e.g. for `ffmpeg -f rawvideo -pixel_format rgb24`.
+    RawRgb,
+    /// Animated PNG, lossless.
+    Apng,
+}
+
+impl VideoFormat {
+    /// Guesses the format from the extension of `path`.
+    pub fn from_path(path: &Path) -> Option<VideoFormat> {
+        let extension = path.extension()?.to_str()?.to_lowercase();
+        match extension.as_ref() {
+            "y4m" => Some(VideoFormat::Y4m),
+            "rgb" | "raw" => Some(VideoFormat::RawRgb),
+            "png" | "apng" => Some(VideoFormat::Apng),
+            _ => None,
+        }
+    }
+}
+
+/// Writes RGBA frames of a fixed size to a video file.
+pub struct VideoWriter {
+    out: BufWriter<File>,
+    format: VideoFormat,
+    width: u32,
+    height: u32,
+    frames: u32,
+    /// Cycles emulated since the recording started.
+    cycles: u64,
+    /// APNG sequence number of the next fcTL/fdAT chunk.
+    sequence: u32,
+}
+
+impl VideoWriter {
+    pub fn create(
+        path: &Path,
+        format: VideoFormat,
+        width: u32,
+        height: u32,
+    ) -> io::Result<VideoWriter> {
+        let mut out = BufWriter::new(File::create(path)?);
+        match format {
+            VideoFormat::Y4m => writeln!(
+                out,
+                "YUV4MPEG2 W{} H{} F{}:{} Ip A1:1 C444",
+                width, height, CPU_FREQUENCY_HZ, FRAME_CYCLES
+            )?,
+            VideoFormat::RawRgb => {}
+            VideoFormat::Apng => {
+                png::write_signature(&mut out)?;
+                png::write_header(&mut out, width, height)?;
+                // The frame count is filled in by `finish`.
+                png::write_chunk(&mut out, b"acTL", &[0; 8])?;
+            }
+        }
+        Ok(VideoWriter {
+            out,
+            format,
+            width,
+            height,
+            frames: 0,
+            cycles: 0,
+            sequence: 0,
+        })
+    }
+
+    /// Adds a frame that took `cycles` to emulate.
+    pub fn write_frame(&mut self, rgba: &[u8], cycles: u32) -> io::Result<()> {
+        assert_eq!(rgba.len(), (self.width * self.height * 4) as usize);
+        let start = self.cycles;
+        self.cycles += cycles as u64;
+        match self.format {
+            VideoFormat::Y4m | VideoFormat::RawRgb => {
+                // Rounded, so that frames are dropped or repeated as little as possible.
+                let frames = (self.cycles + FRAME_CYCLES as u64 / 2) / FRAME_CYCLES as u64;
+                while (self.frames as u64) < frames {
+                    if self.format == VideoFormat::Y4m {
+                        self.write_y4m_frame(rgba)?;
+                    } else {
+                        self.write_rgb_frame(rgba)?;
+                    }
+                    self.frames += 1;
+                }
+            }
+            VideoFormat::Apng => {
+                let delay = self.apng_time(self.cycles) - self.apng_time(start);
+                self.write_apng_frame(rgba, delay.min(u16::MAX as u64) as u16)?;
+                self.frames += 1;
+            }
+        }
+        Ok(())
+    }
+
+    /// Time at `cycles` in APNG delay units, rounded the same way every frame so that the delays
+    /// add up to the emulated time.
+    fn apng_time(&self, cycles: u64) -> u64 {
+        (cycles * APNG_DELAY_DEN as u64 + CPU_FREQUENCY_HZ as u64 / 2) / CPU_FREQUENCY_HZ as u64
+    }
+
+    fn write_rgb_frame(&mut self, rgba: &[u8]) -> io::Result<()> {
+        let rgb: Vec<u8> = rgba
+            .chunks(4)
+            .flat_map(|pixel| pixel[..3].iter().cloned())
+            .collect();
+        self.out.write_all(&rgb)
+    }
+
+    fn write_y4m_frame(&mut self, rgba: &[u8]) -> io::Result<()> {
+        let pixels = (self.width * self.height) as usize;
+        let mut planes = vec![0u8; pixels * 3];
+        for (i, pixel) in rgba.chunks(4).enumerate() {
+            // BT.601, limited range.
+            let (r, g, b) = (pixel[0] as i32, pixel[1] as i32, pixel[2] as i32);
+            let y = ((66 * r + 129 * g + 25 * b + 128) >> 8) + 16;
+            let u = ((-38 * r - 74 * g + 112 * b + 128) >> 8) + 128;
+            let v = ((112 * r - 94 * g - 18 * b + 128) >> 8) + 128;
+            planes[i] = y as u8;
+            planes[pixels + i] = u as u8;
+            planes[pixels * 2 + i] = v as u8;
+        }
+        self.out.write_all(b"FRAME\n")?;
+        self.out.write_all(&planes)
+    }
+
+    fn write_apng_frame(&mut self, rgba: &[u8], delay: u16) -> io::Result<()> {
+        let mut fctl = Vec::with_capacity(26);
+        fctl.extend_from_slice(&self.sequence.to_be_bytes());
+        fctl.extend_from_slice(&self.width.to_be_bytes());
+        fctl.extend_from_slice(&self.height.to_be_bytes());
+        fctl.extend_from_slice(&[0; 8]); // x and y offsets
+        fctl.extend_from_slice(&delay.to_be_bytes());
+        fctl.extend_from_slice(&APNG_DELAY_DEN.to_be_bytes());
+        fctl.extend_from_slice(&[0, 0]); // no disposal, no blending
+        png::write_chunk(&mut self.out, b"fcTL", &fctl)?;
+        self.sequence += 1;
+
+        let data = png::image_data(self.width, rgba);
+        if self.frames == 0 {
+            png::write_chunk(&mut self.out, b"IDAT", &data)
+        } else {
+            let mut fdat = Vec::with_capacity(data.len() + 4);
+            fdat.extend_from_slice(&self.sequence.to_be_bytes());
+            fdat.extend_from_slice(&data);
+            self.sequence += 1;
+            png::write_chunk(&mut self.out, b"fdAT", &fdat)
+        }
+    }
+
+    pub fn finish(mut self) -> io::Result<()> {
+        if self.format == VideoFormat::Apng {
+            png::write_chunk(&mut self.out, b"IEND", &[])?;
+            // Signature (8 bytes) and IHDR (25 bytes) come before acTL.
+            self.out.seek(SeekFrom::Start(33))?;
+            let mut actl = Vec::with_capacity(8);
+            actl.extend_from_slice(&self.frames.to_be_bytes());
+            actl.extend_from_slice(&0u32.to_be_bytes()); // loop forever
+            png::write_chunk(&mut self.out, b"acTL", &actl)?;
+        }
+        self.out.flush()
+    }
+}
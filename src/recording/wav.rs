@@ -0,0 +1,66 @@
+use std::io::{self, Seek, SeekFrom, Write};
+
+const HEADER_SIZE: u32 = 44;
+const BITS_PER_SAMPLE: u16 = 16;
+
+/// Writes 16-bit PCM samples to a WAV file. The header is written last, by `finish`, since it has
+/// to contain the size of the data.
+pub struct WavWriter<W: Write + Seek> {
+    inner: W,
+    sample_rate: u32,
+    channels: u16,
+    data_size: u32,
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut inner: W, sample_rate: u32, channels: u16) -> io::Result<WavWriter<W>> {
+        // Room for the header.
+        inner.write_all(&[0; HEADER_SIZE as usize])?;
+        Ok(WavWriter {
+            inner,
+            sample_rate,
+            channels,
+            data_size: 0,
+        })
+    }
+
+    /// Only meant to be called before any samples are written.
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+    }
+
+    /// `samples` are interleaved, one per channel.
+    pub fn write_samples(&mut self, samples: &[i16]) -> io::Result<()> {
+        let mut bytes = Vec::with_capacity(samples.len() * 2);
+        for sample in samples {
+            bytes.extend_from_slice(&sample.to_le_bytes());
+        }
+        self.inner.write_all(&bytes)?;
+        self.data_size += bytes.len() as u32;
+        Ok(())
+    }
+
+    pub fn finish(mut self) -> io::Result<W> {
+        let block_align = self.channels * BITS_PER_SAMPLE / 8;
+        let mut header = Vec::with_capacity(HEADER_SIZE as usize);
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(HEADER_SIZE - 8 + self.data_size).to_le_bytes());
+        header.extend_from_slice(b"WAVE");
+        header.extend_from_slice(b"fmt ");
+        header.extend_from_slice(&16u32.to_le_bytes());
+        header.extend_from_slice(&1u16.to_le_bytes()); // PCM
+        header.extend_from_slice(&self.channels.to_le_bytes());
+        header.extend_from_slice(&self.sample_rate.to_le_bytes());
+        header.extend_from_slice(&(self.sample_rate * block_align as u32).to_le_bytes());
+        header.extend_from_slice(&block_align.to_le_bytes());
+        header.extend_from_slice(&BITS_PER_SAMPLE.to_le_bytes());
+        header.extend_from_slice(b"data");
+        header.extend_from_slice(&self.data_size.to_le_bytes());
+
+        self.inner.seek(SeekFrom::Start(0))?;
+        self.inner.write_all(&header)?;
+        self.inner.seek(SeekFrom::End(0))?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
@@ -0,0 +1,133 @@
+use std::collections::VecDeque;
+
+/// Keeps the snapshots of the last frames so that they can be restored in reverse order.
+///
+/// Only the latest snapshot is kept in full. Every older one is stored as the difference from the
+/// one taken after it, which is mostly zeroes between consecutive frames and is run-length
+/// encoded. Going back one frame decodes a single delta, and the oldest snapshot can be dropped
+/// without touching the others.
+pub struct RewindBuffer {
+    latest: Option<Vec<u8>>,
+    /// Oldest first.
+    deltas: VecDeque<Vec<u8>>,
+    /// Bytes taken by `latest` and `deltas`.
+    size: usize,
+    max_snapshots: usize,
+    max_size: usize,
+}
+
+impl RewindBuffer {
+    /// Keeps at most `max_snapshots` snapshots, dropping the oldest ones earlier if they would
+    /// take more than `max_size` bytes.
+    pub fn new(max_snapshots: usize, max_size: usize) -> RewindBuffer {
+        RewindBuffer {
+            latest: None,
+            deltas: VecDeque::new(),
+            size: 0,
+            max_snapshots,
+            max_size,
+        }
+    }
+
+    fn len(&self) -> usize {
+        self.deltas.len() + self.latest.is_some() as usize
+    }
+
+    pub fn clear(&mut self) {
+        self.latest = None;
+        self.deltas.clear();
+        self.size = 0;
+    }
+
+    pub fn push(&mut self, snapshot: Vec<u8>) {
+        if let Some(previous) = self.latest.take() {
+            let delta = encode_delta(&previous, &snapshot);
+            self.size += delta.len();
+            self.size -= previous.len();
+            self.deltas.push_back(delta);
+        }
+        self.size += snapshot.len();
+        self.latest = Some(snapshot);
+
+        while self.len() > self.max_snapshots.max(1) || self.size > self.max_size {
+            match self.deltas.pop_front() {
+                Some(delta) => self.size -= delta.len(),
+                None => break,
+            }
+        }
+    }
+
+    /// Drops the latest snapshot and returns the one before it.
+    pub fn pop(&mut self) -> Option<&[u8]> {
+        let delta = self.deltas.pop_back()?;
+        let latest = self.latest.take().expect("rewind deltas without a latest snapshot");
+        let previous = decode_delta(&latest, &delta);
+        self.size -= delta.len() + latest.len();
+        self.size += previous.len();
+        self.latest = Some(previous);
+        self.latest.as_ref().map(|s| &s[..])
+    }
+}
+
+fn write_varint(out: &mut Vec<u8>, mut value: usize) {
+    while value >= 0x80 {
+        out.push((value as u8 & 0x7F) | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+fn read_varint(data: &[u8], pos: &mut usize) -> usize {
+    let mut value = 0;
+    let mut shift = 0;
+    loop {
+        let byte = data[*pos];
+        *pos += 1;
+        value |= ((byte & 0x7F) as usize) << shift;
+        if byte & 0x80 == 0 {
+            return value;
+        }
+        shift += 7;
+    }
+}
+
+/// Encodes `target` as the XOR with `base`: its length, then pairs of a run of equal bytes and a
+/// run of literal XORed bytes. Bytes past the end of `base` are XORed with zero.
+fn encode_delta(target: &[u8], base: &[u8]) -> Vec<u8> {
+    let xor = |i: usize| target[i] ^ base.get(i).cloned().unwrap_or(0);
+    let mut out = Vec::new();
+    write_varint(&mut out, target.len());
+    let mut i = 0;
+    while i < target.len() {
+        let same_start = i;
+        while i < target.len() && xor(i) == 0 {
+            i += 1;
+        }
+        let literal_start = i;
+        // Short runs of equal bytes are cheaper to keep as literals.
+        while i < target.len() && (xor(i) != 0 || (i + 1 < target.len() && xor(i + 1) != 0)) {
+            i += 1;
+        }
+        write_varint(&mut out, literal_start - same_start);
+        write_varint(&mut out, i - literal_start);
+        out.extend((literal_start..i).map(xor));
+    }
+    out
+}
+
+fn decode_delta(base: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut pos = 0;
+    let len = read_varint(delta, &mut pos);
+    let mut target: Vec<u8> = (0..len).map(|i| base.get(i).cloned().unwrap_or(0)).collect();
+    let mut i = 0;
+    while i < len {
+        i += read_varint(delta, &mut pos);
+        let literals = read_varint(delta, &mut pos);
+        for byte in &mut target[i..i + literals] {
+            *byte ^= delta[pos];
+            pos += 1;
+        }
+        i += literals;
+    }
+    target
+}
@@ -0,0 +1,128 @@
+use std::fmt;
+
+/// Identifies a gebemula save state file.
+pub const STATE_MAGIC: &[u8; 4] = b"GBMS";
+/// Bumped every time the layout of the serialized state changes.
+pub const STATE_VERSION: u32 = 6;
+
+#[derive(Debug)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    /// The state was saved while running a different game.
+    RomMismatch,
+    Truncated,
+    Corrupt(&'static str),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StateError::BadMagic => write!(f, "not a gebemula save state"),
+            StateError::UnsupportedVersion(v) => write!(
+                f,
+                "unsupported save state version {} (expected {})",
+                v, STATE_VERSION
+            ),
+            StateError::RomMismatch => write!(f, "save state belongs to a different ROM"),
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::Corrupt(what) => write!(f, "save state is corrupt: {}", what),
+        }
+    }
+}
+
+/// Implemented by every component that is part of the machine state.
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader) -> Result<(), StateError>;
+}
+
+/// Serializes state as a flat little-endian byte stream.
+#[derive(Default)]
+pub struct StateWriter {
+    data: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter::default()
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.data.push(value);
+    }
+    pub fn write_bool(&mut self, value: bool) {
+        self.write_u8(value as u8);
+    }
+    pub fn write_u16(&mut self, value: u16) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u32(&mut self, value: u32) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+    pub fn write_u64(&mut self, value: u64) {
+        self.data.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Writes a length-prefixed block of bytes.
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.data.extend_from_slice(bytes);
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> StateReader<'a> {
+        StateReader { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.data.len() - self.pos < len {
+            return Err(StateError::Truncated);
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(*array_ref![self.take(2)?, 0, 2]))
+    }
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(*array_ref![self.take(4)?, 0, 4]))
+    }
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(*array_ref![self.take(8)?, 0, 8]))
+    }
+
+    /// Reads a length-prefixed block of bytes of any size.
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    /// Reads a length-prefixed block of bytes into `buffer`, which must have the same size.
+    pub fn read_bytes_into(&mut self, buffer: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != buffer.len() {
+            return Err(StateError::Corrupt("unexpected block size"));
+        }
+        buffer.copy_from_slice(bytes);
+        Ok(())
+    }
+}
@@ -45,3 +45,16 @@ pub fn sign_extend(value: u8) -> u16 {
     }
     res
 }
+
+/// CRC-32 (as used by PNG and zlib) of `data`, continuing from a previous `crc` (0 to start).
+pub fn crc32(data: &[u8], crc: u32) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            let mask = (!(crc & 1)).wrapping_add(1);
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}